@@ -10,7 +10,7 @@ use serde_json::json;
 use tower::ServiceExt;
 
 fn bench_add_batch(c: &mut Criterion) {
-    let mut aggregator = SymbolAggregator::<8, 10>::new();
+    let mut aggregator = SymbolAggregator::default();
 
     let values = generate_random_data(100, 314.15, 27.172, 4573.25);
 
@@ -37,8 +37,9 @@ fn bench_add_batch(c: &mut Criterion) {
     });
 }
 
+#[allow(clippy::approx_constant)]
 fn bench_get_stats(c: &mut Criterion) {
-    let mut aggregator = SymbolAggregator::<8, 10>::new();
+    let mut aggregator = SymbolAggregator::default();
 
     let values = generate_random_data(100_000_000, 3.14, 271.72, 457325.);
 
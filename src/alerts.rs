@@ -0,0 +1,483 @@
+//! Alert rules evaluated after every batch of their symbols, see [`Alerts`].
+use crate::api::StatsResult;
+use crate::snapshot::tmp_path;
+use crate::symbol_aggregator::{StatsOptions, SymbolAggregator};
+use crate::webhook::{Notification, WebhookUrl};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc;
+
+/// Notifications evaluated but not delivered yet; more are dropped.
+const PENDING_NOTIFICATIONS: usize = 1024;
+
+/// Stat of a window a rule watches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    Min,
+    Max,
+    Last,
+    Avg,
+    Var,
+    Std,
+    Median,
+    P5,
+    P25,
+    P75,
+    P95,
+    Skew,
+    Kurtosis,
+    Vwap,
+    WeightedVar,
+    Volume,
+}
+
+impl Field {
+    /// Value of the field, or `None` if the window does not have it, e.g. `vwap` without weights.
+    fn of(self, stats: &StatsResult) -> Option<f64> {
+        match self {
+            Self::Min => Some(stats.min),
+            Self::Max => Some(stats.max),
+            Self::Last => Some(stats.last),
+            Self::Avg => Some(stats.avg),
+            Self::Var => Some(stats.var),
+            Self::Std => Some(stats.var.sqrt()),
+            Self::Median => Some(stats.median),
+            Self::P5 => Some(stats.p5),
+            Self::P25 => Some(stats.p25),
+            Self::P75 => Some(stats.p75),
+            Self::P95 => Some(stats.p95),
+            Self::Skew => stats.skew,
+            Self::Kurtosis => stats.kurtosis,
+            Self::Vwap => stats.vwap,
+            Self::WeightedVar => stats.weighted_var,
+            Self::Volume => stats.volume,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Op {
+    #[serde(rename = ">")]
+    Gt,
+    #[serde(rename = ">=")]
+    Ge,
+    #[serde(rename = "<")]
+    Lt,
+    #[serde(rename = "<=")]
+    Le,
+}
+
+impl Op {
+    fn holds(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Self::Gt => value > threshold,
+            Self::Ge => value >= threshold,
+            Self::Lt => value < threshold,
+            Self::Le => value <= threshold,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Above,
+    Below,
+}
+
+/// Field of the window of level `k`, or of the window of the rule if `k` is missing.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Reference {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k: Option<u32>,
+    pub field: Field,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Condition {
+    /// `field` compared with `value` by `op`; fires once it holds, and again only after
+    /// it stopped holding meanwhile
+    Threshold { field: Field, op: Op, value: f64 },
+    /// `field` crossing `reference` as it was before the batch; e.g. `last` crossing above
+    /// `max` is a new high
+    Crosses {
+        field: Field,
+        direction: Direction,
+        reference: Reference,
+    },
+    /// `field` changing since the previous batch by more than `sigmas` standard deviations
+    /// of the window as it was before the batch; never fires while the window had no variance
+    Moves { field: Field, sigmas: f64 },
+}
+
+/// Condition on the window of level `k` of a symbol, with webhooks to notify when it fires,
+/// or the configured ones if there are none.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    /// assigned when the rule is created
+    #[serde(default)]
+    pub id: u64,
+    pub symbol: String,
+    pub k: u32,
+    pub condition: Condition,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub webhooks: Vec<WebhookUrl>,
+}
+
+impl AlertRule {
+    /// Levels of windows the rule needs stats of.
+    pub fn levels(&self) -> impl Iterator<Item = u32> + '_ {
+        let reference = match &self.condition {
+            Condition::Crosses { reference, .. } => reference.k,
+            _ => None,
+        };
+        [self.k].into_iter().chain(reference)
+    }
+
+    /// Checks values of the rule, but not its levels, which depend on windows of the symbol.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.symbol.trim().is_empty() {
+            return Err("Symbol is empty".into());
+        }
+        match self.condition {
+            Condition::Threshold { value, .. } if !value.is_finite() => {
+                Err("Threshold value must be finite".into())
+            }
+            Condition::Moves { sigmas, .. } if !(sigmas.is_finite() && sigmas > 0.0) => {
+                Err("Sigmas must be a positive number".into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What a rule saw at the previous batch of its symbol.
+#[derive(Default)]
+struct RuleState {
+    /// whether the threshold held
+    active: bool,
+    /// `field` with its reference, or with standard deviation of the window for moves
+    previous: Option<(f64, f64)>,
+}
+
+struct RuleEntry {
+    rule: AlertRule,
+    state: RuleState,
+}
+
+impl RuleEntry {
+    fn new(rule: AlertRule) -> Self {
+        Self {
+            rule,
+            state: RuleState::default(),
+        }
+    }
+
+    /// Value and reference of the rule if it fires with `stats` of its levels.
+    fn evaluate(&mut self, stats: &BTreeMap<u32, Option<StatsResult>>) -> Option<(f64, f64)> {
+        let window = stats.get(&self.rule.k)?.as_ref()?;
+        let state = &mut self.state;
+        match &self.rule.condition {
+            Condition::Threshold { field, op, value } => {
+                let current = field.of(window)?;
+                let was_active = std::mem::replace(&mut state.active, op.holds(current, *value));
+                (state.active && !was_active).then_some((current, *value))
+            }
+            Condition::Crosses {
+                field,
+                direction,
+                reference,
+            } => {
+                let current = field.of(window)?;
+                let reference_window = stats.get(&reference.k.unwrap_or(self.rule.k))?;
+                let reference_now = reference.field.of(reference_window.as_ref()?)?;
+                let (value, reference) = state.previous.replace((current, reference_now))?;
+                let crossed = match direction {
+                    Direction::Above => value <= reference && current > reference,
+                    Direction::Below => value >= reference && current < reference,
+                };
+                crossed.then_some((current, reference))
+            }
+            Condition::Moves { field, sigmas } => {
+                let current = field.of(window)?;
+                let (value, std) = state.previous.replace((current, window.var.sqrt()))?;
+                (std > 0.0 && (current - value).abs() > sigmas * std).then_some((current, value))
+            }
+        }
+    }
+}
+
+/// Saved rules, with the next id so ids of deleted rules are not reused.
+#[derive(Serialize, Deserialize)]
+struct SavedRules {
+    next_id: u64,
+    rules: Vec<AlertRule>,
+}
+
+/// Alert rules by symbol, evaluated after every batch of the symbol.
+///
+/// Rules are saved to a JSON file after every change, if there is one, and loaded at startup.
+/// What rules saw at previous batches is not saved, so they start over after restart.
+pub struct Alerts {
+    rules: DashMap<String, Vec<RuleEntry>>,
+    next_id: AtomicU64,
+    path: Option<PathBuf>,
+    /// held while rules are changed and saved, so the file has the latest ones
+    changes: Mutex<()>,
+    notifications: mpsc::Sender<Notification>,
+    /// taken by the task delivering notifications
+    receiver: Mutex<Option<mpsc::Receiver<Notification>>>,
+}
+
+impl Default for Alerts {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Alerts {
+    /// Creates alerts saved to the file at `path`, if any.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let (notifications, receiver) = mpsc::channel(PENDING_NOTIFICATIONS);
+        Self {
+            rules: DashMap::new(),
+            next_id: AtomicU64::new(1),
+            path,
+            changes: Mutex::new(()),
+            notifications,
+            receiver: Mutex::new(Some(receiver)),
+        }
+    }
+
+    /// Notifications of fired rules; there is just one receiver of them.
+    pub fn take_notifications(&self) -> Option<mpsc::Receiver<Notification>> {
+        self.receiver.lock().unwrap().take()
+    }
+
+    /// Loads rules from the file, if it exists, returning their number.
+    pub fn load(&self) -> io::Result<usize> {
+        let Some(path) = &self.path else {
+            return Ok(0);
+        };
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+        let saved: SavedRules = serde_json::from_reader(BufReader::new(file))?;
+        let count = saved.rules.len();
+        let _changes = self.changes.lock().unwrap();
+        self.next_id.store(saved.next_id, Ordering::Relaxed);
+        for rule in saved.rules {
+            self.insert(rule);
+        }
+        Ok(count)
+    }
+
+    /// All rules, ordered by id.
+    pub fn list(&self) -> Vec<AlertRule> {
+        let mut rules: Vec<AlertRule> = self
+            .rules
+            .iter()
+            .flat_map(|entries| {
+                let rules: Vec<_> = entries.iter().map(|entry| entry.rule.clone()).collect();
+                rules
+            })
+            .collect();
+        rules.sort_by_key(|rule| rule.id);
+        rules
+    }
+
+    pub fn get(&self, id: u64) -> Option<AlertRule> {
+        self.list().into_iter().find(|rule| rule.id == id)
+    }
+
+    /// Adds `rule` with a new id, returning it.
+    pub fn create(&self, mut rule: AlertRule) -> io::Result<AlertRule> {
+        let _changes = self.changes.lock().unwrap();
+        rule.id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.insert(rule.clone());
+        self.save()?;
+        Ok(rule)
+    }
+
+    /// Replaces rule of given `id`, which starts over, returning `None` if there is no such rule.
+    pub fn update(&self, id: u64, mut rule: AlertRule) -> io::Result<Option<AlertRule>> {
+        let _changes = self.changes.lock().unwrap();
+        if self.remove(id).is_none() {
+            return Ok(None);
+        }
+        rule.id = id;
+        self.insert(rule.clone());
+        self.save()?;
+        Ok(Some(rule))
+    }
+
+    /// Removes rule of given `id`, returning `false` if there is no such rule.
+    pub fn delete(&self, id: u64) -> io::Result<bool> {
+        let _changes = self.changes.lock().unwrap();
+        if self.remove(id).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    fn insert(&self, rule: AlertRule) {
+        self.rules
+            .entry(rule.symbol.clone())
+            .or_default()
+            .push(RuleEntry::new(rule));
+    }
+
+    fn remove(&self, id: u64) -> Option<AlertRule> {
+        let symbol = self.get(id)?.symbol;
+        let mut entries = self.rules.get_mut(&symbol)?;
+        let position = entries.iter().position(|entry| entry.rule.id == id)?;
+        let removed = entries.remove(position).rule;
+        let is_empty = entries.is_empty();
+        drop(entries);
+        if is_empty {
+            self.rules
+                .remove_if(&symbol, |_, entries| entries.is_empty());
+        }
+        Some(removed)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let saved = SavedRules {
+            next_id: self.next_id.load(Ordering::Relaxed),
+            rules: self.list(),
+        };
+        let tmp_path = tmp_path(path);
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer_pretty(&mut writer, &saved)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    /// Evaluates rules of `symbol` right after a batch was added to its `agg`,
+    /// queueing notifications of the ones which fire.
+    pub fn evaluate(&self, symbol: &str, agg: &mut SymbolAggregator, now: u64) {
+        let Some(mut entries) = self.rules.get_mut(symbol) else {
+            return;
+        };
+        let options = StatsOptions {
+            now,
+            moments: true,
+            ..StatsOptions::default()
+        };
+        let mut stats = BTreeMap::new();
+        for entry in entries.iter() {
+            for k in entry.rule.levels() {
+                stats
+                    .entry(k)
+                    .or_insert_with(|| agg.get_stats_with(k, &options));
+            }
+        }
+
+        for entry in entries.iter_mut() {
+            let Some((value, reference)) = entry.evaluate(&stats) else {
+                continue;
+            };
+            let body = json!({
+                "rule": entry.rule,
+                "symbol": symbol,
+                "index": agg.index(),
+                "time": now,
+                "value": value,
+                "reference": reference,
+            });
+            let notification = Notification {
+                webhooks: entry.rule.webhooks.clone(),
+                body: body.to_string(),
+            };
+            if self.notifications.try_send(notification).is_err() {
+                tracing::warn!("dropped notification of alert rule {}", entry.rule.id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::windows::Windows;
+
+    fn rule(condition: serde_json::Value) -> AlertRule {
+        serde_json::from_value(json!({"symbol": "A", "k": 1, "condition": condition})).unwrap()
+    }
+
+    /// Adds `values` one by one, returning values of notifications.
+    fn fired(alerts: &Alerts, values: &[f64]) -> Vec<f64> {
+        let mut notifications = alerts.take_notifications().unwrap();
+        let mut agg = SymbolAggregator::new(Windows::geometric(2, 10));
+        let mut fired = Vec::new();
+        for &val in values {
+            agg.add_batch(&[val]);
+            alerts.evaluate("A", &mut agg, 0);
+            while let Ok(notification) = notifications.try_recv() {
+                let body: serde_json::Value = serde_json::from_str(&notification.body).unwrap();
+                fired.push(body["value"].as_f64().unwrap());
+            }
+        }
+        fired
+    }
+
+    #[test]
+    fn test_conditions() {
+        let alerts = Alerts::default();
+        let threshold = json!({"type": "threshold", "field": "last", "op": ">", "value": 5});
+        alerts.create(rule(threshold)).unwrap();
+        // once it holds, and again after it stopped holding
+        assert_eq!(fired(&alerts, &[1., 6., 7., 2., 8.]), vec![6., 8.]);
+
+        let alerts = Alerts::default();
+        let new_high = json!({
+            "type": "crosses", "field": "last", "direction": "above",
+            "reference": {"k": 2, "field": "max"},
+        });
+        alerts.create(rule(new_high)).unwrap();
+        assert_eq!(fired(&alerts, &[3., 1., 4., 4., 2., 5.]), vec![4., 5.]);
+
+        let alerts = Alerts::default();
+        let jump = json!({"type": "moves", "field": "last", "sigmas": 3});
+        alerts.create(rule(jump)).unwrap();
+        assert_eq!(fired(&alerts, &[1., 2., 1., 2., 9.]), vec![9.]);
+    }
+
+    #[test]
+    fn test_rules_saved() {
+        let path = std::env::temp_dir().join(format!("fast-stats-alerts-{}", std::process::id()));
+        let alerts = Alerts::new(Some(path.clone()));
+        let var = json!({"type": "threshold", "field": "var", "op": ">=", "value": 2.5});
+        let first = alerts.create(rule(var.clone())).unwrap();
+        let second = alerts.create(rule(var)).unwrap();
+        assert_eq!((first.id, second.id), (1, 2));
+        assert!(alerts.delete(first.id).unwrap());
+        assert!(!alerts.delete(first.id).unwrap());
+
+        let restored = Alerts::new(Some(path.clone()));
+        assert_eq!(restored.load().unwrap(), 1);
+        assert_eq!(restored.list(), vec![second]);
+        // ids are not reused
+        let jump = json!({"type": "moves", "field": "avg", "sigmas": 3});
+        assert_eq!(restored.create(rule(jump)).unwrap().id, 3);
+        std::fs::remove_file(path).unwrap();
+    }
+}
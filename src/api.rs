@@ -1,29 +1,46 @@
-use crate::app_state::SYMBOLS;
+use crate::alerts::AlertRule;
+use crate::app_state::{SYMBOLS, config};
+use crate::binary_batch::BatchBody;
+use crate::candles::{Candle, Candles};
 use crate::error::Error;
-use crate::symbol_aggregator::SymbolAggregator;
+use crate::ewma::EwmaStats;
+use crate::registry::Batch;
+use crate::returns::ReturnStats;
+use crate::symbol_aggregator::StatsOptions;
+use crate::variance::{ShiftedSums, WeightedSums};
+use crate::windows::Windows;
 use axum::{
-    extract::{Json, Query},
+    extract::{Json, Path, Query},
     http::StatusCode,
     response::IntoResponse,
 };
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use serde_json::json;
-use std::sync::Mutex;
+use std::collections::BTreeMap;
+use std::str::FromStr;
 
 #[derive(Deserialize)]
 pub struct AddBatchRequest {
     pub symbol: String,
     pub values: Vec<f64>,
+    /// milliseconds since unix epoch, one per value; values are observed now if missing
+    pub timestamps: Option<Vec<u64>>,
+    /// non-negative weights, e.g. traded volumes, one per value; values weigh `1` if missing
+    pub weights: Option<Vec<f64>>,
 }
 
-pub async fn add_batch(Json(payload): Json<AddBatchRequest>) -> impl IntoResponse {
-    if payload.values.len() > 10_000 {
-        return Err(Error::TooManyValues);
-    }
-
-    if payload.symbol.trim().is_empty() {
-        return Err(Error::InvalidRequest("Symbol is empty".into()));
-    }
+/// Adds a batch given as JSON, or in the binary format of [`crate::binary_batch`].
+pub async fn add_batch(body: BatchBody) -> Result<impl IntoResponse, Error> {
+    let payload = body.batch;
+    validate_batch(
+        &Batch {
+            symbol: &payload.symbol,
+            values: &payload.values,
+            timestamps: payload.timestamps.as_deref(),
+            weights: payload.weights.as_deref(),
+        },
+        body.max_batch_size,
+    )?;
 
     tracing::info!(
         "POST /add_batch/ - symbol: {}, values: {}",
@@ -31,20 +48,262 @@ pub async fn add_batch(Json(payload): Json<AddBatchRequest>) -> impl IntoRespons
         payload.values.len()
     );
 
-    let entry = SYMBOLS
-        .entry(payload.symbol.clone())
-        .or_insert_with(|| Mutex::new(SymbolAggregator::new()));
-
-    let mut agg = entry.lock().unwrap();
-    agg.add_batch(&payload.values);
+    let windows = config().windows_for(&payload.symbol);
+    SYMBOLS.add_batch(
+        &payload.symbol,
+        &payload.values,
+        payload.timestamps.as_deref(),
+        payload.weights.as_deref(),
+        windows,
+    )?;
 
     Ok((StatusCode::CREATED, Json(json!({ "status": "ok" }))))
 }
 
+/// Checks a batch against the limits of `/add_batch/`, with up to `max_batch_size` values.
+pub fn validate_batch(batch: &Batch<'_>, max_batch_size: usize) -> Result<(), Error> {
+    let Batch {
+        symbol,
+        values,
+        timestamps,
+        weights,
+    } = *batch;
+    if values.len() > max_batch_size {
+        return Err(Error::TooManyValues(max_batch_size));
+    }
+
+    if symbol.trim().is_empty() {
+        return Err(Error::InvalidRequest("Symbol is empty".into()));
+    }
+
+    if let Some(timestamps) = timestamps {
+        if timestamps.len() != values.len() {
+            return Err(Error::InvalidRequest(
+                "Number of timestamps must match number of values".into(),
+            ));
+        }
+        if timestamps.windows(2).any(|pair| pair[0] > pair[1]) {
+            return Err(Error::InvalidRequest("Timestamps must not decrease".into()));
+        }
+    }
+
+    if let Some(weights) = weights {
+        if weights.len() != values.len() {
+            return Err(Error::InvalidRequest(
+                "Number of weights must match number of values".into(),
+            ));
+        }
+        if !weights
+            .iter()
+            .all(|weight| weight.is_finite() && *weight >= 0.0)
+        {
+            return Err(Error::InvalidRequest(
+                "Weights must be finite and non-negative".into(),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Batches of many symbols, each either just values, or values with timestamps and weights
+/// as in `/add_batch/`.
+///
+/// With `atomic`, batches of all symbols are applied, or none if any of them is invalid
+/// or does not fit into the memory budget. Otherwise each is applied on its own,
+/// and results tell which ones failed.
+#[derive(Deserialize)]
+pub struct AddBatchesRequest {
+    pub batches: BTreeMap<String, BatchInput>,
+    #[serde(default)]
+    pub atomic: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum BatchInput {
+    Values(Vec<f64>),
+    Batch {
+        values: Vec<f64>,
+        timestamps: Option<Vec<u64>>,
+        weights: Option<Vec<f64>>,
+    },
+}
+
+impl BatchInput {
+    fn batch<'a>(&'a self, symbol: &'a str) -> Batch<'a> {
+        match self {
+            Self::Values(values) => Batch {
+                symbol,
+                values,
+                timestamps: None,
+                weights: None,
+            },
+            Self::Batch {
+                values,
+                timestamps,
+                weights,
+            } => Batch {
+                symbol,
+                values,
+                timestamps: timestamps.as_deref(),
+                weights: weights.as_deref(),
+            },
+        }
+    }
+}
+
+/// Result of a batch of a symbol applied on its own.
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchResult {
+    Status(String),
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AddBatchesResponse {
+    pub results: BTreeMap<String, BatchResult>,
+}
+
+pub async fn add_batches(Json(payload): Json<AddBatchesRequest>) -> impl IntoResponse {
+    if payload.batches.is_empty() {
+        return Err(Error::InvalidRequest("No batches".into()));
+    }
+    let batches: Vec<Batch> = payload
+        .batches
+        .iter()
+        .map(|(symbol, input)| input.batch(symbol))
+        .collect();
+    let values: usize = batches.iter().map(|batch| batch.values.len()).sum();
+    tracing::info!(
+        "POST /add_batches/ - symbols: {}, values: {values}, atomic: {}",
+        batches.len(),
+        payload.atomic
+    );
+
+    let results: BTreeMap<_, _> = if payload.atomic {
+        for batch in &batches {
+            validate_batch(batch, config().max_batch_size).map_err(|err| match err {
+                Error::InvalidRequest(reason) => {
+                    Error::InvalidRequest(format!("{reason} (symbol {})", batch.symbol))
+                }
+                err => err,
+            })?;
+        }
+        SYMBOLS.add_batches(&batches, |symbol| config().windows_for(symbol))?;
+        batches
+            .iter()
+            .map(|batch| (batch.symbol.to_string(), BatchResult::Status("ok".into())))
+            .collect()
+    } else {
+        batches
+            .iter()
+            .map(|batch| {
+                let result = validate_batch(batch, config().max_batch_size).and_then(|_| {
+                    let windows = config().windows_for(batch.symbol);
+                    SYMBOLS.add_batch(
+                        batch.symbol,
+                        batch.values,
+                        batch.timestamps,
+                        batch.weights,
+                        windows,
+                    )
+                });
+                let result = match result {
+                    Ok(()) => BatchResult::Status("ok".into()),
+                    Err(err) => BatchResult::Error(err.to_string()),
+                };
+                (batch.symbol.to_string(), result)
+            })
+            .collect()
+    };
+
+    // with nothing applied the request failed, still with the reason of each batch
+    let status = match results
+        .values()
+        .any(|result| matches!(result, BatchResult::Status(_)))
+    {
+        true => StatusCode::CREATED,
+        false => StatusCode::BAD_REQUEST,
+    };
+    Ok((status, Json(AddBatchesResponse { results })))
+}
+
+/// Window is selected either by its level `k` (`1` is the smallest count window,
+/// time windows follow count ones), or by `window` name or span, e.g. `1000` or `5m`.
+/// Without either, or with `k=all`, stats of all windows are returned.
 #[derive(Deserialize)]
 pub struct StatsRequest {
     pub symbol: String,
-    pub k: u32,
+    pub k: Option<Level>,
+    pub window: Option<String>,
+    /// comma separated quantiles to return besides the summary ones, e.g. `0.9,0.99`
+    pub q: Option<String>,
+    /// whether to return `std`, `skew` and `kurtosis`
+    pub moments: Option<bool>,
+    /// whether to return stats of returns, if the service keeps them
+    pub returns: Option<bool>,
+    /// returns per year to annualize their volatility and Sharpe ratio; configured one if missing
+    pub periods_per_year: Option<f64>,
+}
+
+/// Level `k` of a window, or `all` of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawLevel")]
+pub enum Level {
+    All,
+    K(u32),
+}
+
+/// Level as JSON number, or as text in query strings and JSON alike.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawLevel {
+    K(u32),
+    Text(String),
+}
+
+impl TryFrom<RawLevel> for Level {
+    type Error = String;
+
+    fn try_from(raw: RawLevel) -> Result<Self, Self::Error> {
+        match raw {
+            RawLevel::K(k) => Ok(Self::K(k)),
+            RawLevel::Text(k) => k.parse(),
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(k: &str) -> Result<Self, Self::Err> {
+        match k {
+            "all" => Ok(Self::All),
+            _ => k
+                .parse()
+                .map(Self::K)
+                .map_err(|_| format!("`k` must be a level or `all`, got {k:?}")),
+        }
+    }
+}
+
+impl Serialize for Level {
+    /// Just like it is given: number, or `"all"`.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Self::All => serializer.serialize_str("all"),
+            Self::K(k) => serializer.serialize_u32(k),
+        }
+    }
+}
+
+/// Stats of single window, or of all windows keyed by `k`.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum SymbolStats {
+    Window(Box<StatsResult>),
+    All(BTreeMap<u32, StatsResult>),
 }
 
 // the output to our `create_user` handler
@@ -55,18 +314,622 @@ pub struct StatsResult {
     pub last: f64,
     pub avg: f64,
     pub var: f64,
+    /// quantiles are `null` for windows which do not maintain them
+    pub median: f64,
+    pub p5: f64,
+    pub p25: f64,
+    pub p75: f64,
+    pub p95: f64,
+    /// upper bound of rank error of quantiles, as a fraction of window length;
+    /// `0` for exact quantiles
+    pub rank_error: f64,
+    /// quantiles requested by `q`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub quantiles: Vec<Quantile>,
+    /// standard deviation, population skewness and excess kurtosis, if requested by `moments`;
+    /// `skew` and `kurtosis` are `null` if all values are equal, or any was skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub std: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skew: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kurtosis: Option<f64>,
+    /// number of values in the window skipped by `skew` and `kurtosis`, if requested by
+    /// `moments`, as their fourth power relative to the others overflows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moments_skipped: Option<usize>,
+    /// exponentially weighted stats of the symbol, one per configured half-life
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub ewma: Vec<EwmaStats>,
+    /// weighted `avg` and `var`, and sum of weights, once the symbol got any weights;
+    /// values added without weights weigh `1`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vwap: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub weighted_var: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<f64>,
+    /// stats of returns between consecutive values of count windows, if requested by `returns`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub returns: Option<ReturnStats>,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Quantile {
+    pub q: f64,
+    pub value: f64,
+}
+
+impl Default for StatsResult {
+    /// All stats unknown, i.e. `null` in responses.
+    fn default() -> Self {
+        Self {
+            min: f64::NAN,
+            max: f64::NAN,
+            last: f64::NAN,
+            avg: f64::NAN,
+            var: f64::NAN,
+            median: f64::NAN,
+            p5: f64::NAN,
+            p25: f64::NAN,
+            p75: f64::NAN,
+            p95: f64::NAN,
+            rank_error: f64::NAN,
+            quantiles: Vec::new(),
+            std: None,
+            skew: None,
+            kurtosis: None,
+            moments_skipped: None,
+            ewma: Vec::new(),
+            vwap: None,
+            weighted_var: None,
+            volume: None,
+            returns: None,
+        }
+    }
+}
+
+impl StatsResult {
+    /// Sets the summary quantiles and `requested` ones from `quantile`, which is `NaN` if unknown.
+    pub fn set_quantiles(&mut self, requested: &[f64], quantile: impl Fn(f64) -> f64) {
+        self.median = quantile(0.5);
+        self.p5 = quantile(0.05);
+        self.p25 = quantile(0.25);
+        self.p75 = quantile(0.75);
+        self.p95 = quantile(0.95);
+        self.quantiles = requested
+            .iter()
+            .map(|&q| Quantile {
+                q,
+                value: quantile(q),
+            })
+            .collect();
+    }
+
+    /// Sets `std` from `var`, and `skew` and `kurtosis` from shifted sums of `count` values.
+    pub fn set_moments(&mut self, sums: &ShiftedSums, count: usize) {
+        let (skew, kurtosis) = sums.skew_kurtosis(count);
+        self.std = Some(self.var.sqrt());
+        self.skew = Some(skew);
+        self.kurtosis = Some(kurtosis);
+        self.moments_skipped = Some(sums.skipped());
+    }
+
+    /// Sets `vwap`, `weighted_var` and `volume` from weighted sums of the window.
+    pub fn set_weighted(&mut self, sums: &WeightedSums) {
+        self.vwap = Some(sums.avg());
+        self.weighted_var = Some(sums.var());
+        self.volume = Some(sums.volume());
+    }
+}
+
+/// Parses comma separated quantiles, e.g. `0.9,0.99`.
+fn parse_quantiles(q: &str) -> Result<Vec<f64>, Error> {
+    q.split(',')
+        .map(|q| match q.trim().parse::<f64>() {
+            Ok(q) if (0.0..=1.0).contains(&q) => Ok(q),
+            _ => Err(Error::InvalidRequest(format!(
+                "Quantile must be a number between 0 and 1, got {q:?}"
+            ))),
+        })
+        .collect()
 }
 pub async fn get_stats(Query(req): Query<StatsRequest>) -> impl IntoResponse {
-    tracing::info!("GET /stats/ - symbol: {}, k: {}", req.symbol, req.k);
+    tracing::info!(
+        "GET /stats/ - symbol: {}, k: {:?}, window: {:?}, q: {:?}, moments: {:?}, returns: {:?}",
+        req.symbol,
+        req.k,
+        req.window,
+        req.q,
+        req.moments,
+        req.returns
+    );
+
+    let options = stats_options(
+        req.q.as_deref(),
+        req.moments,
+        req.returns,
+        req.periods_per_year,
+    )?;
+    symbol_stats(&req.symbol, req.k, req.window.as_deref(), &options).map(Json)
+}
+
+/// Options of stats given by request parameters, see [`StatsRequest`].
+pub fn stats_options(
+    q: Option<&str>,
+    moments: Option<bool>,
+    returns: Option<bool>,
+    periods_per_year: Option<f64>,
+) -> Result<StatsOptions, Error> {
+    let periods_per_year = periods_per_year.unwrap_or(config().periods_per_year);
+    if !(periods_per_year.is_finite() && periods_per_year > 0.0) {
+        return Err(Error::InvalidRequest(
+            "Periods per year must be a positive number".into(),
+        ));
+    }
+
+    Ok(StatsOptions {
+        now: SYMBOLS.now(),
+        quantiles: q.map(parse_quantiles).transpose()?.unwrap_or_default(),
+        moments: moments.unwrap_or(false),
+        returns: returns.unwrap_or(false),
+        periods_per_year,
+    })
+}
+
+/// Stats of the `symbol` window selected by `k` or `window`, or of all windows without either.
+fn symbol_stats(
+    symbol: &str,
+    k: Option<Level>,
+    window: Option<&str>,
+    options: &StatsOptions,
+) -> Result<SymbolStats, Error> {
+    let k = match (k, window) {
+        (Some(_), Some(_)) => {
+            return Err(Error::InvalidRequest(
+                "At most one of `k` or `window` is allowed".into(),
+            ));
+        }
+        (Some(Level::K(k)), None) => Some(k),
+        _ => None,
+    };
+
+    if let Some(entry) = SYMBOLS.get(symbol) {
+        // locked once, so stats of all levels come from the same state
+        let mut agg = entry.aggregator.lock().unwrap();
+        if k.is_none() && window.is_none() {
+            if let Some(stats) = agg.get_all_stats_with(options) {
+                return Ok(SymbolStats::All(stats));
+            }
+        } else {
+            let k = selected_level(agg.windows(), k, window)?;
+            if let Some(stats) = agg.get_stats_with(k, options) {
+                return Ok(SymbolStats::Window(Box::new(stats)));
+            }
+        }
+    }
+
+    let err = Error::SymbolNotFound(symbol.to_string());
+    tracing::warn!("{err}");
+    Err(err)
+}
+
+/// Stats of many symbols and windows at once: `queries`, followed by every level of `levels`
+/// of every symbol of `symbols`, or by all levels of them if `levels` are empty.
+///
+/// Options of stats apply to all of them, just like in [`StatsRequest`].
+#[derive(Deserialize)]
+pub struct StatsQueryRequest {
+    #[serde(default)]
+    pub queries: Vec<StatsQuery>,
+    #[serde(default)]
+    pub symbols: Vec<String>,
+    #[serde(default)]
+    pub levels: Vec<Level>,
+    pub q: Option<String>,
+    pub moments: Option<bool>,
+    pub returns: Option<bool>,
+    pub periods_per_year: Option<f64>,
+}
+
+/// Window of a symbol, selected just like by [`StatsRequest`].
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StatsQuery {
+    pub symbol: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub k: Option<Level>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub window: Option<String>,
+}
+
+/// Outcome of a [`StatsQuery`]: its `stats`, or why there are none.
+#[derive(Serialize)]
+pub struct StatsQueryResult {
+    #[serde(flatten)]
+    pub query: StatsQuery,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<SymbolStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct StatsQueryResponse {
+    /// one per query, in order
+    pub results: Vec<StatsQueryResult>,
+}
+
+pub async fn query_stats(Json(req): Json<StatsQueryRequest>) -> impl IntoResponse {
+    tracing::info!(
+        "POST /stats/query - queries: {}, symbols: {}, levels: {}",
+        req.queries.len(),
+        req.symbols.len(),
+        req.levels.len()
+    );
+
+    let options = stats_options(
+        req.q.as_deref(),
+        req.moments,
+        req.returns,
+        req.periods_per_year,
+    )?;
+
+    let levels = if req.levels.is_empty() {
+        vec![Level::All]
+    } else {
+        req.levels
+    };
+    let products = req.symbols.iter().flat_map(|symbol| {
+        levels.iter().map(|&k| StatsQuery {
+            symbol: symbol.clone(),
+            k: Some(k),
+            window: None,
+        })
+    });
+    let results = req
+        .queries
+        .into_iter()
+        .chain(products)
+        .map(|query| {
+            match symbol_stats(&query.symbol, query.k, query.window.as_deref(), &options) {
+                Ok(stats) => StatsQueryResult {
+                    query,
+                    stats: Some(stats),
+                    error: None,
+                },
+                Err(err) => StatsQueryResult {
+                    query,
+                    stats: None,
+                    error: Some(err.to_string()),
+                },
+            }
+        })
+        .collect();
+    Ok::<_, Error>(Json(StatsQueryResponse { results }))
+}
+
+/// Checks that window is selected either by `k` or by `window`, see [`StatsRequest`].
+fn check_window_selector(k: Option<u32>, window: Option<&str>) -> Result<(), Error> {
+    if k.is_some() == window.is_some() {
+        return Err(Error::InvalidRequest(
+            "Exactly one of `k` or `window` is required".into(),
+        ));
+    }
+    Ok(())
+}
+
+/// Level `k` of window selected by [`check_window_selector`] among `windows`.
+fn selected_level(windows: &Windows, k: Option<u32>, window: Option<&str>) -> Result<u32, Error> {
+    match (k, window) {
+        (Some(k), _) if (1..=windows.len() as u32).contains(&k) => Ok(k),
+        (Some(k), _) => Err(Error::InvalidRequest(format!("Unknown level: {k}"))),
+        (None, Some(window)) => match windows.level_of(window) {
+            Some(level) => Ok(level as u32 + 1),
+            None => Err(Error::InvalidRequest(format!("Unknown window: {window}"))),
+        },
+        (None, None) => unreachable!("checked by `check_window_selector`"),
+    }
+}
+
+/// Count window of the `symbol`, selected just like by [`StatsRequest`],
+/// for stats of its registered pair with the `benchmark`.
+#[derive(Deserialize)]
+pub struct PairStatsRequest {
+    pub symbol: String,
+    pub benchmark: String,
+    pub k: Option<u32>,
+    pub window: Option<String>,
+}
+
+pub async fn get_pair_stats(Query(req): Query<PairStatsRequest>) -> impl IntoResponse {
+    tracing::info!(
+        "GET /pair_stats/ - symbol: {}, benchmark: {}, k: {:?}, window: {:?}",
+        req.symbol,
+        req.benchmark,
+        req.k,
+        req.window
+    );
+
+    check_window_selector(req.k, req.window.as_deref())?;
+    let Some(pair) = SYMBOLS.pair(&req.symbol, &req.benchmark) else {
+        let err = Error::PairNotFound(format!("{}:{}", req.symbol, req.benchmark));
+        tracing::warn!("{err}");
+        return Err(err);
+    };
+    let pair = pair.lock().unwrap();
+    let k = selected_level(pair.windows(), req.k, req.window.as_deref())?;
+    let stats = pair.stats(k);
+    match stats {
+        Some(stats) => Ok(Json(stats)),
+        None => Err(Error::InvalidRequest(format!(
+            "Pairs have count windows only, and there is no count window {k}"
+        ))),
+    }
+}
+
+/// Candles of window selected just like by [`StatsRequest`]: consecutive blocks of window size
+/// for count windows, or intervals of window duration for time windows.
+#[derive(Deserialize)]
+pub struct CandlesRequest {
+    pub symbol: String,
+    pub k: Option<u32>,
+    pub window: Option<String>,
+    /// max number of the latest closed candles to return; all kept ones if missing
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CandlesResult {
+    /// closed candles, oldest first
+    pub candles: Vec<Candle>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CurrentCandleResult {
+    /// `null` if the current block has no values yet
+    pub candle: Option<Candle>,
+}
+
+pub async fn get_candles(Query(req): Query<CandlesRequest>) -> impl IntoResponse {
+    tracing::info!(
+        "GET /candles/ - symbol: {}, k: {:?}, window: {:?}, limit: {:?}",
+        req.symbol,
+        req.k,
+        req.window,
+        req.limit
+    );
+    with_candles(&req, |candles| CandlesResult {
+        candles: candles
+            .closed(req.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect(),
+    })
+}
+
+pub async fn get_current_candle(Query(req): Query<CandlesRequest>) -> impl IntoResponse {
+    tracing::info!(
+        "GET /candles/current/ - symbol: {}, k: {:?}, window: {:?}",
+        req.symbol,
+        req.k,
+        req.window
+    );
+    with_candles(&req, |candles| CurrentCandleResult {
+        candle: candles.forming().cloned(),
+    })
+}
+
+/// Responds with `result` of candles selected by `req`.
+fn with_candles<T: Serialize>(
+    req: &CandlesRequest,
+    result: impl FnOnce(&Candles) -> T,
+) -> Result<Json<T>, Error> {
+    check_window_selector(req.k, req.window.as_deref())?;
+    if config().candles == 0 {
+        return Err(Error::InvalidRequest("Candles are disabled".into()));
+    }
 
     if let Some(entry) = SYMBOLS.get(&req.symbol) {
-        let mut agg = entry.lock().unwrap();
-        if let Some(stats) = agg.get_stats(req.k) {
-            return Ok(Json(stats));
+        let mut agg = entry.aggregator.lock().unwrap();
+        let k = selected_level(agg.windows(), req.k, req.window.as_deref())?;
+        if let Some(candles) = agg.candles(k, SYMBOLS.now()) {
+            return Ok(Json(result(candles)));
         }
     }
 
-    let err = Error::SymbolNotFound(req.symbol);
+    let err = Error::SymbolNotFound(req.symbol.clone());
     tracing::warn!("{err}");
     Err(err)
 }
+
+/// Alert rules, ordered by id.
+#[derive(Serialize, Deserialize)]
+pub struct AlertRulesResult {
+    pub rules: Vec<AlertRule>,
+}
+
+pub async fn list_alerts() -> Json<AlertRulesResult> {
+    Json(AlertRulesResult {
+        rules: SYMBOLS.alerts().list(),
+    })
+}
+
+pub async fn get_alert(Path(id): Path<u64>) -> Result<Json<AlertRule>, Error> {
+    SYMBOLS
+        .alerts()
+        .get(id)
+        .map(Json)
+        .ok_or(Error::AlertNotFound(id))
+}
+
+pub async fn create_alert(Json(rule): Json<AlertRule>) -> Result<impl IntoResponse, Error> {
+    validate_rule(&rule)?;
+    let rule = SYMBOLS
+        .alerts()
+        .create(rule)
+        .map_err(|err| anyhow::anyhow!("Unable to save alert rules: {err}"))?;
+    tracing::info!("POST /alerts/ - rule {} of symbol {}", rule.id, rule.symbol);
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+pub async fn update_alert(
+    Path(id): Path<u64>,
+    Json(rule): Json<AlertRule>,
+) -> Result<Json<AlertRule>, Error> {
+    validate_rule(&rule)?;
+    tracing::info!("PUT /alerts/{id} - symbol {}", rule.symbol);
+    SYMBOLS
+        .alerts()
+        .update(id, rule)
+        .map_err(|err| anyhow::anyhow!("Unable to save alert rules: {err}"))?
+        .map(Json)
+        .ok_or(Error::AlertNotFound(id))
+}
+
+pub async fn delete_alert(Path(id): Path<u64>) -> Result<impl IntoResponse, Error> {
+    tracing::info!("DELETE /alerts/{id}");
+    let deleted = SYMBOLS
+        .alerts()
+        .delete(id)
+        .map_err(|err| anyhow::anyhow!("Unable to save alert rules: {err}"))?;
+    match deleted {
+        true => Ok(Json(json!({ "status": "ok" }))),
+        false => Err(Error::AlertNotFound(id)),
+    }
+}
+
+/// Checks `rule`, including its levels among windows of its symbol, and that it has webhooks.
+fn validate_rule(rule: &AlertRule) -> Result<(), Error> {
+    rule.validate().map_err(Error::InvalidRequest)?;
+    let windows = config().windows_for(&rule.symbol);
+    if let Some(k) = rule
+        .levels()
+        .find(|&k| k == 0 || k as usize > windows.len())
+    {
+        return Err(Error::InvalidRequest(format!(
+            "Symbol {} has no window {k}",
+            rule.symbol
+        )));
+    }
+    if rule.webhooks.is_empty() && config().alert_webhooks.is_empty() {
+        return Err(Error::InvalidRequest(
+            "Rule has no webhooks, and none are configured".into(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    async fn query(req: Value) -> Value {
+        let response = query_stats(Json(serde_json::from_value(req).unwrap()))
+            .await
+            .into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_query_stats() {
+        let windows = Windows::geometric(2, 10);
+        for symbol in ["QUERY_A", "QUERY_B"] {
+            SYMBOLS
+                .add_batch(symbol, &[1.0, 2.0, 3.0], None, None, &windows)
+                .unwrap();
+        }
+
+        let response = query(json!({
+            "queries": [
+                {"symbol": "QUERY_A", "k": 1},
+                {"symbol": "QUERY_B", "window": "100"},
+                {"symbol": "QUERY_A", "k": "9"},
+                {"symbol": "QUERY_MISSING", "k": 1},
+            ],
+            "symbols": ["QUERY_A", "QUERY_B"],
+            "levels": [2, "all"],
+        }))
+        .await;
+        let results = response["results"].as_array().unwrap();
+        assert_eq!(results.len(), 8);
+        assert_eq!(results[0]["stats"]["avg"], 2.0);
+        assert_eq!(results[1]["window"], "100");
+        assert_eq!(results[1]["stats"]["max"], 3.0);
+        assert_eq!(results[2]["k"], 9);
+        assert!(results[2]["stats"].is_null());
+        assert_eq!(results[2]["error"], "Invalid request: Unknown level: 9");
+        assert_eq!(results[3]["error"], "Symbol not found: QUERY_MISSING");
+        assert_eq!(results[4]["k"], 2);
+        assert_eq!(results[5]["k"], "all");
+        assert_eq!(results[5]["stats"]["2"]["last"], 3.0);
+    }
+
+    #[tokio::test]
+    async fn test_add_batches() {
+        let add = |req: Value| async move {
+            add_batches(Json(serde_json::from_value(req).unwrap()))
+                .await
+                .into_response()
+        };
+        let invalid = json!({
+            "BATCHES_A": [1.0, 2.0],
+            "BATCHES_B": {"values": [3.0], "weights": [-1.0]},
+        });
+
+        let response = add(json!({"batches": invalid, "atomic": true})).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(SYMBOLS.peek("BATCHES_A").is_none());
+
+        let response = add(json!({"batches": invalid})).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let AddBatchesResponse { results } = serde_json::from_slice(&body).unwrap();
+        assert_eq!(results["BATCHES_A"], BatchResult::Status("ok".into()));
+        assert!(
+            matches!(&results["BATCHES_B"], BatchResult::Error(err) if err.contains("Weights"))
+        );
+        assert!(SYMBOLS.peek("BATCHES_B").is_none());
+
+        let response = add(json!({"batches": {
+            "BATCHES_C": {"values": [1.0], "weights": [-1.0]},
+            "BATCHES_D": {"values": [1.0], "timestamps": [1000, 2000]},
+        }}))
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let AddBatchesResponse { results } = serde_json::from_slice(&body).unwrap();
+        assert!(matches!(&results["BATCHES_C"], BatchResult::Error(_)));
+        assert!(
+            matches!(&results["BATCHES_D"], BatchResult::Error(err) if err.contains("timestamps"))
+        );
+        assert!(SYMBOLS.peek("BATCHES_C").is_none());
+
+        let response = add(json!({
+            "batches": {
+                "BATCHES_A": [3.0],
+                "BATCHES_B": {"values": [3.0, 5.0], "timestamps": [1000, 2000], "weights": [1.0, 3.0]},
+            },
+            "atomic": true,
+        }))
+        .await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        let entry = SYMBOLS.peek("BATCHES_A").unwrap();
+        assert_eq!(
+            entry.aggregator.lock().unwrap().get_stats(1).unwrap().last,
+            3.0
+        );
+        let entry = SYMBOLS.peek("BATCHES_B").unwrap();
+        assert_eq!(
+            entry.aggregator.lock().unwrap().get_stats(2).unwrap().vwap,
+            Some(4.5)
+        );
+    }
+}
@@ -1,11 +1,36 @@
-use dashmap::DashMap;
-use std::sync::{LazyLock, Mutex};
+use std::sync::{LazyLock, OnceLock};
 
-use crate::symbol_aggregator::SymbolAggregator;
-
-pub const MAX_K: usize = 8;
-pub const RADIX: usize = 10;
+use crate::alerts::Alerts;
+use crate::config::Config;
+use crate::metrics::Metrics;
+use crate::pairs::PairAggregator;
+use crate::registry::SymbolRegistry;
+use crate::symbol_aggregator::AggregatorOptions;
 
 /// There will NOT be concurrent requests for single symbol.
-pub static SYMBOLS: LazyLock<DashMap<String, Mutex<SymbolAggregator<MAX_K, RADIX>>>> =
-    LazyLock::new(DashMap::new);
+pub static SYMBOLS: LazyLock<SymbolRegistry> = LazyLock::new(|| {
+    SymbolRegistry::new(config().memory_budget, config().memory_policy)
+        .with_options(AggregatorOptions {
+            exact_quantiles_max_window: config().exact_quantiles_max_window,
+            approx_quantiles: config().approx_quantiles,
+            ewma_half_lives: config().ewma_half_lives.clone(),
+            candles: config().candles,
+            returns: config().returns,
+        })
+        .with_pairs(config().pairs.iter().map(|spec| {
+            let windows = config().windows_for(&spec.symbol);
+            let pair = PairAggregator::new(config().pair_alignment, windows);
+            (spec.clone(), pair)
+        }))
+        .with_alerts(Alerts::new(config().alerts_path.clone()))
+});
+
+/// Requests served so far, see [`crate::metrics`].
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::default);
+
+/// Set once by `start_server`; defaults are used when app is built without it (tests, benches).
+pub static CONFIG: OnceLock<Config> = OnceLock::new();
+
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
@@ -0,0 +1,268 @@
+//! Binary bodies of `/add_batch/`, sparing clients of large batches the cost of JSON floats.
+//!
+//! Bodies with `Content-Type: application/x-fast-stats-batch` are, all numbers little-endian:
+//! ```txt
+//! u8        flags: 1 if there are timestamps, 2 if there are weights
+//! u16       length of symbol in bytes
+//! [u8]      symbol, UTF-8
+//! u32       number of values n
+//! [f64; n]  values
+//! [u64; n]  timestamps, milliseconds since unix epoch, if flagged
+//! [f64; n]  weights, if flagged
+//! ```
+use crate::api::AddBatchRequest;
+use crate::app_state::config;
+use crate::error::Error;
+use axum::extract::{FromRequest, Json, Request};
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+
+pub const CONTENT_TYPE: &str = "application/x-fast-stats-batch";
+
+const TIMESTAMPS: u8 = 1;
+const WEIGHTS: u8 = 2;
+
+/// Body of `/add_batch/`, JSON or binary by its `Content-Type`.
+pub struct BatchBody {
+    pub batch: AddBatchRequest,
+    /// max number of values in batches of the format of the body
+    pub max_batch_size: usize,
+}
+
+impl<S: Send + Sync> FromRequest<S> for BatchBody {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let binary = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .is_some_and(|mime| mime.trim().eq_ignore_ascii_case(CONTENT_TYPE));
+        if !binary {
+            let Json(batch) = Json::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            return Ok(Self {
+                batch,
+                max_batch_size: config().max_batch_size,
+            });
+        }
+
+        let max_batch_size = config().max_binary_batch_size;
+        let body = axum::body::to_bytes(req.into_body(), max_body_len(max_batch_size))
+            .await
+            .map_err(|err| {
+                Error::InvalidRequest(format!("Unable to read body: {err}")).into_response()
+            })?;
+        let batch = decode(&body, max_batch_size).map_err(IntoResponse::into_response)?;
+        Ok(Self {
+            batch,
+            max_batch_size,
+        })
+    }
+}
+
+/// Length of the largest body of up to `max_batch_size` values.
+fn max_body_len(max_batch_size: usize) -> usize {
+    max_batch_size
+        .saturating_mul(3 * 8)
+        .saturating_add(1 + 2 + u16::MAX as usize + 4)
+}
+
+/// Decodes a batch of up to `max_batch_size` values, which must be finite.
+pub fn decode(body: &[u8], max_batch_size: usize) -> Result<AddBatchRequest, Error> {
+    let mut reader = Reader(body);
+    let [flags] = reader.array()?;
+    if flags & !(TIMESTAMPS | WEIGHTS) != 0 {
+        return Err(Error::InvalidRequest(format!("Unknown flags {flags:#x}")));
+    }
+    let symbol_len = u16::from_le_bytes(reader.array()?) as usize;
+    let symbol = std::str::from_utf8(reader.bytes(symbol_len)?)
+        .map_err(|_| Error::InvalidRequest("Symbol is not UTF-8".into()))?
+        .to_string();
+    let len = u32::from_le_bytes(reader.array()?) as usize;
+    if len > max_batch_size {
+        return Err(Error::TooManyValues(max_batch_size));
+    }
+
+    let values = reader.numbers(len, f64::from_le_bytes)?;
+    if !values.iter().all(|val| val.is_finite()) {
+        return Err(Error::InvalidRequest("Values must be finite".into()));
+    }
+    let timestamps = (flags & TIMESTAMPS != 0)
+        .then(|| reader.numbers(len, u64::from_le_bytes))
+        .transpose()?;
+    let weights = (flags & WEIGHTS != 0)
+        .then(|| reader.numbers(len, f64::from_le_bytes))
+        .transpose()?;
+    if !reader.0.is_empty() {
+        return Err(Error::InvalidRequest(format!(
+            "Unexpected {} bytes after batch",
+            reader.0.len()
+        )));
+    }
+    Ok(AddBatchRequest {
+        symbol,
+        values,
+        timestamps,
+        weights,
+    })
+}
+
+/// Remaining bytes of a body.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.0.len() < len {
+            return Err(Error::InvalidRequest("Binary batch is truncated".into()));
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.bytes(N)?.try_into().expect("N bytes are taken"))
+    }
+
+    fn numbers<T, const N: usize>(
+        &mut self,
+        len: usize,
+        from_le_bytes: fn([u8; N]) -> T,
+    ) -> Result<Vec<T>, Error> {
+        Ok(self
+            .bytes(len * N)?
+            .chunks_exact(N)
+            .map(|chunk| from_le_bytes(chunk.try_into().expect("chunks have N bytes")))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_state::SYMBOLS;
+    use crate::build_app;
+    use axum::body::Body;
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    fn encode(batch: &AddBatchRequest) -> Vec<u8> {
+        let flags = (batch.timestamps.is_some() as u8 * TIMESTAMPS)
+            | (batch.weights.is_some() as u8 * WEIGHTS);
+        let mut body = vec![flags];
+        body.extend((batch.symbol.len() as u16).to_le_bytes());
+        body.extend(batch.symbol.as_bytes());
+        body.extend((batch.values.len() as u32).to_le_bytes());
+        body.extend(batch.values.iter().flat_map(|val| val.to_le_bytes()));
+        if let Some(timestamps) = &batch.timestamps {
+            body.extend(timestamps.iter().flat_map(|ts| ts.to_le_bytes()));
+        }
+        if let Some(weights) = &batch.weights {
+            body.extend(weights.iter().flat_map(|weight| weight.to_le_bytes()));
+        }
+        body
+    }
+
+    #[test]
+    fn test_decode() {
+        let batch = AddBatchRequest {
+            symbol: "AB".into(),
+            values: vec![1.5, -2.0, 3.25],
+            timestamps: None,
+            weights: Some(vec![1.0, 0.0, 2.0]),
+        };
+        let decoded = decode(&encode(&batch), 3).unwrap();
+        assert_eq!(decoded.symbol, "AB");
+        assert_eq!(decoded.values, batch.values);
+        assert_eq!(decoded.timestamps, None);
+        assert_eq!(decoded.weights, batch.weights);
+
+        let batch = AddBatchRequest {
+            timestamps: Some(vec![1_000, 2_000, 3_000]),
+            ..batch
+        };
+        let decoded = decode(&encode(&batch), 3).unwrap();
+        assert_eq!(decoded.timestamps, batch.timestamps);
+        assert_eq!(decoded.weights, batch.weights);
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let batch = AddBatchRequest {
+            symbol: "AB".into(),
+            values: vec![1.0, 2.0],
+            timestamps: Some(vec![1, 2]),
+            weights: None,
+        };
+        let body = encode(&batch);
+        let message =
+            |body: &[u8], max_batch_size| decode(body, max_batch_size).err().unwrap().to_string();
+
+        assert_eq!(message(&body, 1), "Too many values in batch (max is 1)");
+        assert_eq!(
+            message(&body[..body.len() - 1], 2),
+            "Invalid request: Binary batch is truncated"
+        );
+        assert_eq!(
+            message(&[body.as_slice(), &[0]].concat(), 2),
+            "Invalid request: Unexpected 1 bytes after batch"
+        );
+        assert_eq!(message(&[4], 2), "Invalid request: Unknown flags 0x4");
+
+        let batch = AddBatchRequest {
+            values: vec![1.0, f64::NAN],
+            ..batch
+        };
+        assert_eq!(
+            message(&encode(&batch), 2),
+            "Invalid request: Values must be finite"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_add_binary_batch() {
+        let post = |body: Vec<u8>| async move {
+            let req = Request::post("/add_batch/")
+                .header(header::CONTENT_TYPE, CONTENT_TYPE)
+                .body(Body::from(body))
+                .unwrap();
+            let response = build_app().oneshot(req).await.unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        };
+
+        // beyond the limit of JSON batches
+        let len = config().max_batch_size + 1;
+        let batch = AddBatchRequest {
+            symbol: "BINARY".into(),
+            values: (0..len).map(|i| i as f64).collect(),
+            timestamps: None,
+            weights: None,
+        };
+        let (status, _) = post(encode(&batch)).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let entry = SYMBOLS.peek("BINARY").unwrap();
+        assert_eq!(
+            entry.aggregator.lock().unwrap().get_stats(1).unwrap().last,
+            (len - 1) as f64
+        );
+
+        // rejected by its header, before values are read
+        let max_batch_size = config().max_binary_batch_size;
+        let mut body = encode(&batch);
+        let count_at = 1 + 2 + batch.symbol.len();
+        body[count_at..count_at + 4].copy_from_slice(&(max_batch_size as u32 + 1).to_le_bytes());
+        let (status, message) = post(body).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(
+            message.contains(&format!("(max is {max_batch_size})")),
+            "{message}"
+        );
+    }
+}
@@ -0,0 +1,239 @@
+//! OHLC candles of consecutive non-overlapping blocks of values, see [`Candles`].
+use crate::snapshot::{Decoder, Encoder, Persist, SnapshotError};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+/// Open, high, low and close of values of a block, as returned by `/candles/`.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Candle {
+    /// absolute index of the first value of the block for count windows,
+    /// or start of the interval in milliseconds since unix epoch for time windows
+    pub start: u64,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    /// number of values in the block
+    pub count: u64,
+    /// sum of weights of values in the block; values without weights weigh `1`
+    pub volume: f64,
+}
+
+impl Candle {
+    fn new(start: u64, val: f64, weight: f64) -> Self {
+        Self {
+            start,
+            open: val,
+            high: val,
+            low: val,
+            close: val,
+            count: 1,
+            volume: weight,
+        }
+    }
+
+    fn push(&mut self, val: f64, weight: f64) {
+        self.high = self.high.max(val);
+        self.low = self.low.min(val);
+        self.close = val;
+        self.count += 1;
+        self.volume += weight;
+    }
+}
+
+/// Candles of a window: blocks of `span` values, or `span` milliseconds for time windows,
+/// aligned to multiples of `span`, i.e. block `b` covers positions `[b * span, (b + 1) * span)`.
+///
+/// Unlike windows, blocks do not slide, so nothing is evicted: the forming candle is updated
+/// by every value in `O(1)`, without the ring or monotonic queues, and closed once a position
+/// past its block is seen. Only the latest `history` closed candles are kept.
+///
+/// Time intervals without values have no candle.
+pub struct Candles {
+    span: u64,
+    history: usize,
+    /// oldest first
+    closed: VecDeque<Candle>,
+    forming: Option<Candle>,
+}
+
+impl Candles {
+    /// Creates candles of non-zero `span`, keeping `history` closed ones.
+    pub fn new(span: u64, history: usize) -> Self {
+        Self {
+            span,
+            history,
+            closed: VecDeque::new(),
+            forming: None,
+        }
+    }
+
+    /// Closes the forming candle if `position`, an index or time, is past its block.
+    pub fn advance(&mut self, position: u64) {
+        if let Some(forming) = &self.forming
+            && position / self.span > forming.start / self.span
+        {
+            if self.closed.len() == self.history {
+                self.closed.pop_front();
+            }
+            self.closed.extend(self.forming.take());
+        }
+    }
+
+    /// Adds `val` of `weight` at `position`, which never decreases.
+    pub fn push(&mut self, position: u64, val: f64, weight: f64) {
+        self.advance(position);
+        match &mut self.forming {
+            Some(forming) => forming.push(val, weight),
+            None => {
+                let start = position - position % self.span;
+                self.forming = Some(Candle::new(start, val, weight));
+            }
+        }
+    }
+
+    /// The latest `limit` closed candles, oldest first.
+    pub fn closed(&self, limit: usize) -> impl Iterator<Item = &Candle> {
+        self.closed.range(self.closed.len().saturating_sub(limit)..)
+    }
+
+    /// Candle of the current block, if it has any values.
+    pub fn forming(&self) -> Option<&Candle> {
+        self.forming.as_ref()
+    }
+
+    /// Takes closed and forming candles of `decoded` ones, keeping the latest `history`.
+    pub fn restore(&mut self, decoded: Candles) {
+        self.closed = decoded.closed;
+        while self.closed.len() > self.history {
+            self.closed.pop_front();
+        }
+        self.forming = decoded.forming;
+    }
+
+    /// Approximate number of bytes allocated by closed candles.
+    pub fn memory_usage(&self) -> usize {
+        size_of::<Self>() + self.closed.capacity() * size_of::<Candle>()
+    }
+}
+
+impl Persist for Candle {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.u64(self.start)?;
+        enc.f64(self.open)?;
+        enc.f64(self.high)?;
+        enc.f64(self.low)?;
+        enc.f64(self.close)?;
+        enc.u64(self.count)?;
+        enc.f64(self.volume)
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            start: dec.u64()?,
+            open: dec.f64()?,
+            high: dec.f64()?,
+            low: dec.f64()?,
+            close: dec.f64()?,
+            count: dec.u64()?,
+            volume: dec.f64()?,
+        })
+    }
+}
+
+impl Persist for Candles {
+    /// Span and history are not stored, as those come from windows and options.
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.usize(self.closed.len())?;
+        for candle in &self.closed {
+            candle.encode(enc)?;
+        }
+        match &self.forming {
+            Some(forming) => {
+                enc.u8(1)?;
+                forming.encode(enc)
+            }
+            None => enc.u8(0),
+        }
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        let len = dec.usize()?;
+        let mut closed = VecDeque::new();
+        for _ in 0..len {
+            closed.push_back(Candle::decode(dec)?);
+        }
+        Ok(Self {
+            // set by the aggregator from its windows and options
+            span: 0,
+            history: 0,
+            closed,
+            forming: match dec.u8()? {
+                0 => None,
+                _ => Some(Candle::decode(dec)?),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks() {
+        let mut candles = Candles::new(3, 2);
+        for (i, val) in [5., 7., 1., 2., 4., 3., 9.].into_iter().enumerate() {
+            candles.push(i as u64, val, 1.0);
+        }
+        let closed: Vec<_> = candles.closed(10).cloned().collect();
+        assert_eq!(
+            closed,
+            vec![
+                Candle {
+                    start: 0,
+                    open: 5.,
+                    high: 7.,
+                    low: 1.,
+                    close: 1.,
+                    count: 3,
+                    volume: 3.
+                },
+                Candle {
+                    start: 3,
+                    open: 2.,
+                    high: 4.,
+                    low: 2.,
+                    close: 3.,
+                    count: 3,
+                    volume: 3.
+                },
+            ]
+        );
+        assert_eq!(candles.closed(1).next().unwrap().start, 3);
+        assert_eq!(candles.forming().unwrap().start, 6);
+
+        // only `history` closed candles are kept
+        candles.advance(9);
+        assert!(candles.forming().is_none());
+        assert_eq!(
+            candles.closed(10).map(|c| c.start).collect::<Vec<_>>(),
+            vec![3, 6]
+        );
+    }
+
+    #[test]
+    fn test_intervals_with_gaps() {
+        let mut candles = Candles::new(1000, 10);
+        candles.push(1_200, 1., 2.);
+        candles.push(1_900, 3., 0.5);
+        // nothing in `[2000, 3000)`
+        candles.push(3_100, 2., 1.);
+        let closed: Vec<_> = candles.closed(10).collect();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].start, 1_000);
+        assert_eq!(closed[0].volume, 2.5);
+        assert_eq!(candles.forming().unwrap().start, 3_000);
+    }
+}
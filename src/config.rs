@@ -0,0 +1,795 @@
+//! Typed configuration of the service.
+//!
+//! Every setting is resolved with following precedence (highest first):
+//! 1. command line flags, e.g. `--port 3001`
+//! 2. environment variables, e.g. `FAST_STATS_PORT=3001`
+//! 3. TOML file given by `--config` or `FAST_STATS_CONFIG`
+//! 4. built-in defaults
+//!
+//! Invalid values are reported by [`Config::load`], so the server fails at startup
+//! instead of misbehaving later.
+use crate::pairs::{Alignment, PairSpec};
+use crate::registry::MemoryPolicy;
+use crate::returns::ReturnKind;
+use crate::symbol_aggregator::{DEFAULT_CANDLES, DEFAULT_EXACT_QUANTILES_MAX_WINDOW};
+use crate::wal::FsyncPolicy;
+use crate::webhook::WebhookUrl;
+use crate::windows::{Span, WindowSpec, Windows};
+use clap::Parser;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+use thiserror::Error;
+use tracing_subscriber::filter::LevelFilter;
+
+pub const DEFAULT_PORT: u16 = 3000;
+pub const DEFAULT_MAX_BATCH_SIZE: usize = 10_000;
+pub const DEFAULT_MAX_BINARY_BATCH_SIZE: usize = 1_000_000;
+/// Bodies of larger binary batches, and their WAL records, could exceed 4GiB.
+pub const MAX_BINARY_BATCH_SIZE: usize = 100_000_000;
+pub const DEFAULT_SNAPSHOT_INTERVAL_SECS: u64 = 300;
+pub const DEFAULT_WAL_FSYNC_INTERVAL_MS: u64 = 100;
+pub const DEFAULT_WAL_SEGMENT_SIZE: usize = 64 << 20;
+pub const DEFAULT_WS_THROTTLE_MS: u64 = 100;
+pub const DEFAULT_ALERT_RETRIES: u32 = 5;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Unable to read config file {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("Unable to parse config file {path:?}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[error("Invalid `{field}`: {reason}")]
+    Invalid { field: &'static str, reason: String },
+}
+
+/// Fully resolved and validated configuration.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// address to bind HTTP listener to
+    pub listen: IpAddr,
+    /// port of HTTP listener
+    pub port: u16,
+    /// max number of values accepted by single `add_batch` request
+    pub max_batch_size: usize,
+    /// max number of values accepted by single `add_batch` request in binary format
+    pub max_binary_batch_size: usize,
+    /// max level of logs
+    pub log_level: LevelFilter,
+    /// windows maintained for symbols without own settings
+    pub windows: Windows,
+    /// per symbol overrides
+    pub symbols: HashMap<String, SymbolConfig>,
+    /// max number of bytes used by all symbols, unlimited if `None`
+    pub memory_budget: Option<usize>,
+    /// what to do when memory budget is exceeded
+    pub memory_policy: MemoryPolicy,
+    /// snapshot file restored at startup and saved periodically and on shutdown
+    pub snapshot_path: Option<PathBuf>,
+    /// period of saving snapshots; zero disables periodic snapshots
+    pub snapshot_interval: Duration,
+    /// directory of the write-ahead log, disabled if `None`
+    pub wal_dir: Option<PathBuf>,
+    /// when to flush WAL to disk
+    pub wal_fsync: FsyncPolicy,
+    /// period of flushing WAL with [`FsyncPolicy::Periodic`]
+    pub wal_fsync_interval: Duration,
+    /// size at which WAL segment is rotated
+    pub wal_segment_size: usize,
+    /// largest count window with exact quantiles
+    pub exact_quantiles_max_window: usize,
+    /// whether bigger windows keep quantile sketches
+    pub approx_quantiles: bool,
+    /// half-lives of exponentially weighted stats, in values or time
+    pub ewma_half_lives: Vec<Span>,
+    /// closed candles kept per window; zero disables candles
+    pub candles: usize,
+    /// returns between consecutive values kept by count windows, disabled if `None`
+    pub returns: Option<ReturnKind>,
+    /// returns per year to annualize their volatility and Sharpe ratio
+    pub periods_per_year: f64,
+    /// symbols with benchmarks, whose covariance is kept over count windows of the symbol
+    pub pairs: Vec<PairSpec>,
+    /// how values of pairs are matched
+    pub pair_alignment: Alignment,
+    /// min period of updates pushed to a subscriber; subscribers may ask for a longer one
+    pub ws_throttle: Duration,
+    /// JSON file alert rules are saved to and loaded from; rules are lost on restart if `None`
+    pub alerts_path: Option<PathBuf>,
+    /// webhooks notified by alert rules without own ones
+    pub alert_webhooks: Vec<WebhookUrl>,
+    /// retries of failed webhook deliveries, with exponential backoff
+    pub alert_retries: u32,
+    /// port of TCP listener of line protocol, disabled if `None`
+    pub line_tcp_port: Option<u16>,
+    /// port of UDP listener of line protocol, disabled if `None`
+    pub line_udp_port: Option<u16>,
+}
+
+/// Settings of single symbol.
+#[derive(Debug, Clone)]
+pub struct SymbolConfig {
+    pub windows: Windows,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: DEFAULT_PORT,
+            max_batch_size: DEFAULT_MAX_BATCH_SIZE,
+            max_binary_batch_size: DEFAULT_MAX_BINARY_BATCH_SIZE,
+            log_level: LevelFilter::INFO,
+            windows: Windows::default(),
+            symbols: HashMap::new(),
+            memory_budget: None,
+            memory_policy: MemoryPolicy::default(),
+            snapshot_path: None,
+            snapshot_interval: Duration::from_secs(DEFAULT_SNAPSHOT_INTERVAL_SECS),
+            wal_dir: None,
+            wal_fsync: FsyncPolicy::default(),
+            wal_fsync_interval: Duration::from_millis(DEFAULT_WAL_FSYNC_INTERVAL_MS),
+            wal_segment_size: DEFAULT_WAL_SEGMENT_SIZE,
+            exact_quantiles_max_window: DEFAULT_EXACT_QUANTILES_MAX_WINDOW,
+            approx_quantiles: true,
+            ewma_half_lives: Vec::new(),
+            candles: DEFAULT_CANDLES,
+            returns: None,
+            periods_per_year: 1.0,
+            pairs: Vec::new(),
+            pair_alignment: Alignment::default(),
+            ws_throttle: Duration::from_millis(DEFAULT_WS_THROTTLE_MS),
+            alerts_path: None,
+            alert_webhooks: Vec::new(),
+            alert_retries: DEFAULT_ALERT_RETRIES,
+            line_tcp_port: None,
+            line_udp_port: None,
+        }
+    }
+}
+
+/// Command line flags, each with environment variable fallback handled by `clap`.
+#[derive(Debug, Default, Parser)]
+#[command(version, about = "In-memory sliding window stats service")]
+pub struct Cli {
+    /// Path to TOML config file
+    #[arg(long, env = "FAST_STATS_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address to listen on
+    #[arg(long, env = "FAST_STATS_LISTEN")]
+    pub listen: Option<IpAddr>,
+
+    /// Port to listen on
+    #[arg(long, env = "FAST_STATS_PORT")]
+    pub port: Option<u16>,
+
+    /// Max number of values in single batch
+    #[arg(long, env = "FAST_STATS_MAX_BATCH_SIZE")]
+    pub max_batch_size: Option<usize>,
+
+    /// Max number of values in single batch in binary format
+    #[arg(long, env = "FAST_STATS_MAX_BINARY_BATCH_SIZE")]
+    pub max_binary_batch_size: Option<usize>,
+
+    /// One of: off, error, warn, info, debug, trace
+    #[arg(long, env = "FAST_STATS_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Comma separated window sizes, optionally named, e.g. `50,200,session=390`
+    #[arg(long, env = "FAST_STATS_WINDOWS", value_delimiter = ',')]
+    pub windows: Option<Vec<WindowSpec>>,
+
+    /// Memory budget for all symbols, e.g. `4GiB`; unlimited by default
+    #[arg(long, env = "FAST_STATS_MEMORY_BUDGET")]
+    pub memory_budget: Option<ByteSize>,
+
+    /// What to do when memory budget is exceeded
+    #[arg(long, env = "FAST_STATS_MEMORY_POLICY")]
+    pub memory_policy: Option<MemoryPolicy>,
+
+    /// Snapshot file; snapshots are disabled if not set
+    #[arg(long, env = "FAST_STATS_SNAPSHOT_PATH")]
+    pub snapshot_path: Option<PathBuf>,
+
+    /// Seconds between periodic snapshots, `0` saves only on shutdown
+    #[arg(long, env = "FAST_STATS_SNAPSHOT_INTERVAL_SECS")]
+    pub snapshot_interval_secs: Option<u64>,
+
+    /// Directory of the write-ahead log; WAL is disabled if not set
+    #[arg(long, env = "FAST_STATS_WAL_DIR")]
+    pub wal_dir: Option<PathBuf>,
+
+    /// When to fsync the write-ahead log
+    #[arg(long, env = "FAST_STATS_WAL_FSYNC")]
+    pub wal_fsync: Option<FsyncPolicy>,
+
+    /// Milliseconds between fsyncs of the write-ahead log with `periodic` policy
+    #[arg(long, env = "FAST_STATS_WAL_FSYNC_INTERVAL_MS")]
+    pub wal_fsync_interval_ms: Option<u64>,
+
+    /// Size of write-ahead log segments, e.g. `64MiB`
+    #[arg(long, env = "FAST_STATS_WAL_SEGMENT_SIZE")]
+    pub wal_segment_size: Option<ByteSize>,
+
+    /// Largest count window keeping its values sorted for exact quantiles
+    #[arg(long, env = "FAST_STATS_EXACT_QUANTILES_MAX_WINDOW")]
+    pub exact_quantiles_max_window: Option<usize>,
+
+    /// Keep quantile sketches for bigger count windows and time windows
+    #[arg(long, env = "FAST_STATS_APPROX_QUANTILES")]
+    pub approx_quantiles: Option<bool>,
+
+    /// Comma separated half-lives of exponentially weighted stats, e.g. `10,100,1s`
+    #[arg(long, env = "FAST_STATS_EWMA_HALF_LIVES", value_delimiter = ',')]
+    pub ewma_half_lives: Option<Vec<Span>>,
+
+    /// Number of closed OHLC candles kept per window; 0 disables candles
+    #[arg(long, env = "FAST_STATS_CANDLES")]
+    pub candles: Option<usize>,
+
+    /// Keep log or simple returns between consecutive values
+    #[arg(long, env = "FAST_STATS_RETURNS")]
+    pub returns: Option<ReturnKind>,
+
+    /// Returns per year to annualize their volatility and Sharpe ratio, e.g. `252` for daily values
+    #[arg(long, env = "FAST_STATS_PERIODS_PER_YEAR")]
+    pub periods_per_year: Option<f64>,
+
+    /// Comma separated symbols with benchmarks to keep covariance of, e.g. `AAPL:SPY,MSFT:SPY`
+    #[arg(long, env = "FAST_STATS_PAIRS", value_delimiter = ',')]
+    pub pairs: Option<Vec<PairSpec>>,
+
+    /// Match values of pairs by their index or timestamp
+    #[arg(long, env = "FAST_STATS_PAIR_ALIGNMENT")]
+    pub pair_alignment: Option<Alignment>,
+
+    /// Min milliseconds between stats updates pushed to a subscriber, `0` pushes after every batch
+    #[arg(long, env = "FAST_STATS_WS_THROTTLE_MS")]
+    pub ws_throttle_ms: Option<u64>,
+
+    /// JSON file of alert rules, saved after every change and loaded at startup
+    #[arg(long, env = "FAST_STATS_ALERTS_PATH")]
+    pub alerts_path: Option<PathBuf>,
+
+    /// Comma separated webhooks notified by alert rules without own ones, e.g. `http://localhost:9000/alerts`
+    #[arg(long, env = "FAST_STATS_ALERT_WEBHOOKS", value_delimiter = ',')]
+    pub alert_webhooks: Option<Vec<WebhookUrl>>,
+
+    /// Retries of failed webhook deliveries, with exponential backoff
+    #[arg(long, env = "FAST_STATS_ALERT_RETRIES")]
+    pub alert_retries: Option<u32>,
+
+    /// Port of TCP listener of lines like `SYMBOL v1 v2 v3`, on the address of HTTP listener
+    #[arg(long, env = "FAST_STATS_LINE_TCP_PORT")]
+    pub line_tcp_port: Option<u16>,
+
+    /// Port of UDP listener of lines like `SYMBOL v1 v2 v3`, on the address of HTTP listener
+    #[arg(long, env = "FAST_STATS_LINE_UDP_PORT")]
+    pub line_udp_port: Option<u16>,
+}
+
+/// Content of TOML config file. All settings are optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub listen: Option<IpAddr>,
+    pub port: Option<u16>,
+    pub max_batch_size: Option<usize>,
+    pub max_binary_batch_size: Option<usize>,
+    pub log_level: Option<String>,
+    pub windows: Option<Vec<WindowSpec>>,
+    #[serde(default)]
+    pub symbols: HashMap<String, FileSymbolConfig>,
+    pub memory_budget: Option<ByteSize>,
+    pub memory_policy: Option<MemoryPolicy>,
+    pub snapshot_path: Option<PathBuf>,
+    pub snapshot_interval_secs: Option<u64>,
+    pub wal_dir: Option<PathBuf>,
+    pub wal_fsync: Option<FsyncPolicy>,
+    pub wal_fsync_interval_ms: Option<u64>,
+    pub wal_segment_size: Option<ByteSize>,
+    pub exact_quantiles_max_window: Option<usize>,
+    pub approx_quantiles: Option<bool>,
+    pub ewma_half_lives: Option<Vec<Span>>,
+    pub candles: Option<usize>,
+    pub returns: Option<ReturnKind>,
+    pub periods_per_year: Option<f64>,
+    pub pairs: Option<Vec<PairSpec>>,
+    pub pair_alignment: Option<Alignment>,
+    pub ws_throttle_ms: Option<u64>,
+    pub alerts_path: Option<PathBuf>,
+    pub alert_webhooks: Option<Vec<WebhookUrl>>,
+    pub alert_retries: Option<u32>,
+    pub line_tcp_port: Option<u16>,
+    pub line_udp_port: Option<u16>,
+}
+
+/// Per symbol section of TOML config file, e.g. `[symbols.AAPL]`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSymbolConfig {
+    pub windows: Option<Vec<WindowSpec>>,
+}
+
+/// Number of bytes, given as plain integer or with binary unit suffix: `512MiB`, `4GiB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawByteSize")]
+pub struct ByteSize(pub usize);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawByteSize {
+    Bytes(usize),
+    Text(String),
+}
+
+impl TryFrom<RawByteSize> for ByteSize {
+    type Error = String;
+
+    fn try_from(raw: RawByteSize) -> Result<Self, Self::Error> {
+        match raw {
+            RawByteSize::Bytes(bytes) => Ok(Self(bytes)),
+            RawByteSize::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(digits);
+        let multiplier: usize = match unit.trim() {
+            "" | "B" => 1,
+            "KiB" => 1 << 10,
+            "MiB" => 1 << 20,
+            "GiB" => 1 << 30,
+            "TiB" => 1 << 40,
+            _ => {
+                return Err(format!(
+                    "unknown unit in {s:?}, use B, KiB, MiB, GiB or TiB"
+                ));
+            }
+        };
+        number
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .map(Self)
+            .ok_or_else(|| format!("invalid number of bytes {s:?}"))
+    }
+}
+
+impl FileConfig {
+    pub fn parse(content: &str, path: PathBuf) -> Result<Self, ConfigError> {
+        toml::from_str(content).map_err(|source| ConfigError::Parse { path, source })
+    }
+
+    fn read(path: PathBuf) -> Result<Self, ConfigError> {
+        match std::fs::read_to_string(&path) {
+            Ok(content) => Self::parse(&content, path),
+            Err(source) => Err(ConfigError::Read { path, source }),
+        }
+    }
+}
+
+impl Config {
+    /// Loads configuration from process arguments, environment and config file.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::from_cli(Cli::parse())
+    }
+
+    /// Reads config file pointed by `cli`, if any, and merges both.
+    pub fn from_cli(cli: Cli) -> Result<Self, ConfigError> {
+        let file = match cli.config.clone() {
+            Some(path) => FileConfig::read(path)?,
+            None => FileConfig::default(),
+        };
+        Self::merge(cli, file)
+    }
+
+    /// Merges settings, `cli` taking precedence over `file`, and validates the result.
+    pub fn merge(cli: Cli, file: FileConfig) -> Result<Self, ConfigError> {
+        let default = Config::default();
+
+        let log_level = match cli.log_level.or(file.log_level) {
+            Some(level) => level.parse().map_err(|_| ConfigError::Invalid {
+                field: "log_level",
+                reason: format!("unknown level {level:?}"),
+            })?,
+            None => default.log_level,
+        };
+
+        let windows = match cli.windows.or(file.windows) {
+            Some(specs) => Windows::new(specs).map_err(|reason| ConfigError::Invalid {
+                field: "windows",
+                reason,
+            })?,
+            None => default.windows,
+        };
+
+        let mut symbols = HashMap::new();
+        for (symbol, symbol_config) in file.symbols {
+            let symbol_windows = match symbol_config.windows {
+                Some(specs) => Windows::new(specs).map_err(|reason| ConfigError::Invalid {
+                    field: "symbols.windows",
+                    reason: format!("{symbol}: {reason}"),
+                })?,
+                None => windows.clone(),
+            };
+            symbols.insert(
+                symbol,
+                SymbolConfig {
+                    windows: symbol_windows,
+                },
+            );
+        }
+
+        let config = Self {
+            listen: cli.listen.or(file.listen).unwrap_or(default.listen),
+            port: cli.port.or(file.port).unwrap_or(default.port),
+            max_batch_size: cli
+                .max_batch_size
+                .or(file.max_batch_size)
+                .unwrap_or(default.max_batch_size),
+            max_binary_batch_size: cli
+                .max_binary_batch_size
+                .or(file.max_binary_batch_size)
+                .unwrap_or(default.max_binary_batch_size),
+            log_level,
+            windows,
+            symbols,
+            memory_budget: cli.memory_budget.or(file.memory_budget).map(|b| b.0),
+            memory_policy: cli
+                .memory_policy
+                .or(file.memory_policy)
+                .unwrap_or(default.memory_policy),
+            snapshot_path: cli.snapshot_path.or(file.snapshot_path),
+            snapshot_interval: cli
+                .snapshot_interval_secs
+                .or(file.snapshot_interval_secs)
+                .map_or(default.snapshot_interval, Duration::from_secs),
+            wal_dir: cli.wal_dir.or(file.wal_dir),
+            wal_fsync: cli
+                .wal_fsync
+                .or(file.wal_fsync)
+                .unwrap_or(default.wal_fsync),
+            wal_fsync_interval: cli
+                .wal_fsync_interval_ms
+                .or(file.wal_fsync_interval_ms)
+                .map_or(default.wal_fsync_interval, Duration::from_millis),
+            wal_segment_size: cli
+                .wal_segment_size
+                .or(file.wal_segment_size)
+                .map_or(default.wal_segment_size, |b| b.0),
+            exact_quantiles_max_window: cli
+                .exact_quantiles_max_window
+                .or(file.exact_quantiles_max_window)
+                .unwrap_or(default.exact_quantiles_max_window),
+            approx_quantiles: cli
+                .approx_quantiles
+                .or(file.approx_quantiles)
+                .unwrap_or(default.approx_quantiles),
+            ewma_half_lives: cli
+                .ewma_half_lives
+                .or(file.ewma_half_lives)
+                .unwrap_or(default.ewma_half_lives),
+            candles: cli.candles.or(file.candles).unwrap_or(default.candles),
+            returns: cli.returns.or(file.returns),
+            periods_per_year: cli
+                .periods_per_year
+                .or(file.periods_per_year)
+                .unwrap_or(default.periods_per_year),
+            pairs: cli.pairs.or(file.pairs).unwrap_or(default.pairs),
+            pair_alignment: cli
+                .pair_alignment
+                .or(file.pair_alignment)
+                .unwrap_or(default.pair_alignment),
+            ws_throttle: cli
+                .ws_throttle_ms
+                .or(file.ws_throttle_ms)
+                .map_or(default.ws_throttle, Duration::from_millis),
+            alerts_path: cli.alerts_path.or(file.alerts_path),
+            alert_webhooks: cli
+                .alert_webhooks
+                .or(file.alert_webhooks)
+                .unwrap_or(default.alert_webhooks),
+            alert_retries: cli
+                .alert_retries
+                .or(file.alert_retries)
+                .unwrap_or(default.alert_retries),
+            line_tcp_port: cli.line_tcp_port.or(file.line_tcp_port),
+            line_udp_port: cli.line_udp_port.or(file.line_udp_port),
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.memory_budget == Some(0) {
+            return Err(ConfigError::Invalid {
+                field: "memory_budget",
+                reason: "must be greater than 0".into(),
+            });
+        }
+        if self.max_batch_size == 0 {
+            return Err(ConfigError::Invalid {
+                field: "max_batch_size",
+                reason: "must be greater than 0".into(),
+            });
+        }
+        if self.line_tcp_port == Some(self.port) {
+            return Err(ConfigError::Invalid {
+                field: "line_tcp_port",
+                reason: "must differ from `port`".into(),
+            });
+        }
+        if self.max_binary_batch_size == 0 {
+            return Err(ConfigError::Invalid {
+                field: "max_binary_batch_size",
+                reason: "must be greater than 0".into(),
+            });
+        }
+        if self.max_binary_batch_size > MAX_BINARY_BATCH_SIZE {
+            return Err(ConfigError::Invalid {
+                field: "max_binary_batch_size",
+                reason: format!("must be at most {MAX_BINARY_BATCH_SIZE}"),
+            });
+        }
+        if self.wal_segment_size == 0 {
+            return Err(ConfigError::Invalid {
+                field: "wal_segment_size",
+                reason: "must be greater than 0".into(),
+            });
+        }
+        for (i, half_life) in self.ewma_half_lives.iter().enumerate() {
+            if matches!(half_life, Span::Count(0) | Span::Time(0)) {
+                return Err(ConfigError::Invalid {
+                    field: "ewma_half_lives",
+                    reason: "must be greater than 0".into(),
+                });
+            }
+            if self.ewma_half_lives[..i].contains(half_life) {
+                return Err(ConfigError::Invalid {
+                    field: "ewma_half_lives",
+                    reason: format!("duplicated half-life {half_life}"),
+                });
+            }
+        }
+        if !(self.periods_per_year.is_finite() && self.periods_per_year > 0.0) {
+            return Err(ConfigError::Invalid {
+                field: "periods_per_year",
+                reason: "must be a positive number".into(),
+            });
+        }
+        for (i, pair) in self.pairs.iter().enumerate() {
+            if pair.symbol == pair.benchmark {
+                return Err(ConfigError::Invalid {
+                    field: "pairs",
+                    reason: format!("{pair} pairs symbol with itself"),
+                });
+            }
+            if self.pairs[..i].contains(pair) {
+                return Err(ConfigError::Invalid {
+                    field: "pairs",
+                    reason: format!("duplicated pair {pair}"),
+                });
+            }
+        }
+        if self.wal_fsync == FsyncPolicy::Periodic && self.wal_fsync_interval.is_zero() {
+            return Err(ConfigError::Invalid {
+                field: "wal_fsync_interval_ms",
+                reason: "must be greater than 0 with periodic fsync".into(),
+            });
+        }
+        Ok(())
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.listen, self.port)
+    }
+
+    /// Windows to maintain for given symbol.
+    pub fn windows_for(&self, symbol: &str) -> &Windows {
+        self.symbols
+            .get(symbol)
+            .map_or(&self.windows, |symbol_config| &symbol_config.windows)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(content: &str) -> FileConfig {
+        FileConfig::parse(content, "test.toml".into()).unwrap()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = Config::merge(Cli::default(), FileConfig::default()).unwrap();
+        assert_eq!(config.socket_addr(), "127.0.0.1:3000".parse().unwrap());
+        assert_eq!(config.max_batch_size, DEFAULT_MAX_BATCH_SIZE);
+        assert_eq!(config.max_binary_batch_size, DEFAULT_MAX_BINARY_BATCH_SIZE);
+        assert_eq!(config.log_level, LevelFilter::INFO);
+        assert_eq!(config.windows, Windows::geometric(8, 10));
+        assert!(config.approx_quantiles);
+        assert!(config.ewma_half_lives.is_empty());
+        assert_eq!(config.candles, DEFAULT_CANDLES);
+        assert_eq!(config.returns, None);
+
+        let config = Config::merge(Cli::default(), file("candles = 0")).unwrap();
+        assert_eq!(config.candles, 0);
+
+        let cli = Cli::try_parse_from(["fast-stats", "--returns", "log"]).unwrap();
+        let config = Config::merge(cli, file("returns = \"simple\"")).unwrap();
+        assert_eq!(config.returns, Some(ReturnKind::Log));
+    }
+
+    #[test]
+    fn test_ewma_half_lives() {
+        let config =
+            Config::merge(Cli::default(), file("ewma_half_lives = [100, \"1s\"]")).unwrap();
+        assert_eq!(
+            config.ewma_half_lives,
+            vec![Span::Count(100), Span::Time(1000)]
+        );
+
+        let cli = Cli::try_parse_from(["fast-stats", "--ewma-half-lives", "10,5m"]).unwrap();
+        let config = Config::merge(cli, file("ewma_half_lives = [100]")).unwrap();
+        assert_eq!(
+            config.ewma_half_lives,
+            vec![Span::Count(10), Span::Time(300_000)]
+        );
+    }
+
+    #[test]
+    fn test_pairs() {
+        let config = Config::merge(Cli::default(), file("pairs = [\"AAPL:SPY\"]")).unwrap();
+        assert_eq!(config.pairs, vec!["AAPL:SPY".parse().unwrap()]);
+        assert_eq!(config.pair_alignment, Alignment::Index);
+
+        let cli = Cli::try_parse_from([
+            "fast-stats",
+            "--pairs",
+            "AAPL:SPY,MSFT:SPY",
+            "--pair-alignment",
+            "time",
+        ])
+        .unwrap();
+        let config = Config::merge(cli, file("pairs = [\"AAPL:QQQ\"]")).unwrap();
+        assert_eq!(config.pairs.len(), 2);
+        assert_eq!(config.pairs[1].symbol, "MSFT");
+        assert_eq!(config.pairs[1].benchmark, "SPY");
+        assert_eq!(config.pair_alignment, Alignment::Time);
+
+        let err = Config::merge(Cli::default(), file("pairs = [\"SPY:SPY\"]")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid `pairs`: SPY:SPY pairs symbol with itself"
+        );
+        assert!(FileConfig::parse("pairs = [\"AAPL\"]", "test.toml".into()).is_err());
+    }
+
+    #[test]
+    fn test_windows() {
+        let cli = Cli::try_parse_from(["fast-stats", "--windows", "200,50,session=390"]).unwrap();
+        let file_config = file("windows = [10]\n[symbols.AAPL]\nwindows = [1000, \"day=390\"]");
+
+        let config = Config::merge(cli, file_config).unwrap();
+        let windows = config.windows_for("MSFT");
+        assert_eq!(windows.sizes().collect::<Vec<_>>(), vec![50, 200, 390]);
+        assert_eq!(windows.level_of("session"), Some(2));
+
+        let windows = config.windows_for("AAPL");
+        assert_eq!(windows.sizes().collect::<Vec<_>>(), vec![390, 1000]);
+        assert_eq!(windows.level_of("day"), Some(0));
+
+        let err = Config::merge(Cli::default(), file("windows = [10, 10]")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid `windows`: duplicated window size 10"
+        );
+    }
+
+    #[test]
+    fn test_cli_overrides_file() {
+        let cli =
+            Cli::try_parse_from(["fast-stats", "--port", "3001", "--log-level", "debug"]).unwrap();
+        let file = file("listen = \"0.0.0.0\"\nport = 4000\nmax_batch_size = 50");
+
+        let config = Config::merge(cli, file).unwrap();
+        assert_eq!(config.socket_addr(), "0.0.0.0:3001".parse().unwrap());
+        assert_eq!(config.max_batch_size, 50);
+        assert_eq!(config.log_level, LevelFilter::DEBUG);
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let cli = Cli::try_parse_from(["fast-stats", "--memory-policy", "reject"]).unwrap();
+        let config = Config::merge(cli, file("memory_budget = \"512MiB\"")).unwrap();
+        assert_eq!(config.memory_budget, Some(512 << 20));
+        assert_eq!(config.memory_policy, MemoryPolicy::Reject);
+
+        let config = Config::merge(Cli::default(), file("memory_budget = 1000")).unwrap();
+        assert_eq!(config.memory_budget, Some(1000));
+        assert_eq!(config.memory_policy, MemoryPolicy::EvictLru);
+
+        assert!("4GB".parse::<ByteSize>().is_err());
+        assert!("GiB".parse::<ByteSize>().is_err());
+    }
+
+    #[test]
+    fn test_wal() {
+        let cli = Cli::try_parse_from(["fast-stats", "--wal-fsync", "always"]).unwrap();
+        let config =
+            Config::merge(cli, file("wal_dir = \"wal\"\nwal_segment_size = \"1MiB\"")).unwrap();
+        assert_eq!(config.wal_dir, Some(PathBuf::from("wal")));
+        assert_eq!(config.wal_fsync, FsyncPolicy::Always);
+        assert_eq!(config.wal_segment_size, 1 << 20);
+
+        let err = Config::merge(Cli::default(), file("wal_fsync_interval_ms = 0")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid `wal_fsync_interval_ms`: must be greater than 0 with periodic fsync"
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let err = Config::merge(Cli::default(), file("max_batch_size = 0")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid `max_batch_size`: must be greater than 0"
+        );
+
+        let err = Config::merge(Cli::default(), file("log_level = \"loud\"")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid `log_level`: unknown level \"loud\""
+        );
+
+        let err =
+            Config::merge(Cli::default(), file("ewma_half_lives = [10, \"10\"]")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid `ewma_half_lives`: duplicated half-life 10"
+        );
+
+        let err = Config::merge(Cli::default(), file("periods_per_year = 0.0")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid `periods_per_year`: must be a positive number"
+        );
+
+        let err = Config::merge(Cli::default(), file("line_tcp_port = 3000")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid `line_tcp_port`: must differ from `port`"
+        );
+
+        let err =
+            Config::merge(Cli::default(), file("max_binary_batch_size = 100_000_001")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid `max_binary_batch_size`: must be at most 100000000"
+        );
+
+        let err = FileConfig::parse("prot = 3000", "test.toml".into()).unwrap_err();
+        assert!(err.to_string().contains("unknown field `prot`"));
+    }
+}
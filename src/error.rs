@@ -1,7 +1,7 @@
 use axum::{
+    Json,
     http::StatusCode,
     response::{IntoResponse, Response},
-    Json,
 };
 use serde_json::json;
 
@@ -15,8 +15,17 @@ pub enum Error {
     #[error("Symbol not found: {0}")]
     SymbolNotFound(String),
 
-    #[error("Too many values in batch (max is 10,000)")]
-    TooManyValues,
+    #[error("Pair not registered: {0}")]
+    PairNotFound(String),
+
+    #[error("Alert rule not found: {0}")]
+    AlertNotFound(u64),
+
+    #[error("Too many values in batch (max is {0})")]
+    TooManyValues(usize),
+
+    #[error("Memory budget exceeded, cannot admit values of symbol: {0}")]
+    MemoryBudgetExceeded(String),
 
     #[error("Internal error: {0}")]
     Internal(#[from] anyhow::Error),
@@ -25,8 +34,11 @@ pub enum Error {
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
         let status = match self {
-            Error::InvalidRequest(_) | Error::TooManyValues => StatusCode::BAD_REQUEST,
-            Error::SymbolNotFound(_) => StatusCode::NOT_FOUND,
+            Error::InvalidRequest(_) | Error::TooManyValues(_) => StatusCode::BAD_REQUEST,
+            Error::SymbolNotFound(_) | Error::PairNotFound(_) | Error::AlertNotFound(_) => {
+                StatusCode::NOT_FOUND
+            }
+            Error::MemoryBudgetExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
             Error::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
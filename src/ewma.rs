@@ -0,0 +1,195 @@
+//! Exponentially weighted moving average and variance, see [`Ewma`].
+use crate::snapshot::{Decoder, Encoder, Persist, SnapshotError};
+use crate::windows::Span;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+
+/// Average and variance of all values seen, weighted by their age: weight of a value
+/// halves every `half_life` values, or milliseconds for time half-lives.
+///
+/// Unlike windows, there is no hard edge, so nothing is evicted and state has constant size.
+/// Each value first decays weights of older ones, then it is added by West's weighted
+/// incremental algorithm, which keeps deviations from the average instead of raw squares,
+/// so it is as stable as [`crate::variance::ShiftedSums`].
+///
+/// Weights are not normalized to the steady state, so the first value alone has full
+/// weight, instead of being biased toward `0`.
+///
+/// Values of the same millisecond are not decayed against each other for time half-lives,
+/// and values older than the latest one are treated as if they came at its time.
+#[derive(Debug, Clone)]
+pub struct Ewma {
+    half_life: Span,
+    /// decay of older weights per value, for count half-lives
+    decay: f64,
+    /// total weight of values seen
+    weight: f64,
+    avg: f64,
+    /// weighted sum of squared deviations from `avg`
+    sum_sq_dev: f64,
+    /// time of the latest value, in milliseconds since unix epoch
+    time: u64,
+}
+
+/// EWMA stats as returned by `/stats/`.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct EwmaStats {
+    /// e.g. `100` values or `1s`
+    pub half_life: String,
+    pub avg: f64,
+    pub var: f64,
+}
+
+impl Ewma {
+    /// Creates EWMA of non-zero `half_life`.
+    pub fn new(half_life: Span) -> Self {
+        let decay = match half_life {
+            Span::Count(values) => (-1.0 / values as f64).exp2(),
+            Span::Time(_) => 1.0,
+        };
+        Self {
+            half_life,
+            decay,
+            weight: 0.0,
+            avg: f64::NAN,
+            sum_sq_dev: 0.0,
+            time: 0,
+        }
+    }
+
+    pub fn half_life(&self) -> Span {
+        self.half_life
+    }
+
+    /// Time of the latest value for time half-lives, `None` for count ones.
+    pub fn now(&self) -> Option<u64> {
+        matches!(self.half_life, Span::Time(_)).then_some(self.time)
+    }
+
+    /// Decay of older weights by value observed at `timestamp`.
+    fn decay_at(&self, timestamp: Option<u64>) -> f64 {
+        match (self.half_life, timestamp) {
+            (Span::Time(millis), Some(timestamp)) if timestamp > self.time => {
+                (-((timestamp - self.time) as f64) / millis as f64).exp2()
+            }
+            (Span::Time(_), _) => 1.0,
+            (Span::Count(_), _) => self.decay,
+        }
+    }
+
+    /// Average, weighted sum of squared deviations and total weight after adding `val`.
+    fn with(&self, val: f64, decay: f64) -> (f64, f64, f64) {
+        let weight = self.weight * decay + 1.0;
+        if self.weight == 0.0 {
+            return (val, 0.0, weight);
+        }
+        let delta = val - self.avg;
+        let avg = self.avg + delta / weight;
+        (avg, self.sum_sq_dev * decay + delta * (val - avg), weight)
+    }
+
+    /// Whether stats stay finite after adding `val`, even without any decay.
+    pub fn fits(&self, val: f64) -> bool {
+        let (avg, sum_sq_dev, _) = self.with(val, 1.0);
+        avg.is_finite() && sum_sq_dev.is_finite()
+    }
+
+    /// Adds `val` observed at `timestamp`, or at the time of the latest value if `None`.
+    pub fn push(&mut self, val: f64, timestamp: Option<u64>) {
+        let decay = self.decay_at(timestamp);
+        (self.avg, self.sum_sq_dev, self.weight) = self.with(val, decay);
+        self.time = self.time.max(timestamp.unwrap_or(self.time));
+    }
+
+    /// Stats of values seen so far; `NaN` without any.
+    pub fn stats(&self) -> EwmaStats {
+        let var = self.sum_sq_dev / self.weight;
+        EwmaStats {
+            half_life: self.half_life.to_string(),
+            avg: self.avg,
+            var: if var < 0.0 { 0.0 } else { var },
+        }
+    }
+}
+
+impl Persist for Ewma {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        self.half_life.encode(enc)?;
+        enc.f64(self.weight)?;
+        enc.f64(self.avg)?;
+        enc.f64(self.sum_sq_dev)?;
+        enc.u64(self.time)
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        let half_life = Span::decode(dec)?;
+        if matches!(half_life, Span::Count(0) | Span::Time(0)) {
+            return Err(SnapshotError::Corrupted("zero EWMA half-life".into()));
+        }
+        Ok(Self {
+            weight: dec.f64()?,
+            avg: dec.f64()?,
+            sum_sq_dev: dec.f64()?,
+            time: dec.u64()?,
+            ..Self::new(half_life)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_half_life() {
+        let mut ewma = Ewma::new(Span::Count(1));
+        assert!(ewma.stats().avg.is_nan() && ewma.stats().var.is_nan());
+
+        ewma.push(4.0, None);
+        assert_eq!(ewma.stats().avg, 4.0);
+        assert_eq!(ewma.stats().var, 0.0);
+
+        // weights `0.5` and `1`
+        ewma.push(1.0, None);
+        assert_eq!(ewma.stats().avg, 2.0);
+        assert_eq!(ewma.stats().var, 2.0);
+
+        // converges to the new level
+        for _ in 0..100 {
+            ewma.push(10.0, None);
+        }
+        assert!((ewma.stats().avg - 10.0).abs() < 1e-12);
+        assert!(ewma.stats().var < 1e-12);
+    }
+
+    #[test]
+    fn test_time_half_life() {
+        let mut ewma = Ewma::new(Span::Time(1000));
+        ewma.push(1.0, Some(5_000));
+        ewma.push(3.0, Some(5_000));
+        // same time, same weights
+        assert_eq!(ewma.stats().avg, 2.0);
+        assert_eq!(ewma.now(), Some(5_000));
+
+        // both halved by a second
+        ewma.push(5.0, Some(6_000));
+        assert_eq!(ewma.stats().avg, 3.5);
+        assert_eq!(ewma.stats().var, 2.75);
+
+        // late value comes at the latest time
+        ewma.push(5.0, Some(1_000));
+        assert_eq!(ewma.now(), Some(6_000));
+        assert_eq!(ewma.stats().avg, 4.0);
+    }
+
+    #[test]
+    fn test_stable_far_from_zero() {
+        let mut ewma = Ewma::new(Span::Count(10));
+        for i in 0..10_000 {
+            ewma.push(1e9 + (i % 2) as f64 * 0.01, None);
+        }
+        // alternating values have variance close to `0.005²`, regardless of the offset
+        let var = ewma.stats().var;
+        assert!((var - 0.005f64.powi(2)).abs() < 2e-6, "{var}");
+    }
+}
@@ -1,3 +1,5 @@
+use crate::snapshot::{Decoder, Encoder, Persist, SnapshotError};
+use std::io::{self, Read, Write};
 use std::ops::{Add, AddAssign};
 // use accurate::sum::Neumaier;
 // use accurate::traits::SumAccumulator;
@@ -54,6 +56,20 @@ impl Add for NeumaierSum {
     }
 }
 
+impl Persist for NeumaierSum {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.f64(self.s)?;
+        enc.f64(self.c)
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            s: dec.f64()?,
+            c: dec.f64()?,
+        })
+    }
+}
+
 #[inline]
 fn neumaier_sum(a: f64, b: f64) -> (f64, f64) {
     if a.abs() >= b.abs() {
@@ -1,31 +1,208 @@
 //! lib was created just for benches
 
+mod alerts;
 mod api;
 mod app_state;
+mod binary_batch;
+mod candles;
+pub mod clock;
+pub mod config;
+mod error;
+mod ewma;
 mod kahan;
+mod line_protocol;
+mod metrics;
 // mod monotonic_queue;
-mod error;
+mod pairs;
+mod quantile_sketch;
+pub mod registry;
+mod returns;
 mod shared_monotonic_queue;
+pub mod snapshot;
+mod sorted_blocks;
+mod sse;
+mod subscriptions;
 pub mod symbol_aggregator;
 pub mod tests;
+mod time_levels;
+mod variance;
+pub mod wal;
+mod webhook;
+pub mod windows;
+mod ws;
 
+use crate::config::Config;
+use crate::wal::{FsyncPolicy, Wal};
 use axum::routing::{get, post};
 use axum::Router;
 
-pub async fn start_server() -> anyhow::Result<()> {
+pub async fn start_server(config: Config) -> anyhow::Result<()> {
     // initialize tracing
-    tracing_subscriber::fmt::init();
+    tracing_subscriber::fmt()
+        .with_max_level(config.log_level)
+        .init();
+
+    let addr = config.socket_addr();
+    if app_state::CONFIG.set(config).is_err() {
+        anyhow::bail!("server is already configured");
+    }
+    let config = app_state::config();
+
+    if let Some(path) = &config.snapshot_path {
+        let count = snapshot::restore(&app_state::SYMBOLS, path, |symbol| {
+            config.windows_for(symbol)
+        })
+        .map_err(|err| anyhow::anyhow!("unable to restore snapshot {path:?}: {err}"))?;
+        tracing::info!("restored {count} symbols from snapshot {path:?}");
+    }
+
+    if let Some(dir) = &config.wal_dir {
+        open_wal(config, dir)?;
+    }
+
+    let alerts = app_state::SYMBOLS.alerts();
+    let count = alerts
+        .load()
+        .map_err(|err| anyhow::anyhow!("unable to load alert rules: {err}"))?;
+    if let Some(path) = &config.alerts_path {
+        tracing::info!("loaded {count} alert rules from {path:?}");
+    }
+    if let Some(notifications) = alerts.take_notifications() {
+        tokio::spawn(webhook::deliver_all(
+            notifications,
+            config.alert_webhooks.clone(),
+            config.alert_retries,
+        ));
+    }
+
+    if let Some(path) = &config.snapshot_path
+        && !config.snapshot_interval.is_zero()
+    {
+        tokio::spawn(snapshot::run_periodic(
+            &app_state::SYMBOLS,
+            path.clone(),
+            config.snapshot_interval,
+        ));
+    }
+
+    if let Some(port) = config.line_tcp_port {
+        let listener = tokio::net::TcpListener::bind((config.listen, port)).await?;
+        tracing::info!("line protocol over TCP at {}", listener.local_addr()?);
+        tokio::spawn(line_protocol::serve_tcp(
+            listener,
+            &line_protocol::LINE_TOTALS.tcp,
+        ));
+    }
+    if let Some(port) = config.line_udp_port {
+        let socket = tokio::net::UdpSocket::bind((config.listen, port)).await?;
+        tracing::info!("line protocol over UDP at {}", socket.local_addr()?);
+        tokio::spawn(line_protocol::serve_udp(
+            socket,
+            &line_protocol::LINE_TOTALS.udp,
+        ));
+    }
 
     let app = build_app();
 
-    tracing::info!("🚀 Server running at http://localhost:3000");
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await?;
-    Ok(axum::serve(listener, app).await?)
+    tracing::info!("🚀 Server running at http://{addr}");
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal())
+        .await?;
+
+    if let Some(path) = &config.snapshot_path {
+        let count = snapshot::save(&app_state::SYMBOLS, path)?;
+        tracing::info!("snapshot of {count} symbols saved on shutdown");
+    }
+    if let Some(wal) = app_state::SYMBOLS.wal() {
+        wal.sync()?;
+    }
+    Ok(())
+}
+
+/// Replays the write-ahead log on top of restored symbols and starts logging new batches.
+fn open_wal(config: &'static Config, dir: &std::path::Path) -> anyhow::Result<()> {
+    let mut replayed = 0;
+    let wal = Wal::open(dir, config.wal_fsync, config.wal_segment_size, |record| {
+        let windows = config.windows_for(&record.symbol);
+        match app_state::SYMBOLS.replay(&record, windows) {
+            Ok(()) => replayed += 1,
+            Err(err) => tracing::warn!("unable to replay WAL record {}: {err}", record.seq),
+        }
+    })
+    .map_err(|err| anyhow::anyhow!("unable to open WAL in {dir:?}: {err}"))?;
+    tracing::info!("replayed {replayed} WAL records from {dir:?}");
+
+    if app_state::SYMBOLS.set_wal(wal).is_err() {
+        anyhow::bail!("WAL is already open");
+    }
+    if config.wal_fsync == FsyncPolicy::Periodic {
+        tokio::spawn(sync_wal_periodically(config.wal_fsync_interval));
+    }
+    Ok(())
+}
+
+/// Flushes the write-ahead log every `interval`, forever.
+async fn sync_wal_periodically(interval: std::time::Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let result = tokio::task::spawn_blocking(|| match app_state::SYMBOLS.wal() {
+            Some(wal) => wal.sync(),
+            None => Ok(()),
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::error!("unable to sync WAL: {err}"),
+            Err(err) => tracing::error!("WAL sync task failed: {err}"),
+        }
+    }
+}
+
+/// Completes on Ctrl+C or, on unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Unable to listen for Ctrl+C");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Unable to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    tracing::info!("shutting down");
 }
 
 pub fn build_app() -> Router {
-    let app = Router::new()
+    Router::new()
         .route("/add_batch/", post(api::add_batch))
-        .route("/stats/", get(api::get_stats));
-    app
+        .route("/add_batches/", post(api::add_batches))
+        .route("/stats/", get(api::get_stats))
+        .route("/stats/query", post(api::query_stats))
+        .route("/stats/stream", get(sse::stream_stats))
+        .route("/candles/", get(api::get_candles))
+        .route("/candles/current/", get(api::get_current_candle))
+        .route("/pair_stats/", get(api::get_pair_stats))
+        .route("/ws", get(ws::subscribe))
+        .route("/alerts/", get(api::list_alerts).post(api::create_alert))
+        .route(
+            "/alerts/{id}",
+            get(api::get_alert)
+                .put(api::update_alert)
+                .delete(api::delete_alert),
+        )
+        .route("/metrics", get(metrics::get_metrics))
+        .route_layer(axum::middleware::from_fn(metrics::track))
 }
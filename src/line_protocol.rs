@@ -0,0 +1,344 @@
+//! TCP and UDP listeners of lines like `SYMBOL v1 v2 v3`, each added as a batch of the symbol.
+//!
+//! Values are separated by whitespace, and empty lines are skipped. Malformed lines are counted
+//! and logged, but never close the connection; nothing is sent back to clients.
+use crate::api::validate_batch;
+use crate::app_state::{SYMBOLS, config};
+use crate::error::Error;
+use crate::registry::Batch;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Longer lines are counted as malformed and skipped.
+const MAX_LINE_LEN: usize = 1 << 20;
+/// Max size of UDP datagrams, each of one or more lines.
+const MAX_DATAGRAM_LEN: usize = 65_536;
+/// Counters of more UDP peers at once start over.
+const MAX_UDP_PEERS: usize = 1024;
+
+/// Lines got over each transport since start, exposed by `/metrics`.
+pub static LINE_TOTALS: LineTotals = LineTotals {
+    tcp: TransportTotals::new(),
+    udp: TransportTotals::new(),
+};
+
+pub struct LineTotals {
+    pub tcp: TransportTotals,
+    pub udp: TransportTotals,
+}
+
+/// [`Counters`] of all connections, or all peers, of a transport.
+pub struct TransportTotals {
+    lines: AtomicU64,
+    values: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl TransportTotals {
+    const fn new() -> Self {
+        Self {
+            lines: AtomicU64::new(0),
+            values: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    fn add(&self, values: u64, errors: u64) {
+        self.lines.fetch_add(1, Ordering::Relaxed);
+        self.values.fetch_add(values, Ordering::Relaxed);
+        self.errors.fetch_add(errors, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> Counters {
+        Counters {
+            lines: self.lines.load(Ordering::Relaxed),
+            values: self.values.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Lines got from a TCP connection, or from a peer of the UDP socket.
+#[derive(Debug, Default, PartialEq)]
+pub struct Counters {
+    pub lines: u64,
+    pub values: u64,
+    pub errors: u64,
+}
+
+impl Counters {
+    /// Adds values of a line, or counts it as an error, in `totals` as well.
+    fn ingest(&mut self, peer: SocketAddr, line: &[u8], totals: &TransportTotals) {
+        let result = match std::str::from_utf8(line) {
+            Ok(line) => ingest_line(line),
+            Err(_) => Err(Error::InvalidRequest("Line is not UTF-8".into())),
+        };
+        match result {
+            Ok(0) => {}
+            Ok(values) => {
+                self.lines += 1;
+                self.values += values as u64;
+                totals.add(values as u64, 0);
+            }
+            Err(err) => self.error(peer, err, totals),
+        }
+    }
+
+    /// Counts a malformed line, logged at the first one and then at every power of two,
+    /// so a misbehaving peer does not flood logs.
+    fn error(&mut self, peer: SocketAddr, err: Error, totals: &TransportTotals) {
+        self.lines += 1;
+        self.errors += 1;
+        totals.add(0, 1);
+        if self.errors.is_power_of_two() {
+            tracing::warn!(
+                "malformed line from {peer} ({} of {} lines so far): {err}",
+                self.errors,
+                self.lines
+            );
+        }
+    }
+}
+
+/// Adds values of a line, returning their number; empty lines have none.
+fn ingest_line(line: &str) -> Result<usize, Error> {
+    let Some((symbol, values)) = parse_line(line)? else {
+        return Ok(0);
+    };
+    let batch = Batch {
+        symbol,
+        values: &values,
+        timestamps: None,
+        weights: None,
+    };
+    validate_batch(&batch, config().max_batch_size)?;
+    SYMBOLS.add_batch(symbol, &values, None, None, config().windows_for(symbol))?;
+    Ok(values.len())
+}
+
+/// Splits a line into its symbol and finite values, or `None` if it is empty.
+fn parse_line(line: &str) -> Result<Option<(&str, Vec<f64>)>, Error> {
+    let mut items = line.split_whitespace();
+    let Some(symbol) = items.next() else {
+        return Ok(None);
+    };
+    let values = items
+        .map(|item| match item.parse::<f64>() {
+            Ok(val) if val.is_finite() => Ok(val),
+            _ => Err(Error::InvalidRequest(format!("Invalid value {item:?}"))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if values.is_empty() {
+        return Err(Error::InvalidRequest(format!("No values of {symbol}")));
+    }
+    Ok(Some((symbol, values)))
+}
+
+/// Accepts connections forever, serving each on its own.
+pub async fn serve_tcp(listener: TcpListener, totals: &'static TransportTotals) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(async move {
+                    let counters = read_lines(stream, peer, totals).await;
+                    if counters.errors > 0 {
+                        tracing::warn!("line protocol connection from {peer} closed: {counters:?}");
+                    } else {
+                        tracing::debug!(
+                            "line protocol connection from {peer} closed: {counters:?}"
+                        );
+                    }
+                });
+            }
+            Err(err) => tracing::warn!("unable to accept line protocol connection: {err}"),
+        }
+    }
+}
+
+/// Ingests lines until the peer disconnects, or the connection fails.
+async fn read_lines(
+    stream: impl AsyncRead + Unpin,
+    peer: SocketAddr,
+    totals: &TransportTotals,
+) -> Counters {
+    let mut reader = BufReader::new(stream);
+    let mut counters = Counters::default();
+    let mut line = Vec::new();
+    let mut too_long = false;
+    loop {
+        line.clear();
+        let read = (&mut reader)
+            .take(MAX_LINE_LEN as u64)
+            .read_until(b'\n', &mut line)
+            .await;
+        match read {
+            Ok(0) => break,
+            Ok(_) if !line.ends_with(b"\n") && line.len() == MAX_LINE_LEN => {
+                // the rest of the line is skipped up to its end
+                if !too_long {
+                    let reason = format!("Line is longer than {MAX_LINE_LEN} bytes");
+                    counters.error(peer, Error::InvalidRequest(reason), totals);
+                }
+                too_long = true;
+            }
+            Ok(_) if too_long => too_long = false,
+            Ok(_) => counters.ingest(peer, &line, totals),
+            Err(err) => {
+                tracing::debug!("line protocol connection from {peer} failed: {err}");
+                break;
+            }
+        }
+    }
+    counters
+}
+
+/// Ingests lines of datagrams forever.
+pub async fn serve_udp(socket: UdpSocket, totals: &'static TransportTotals) {
+    let mut peers = UdpPeers::default();
+    let mut datagram = vec![0; MAX_DATAGRAM_LEN];
+    loop {
+        match socket.recv_from(&mut datagram).await {
+            Ok((len, peer)) => peers.ingest(peer, &datagram[..len], totals),
+            Err(err) => tracing::warn!("unable to receive line protocol datagram: {err}"),
+        }
+    }
+}
+
+/// Counters of peers of the UDP socket, which has no connections to close.
+#[derive(Default)]
+struct UdpPeers(HashMap<SocketAddr, Counters>);
+
+impl UdpPeers {
+    fn ingest(&mut self, peer: SocketAddr, datagram: &[u8], totals: &TransportTotals) {
+        if self.0.len() >= MAX_UDP_PEERS && !self.0.contains_key(&peer) {
+            tracing::debug!(
+                "counters of {} line protocol UDP peers start over",
+                self.0.len()
+            );
+            self.0.clear();
+        }
+        let counters = self.0.entry(peer).or_default();
+        for line in datagram.split(|&byte| byte == b'\n') {
+            counters.ingest(peer, line, totals);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            parse_line("AB 1.5 -2 3e2\n").unwrap(),
+            Some(("AB", vec![1.5, -2.0, 300.0]))
+        );
+        assert_eq!(parse_line(" \r\n").unwrap(), None);
+
+        let message = |line| parse_line(line).unwrap_err().to_string();
+        assert_eq!(message("AB"), "Invalid request: No values of AB");
+        assert_eq!(message("AB 1 x"), "Invalid request: Invalid value \"x\"");
+        assert_eq!(
+            message("AB 1 NaN"),
+            "Invalid request: Invalid value \"NaN\""
+        );
+    }
+
+    #[tokio::test]
+    async fn test_malformed_lines_counted() {
+        static TOTALS: TransportTotals = TransportTotals::new();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, peer) = listener.accept().await.unwrap();
+            read_lines(stream, peer, &TOTALS).await
+        });
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"LINES 1 2\nLINES one\n\nLINES 3\n")
+            .await
+            .unwrap();
+        client
+            .write_all(&vec![b'1'; MAX_LINE_LEN + 10])
+            .await
+            .unwrap();
+        client.write_all(b"\nLINES 4").await.unwrap();
+        drop(client);
+
+        let counters = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(counters.lines, 5);
+        assert_eq!(counters.values, 4);
+        assert_eq!(counters.errors, 2);
+        assert_eq!(TOTALS.get(), counters);
+        let entry = SYMBOLS.peek("LINES").unwrap();
+        assert_eq!(
+            entry.aggregator.lock().unwrap().get_stats(1).unwrap().last,
+            4.0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_udp_lines_counted_per_peer() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let a = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        a.send_to(b"UDP_LINES 1 2\nUDP_LINES x", addr)
+            .await
+            .unwrap();
+        b.send_to(b"UDP_LINES 3\n", addr).await.unwrap();
+
+        let totals = TransportTotals::new();
+        let mut peers = UdpPeers::default();
+        let mut datagram = vec![0; MAX_DATAGRAM_LEN];
+        for _ in 0..2 {
+            let (len, peer) =
+                tokio::time::timeout(Duration::from_secs(5), socket.recv_from(&mut datagram))
+                    .await
+                    .unwrap()
+                    .unwrap();
+            peers.ingest(peer, &datagram[..len], &totals);
+        }
+        let counters = |client: &UdpSocket| &peers.0[&client.local_addr().unwrap()];
+        assert_eq!(
+            *counters(&a),
+            Counters {
+                lines: 2,
+                values: 2,
+                errors: 1
+            }
+        );
+        assert_eq!(
+            *counters(&b),
+            Counters {
+                lines: 1,
+                values: 1,
+                errors: 0
+            }
+        );
+        assert_eq!(
+            totals.get(),
+            Counters {
+                lines: 3,
+                values: 3,
+                errors: 1
+            }
+        );
+        // in either order of datagrams
+        let entry = SYMBOLS.peek("UDP_LINES").unwrap();
+        assert_eq!(
+            entry.aggregator.lock().unwrap().get_stats(1).unwrap().avg,
+            2.0
+        );
+    }
+}
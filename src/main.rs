@@ -1,6 +1,15 @@
+use fast_stats::config::Config;
 use fast_stats::start_server;
 
 #[tokio::main]
 async fn main() {
-    start_server().await.expect("Unable to start server");
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid configuration: {err}");
+            std::process::exit(2);
+        }
+    };
+
+    start_server(config).await.expect("Unable to start server");
 }
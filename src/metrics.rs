@@ -0,0 +1,277 @@
+//! `/metrics` endpoint with internals of the service in Prometheus text format.
+use crate::app_state::{METRICS, SYMBOLS};
+use crate::line_protocol::LINE_TOTALS;
+use crate::registry::SymbolRegistry;
+use crate::shared_monotonic_queue::BEST_LOOKUPS;
+use axum::extract::{MatchedPath, Request};
+use axum::http::{Method, StatusCode, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use dashmap::DashMap;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::time::{Duration, Instant};
+
+/// Upper bounds of buckets of request latencies, in seconds.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Requests served by routes, keyed by method and route, e.g. `/alerts/{id}`.
+#[derive(Default)]
+pub struct Metrics {
+    routes: DashMap<(Method, String), RouteStats>,
+}
+
+#[derive(Default, Clone)]
+struct RouteStats {
+    statuses: BTreeMap<u16, u64>,
+    /// number of requests up to each bound of [`LATENCY_BUCKETS`], not cumulative yet
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_sum: f64,
+    count: u64,
+}
+
+impl Metrics {
+    pub fn observe(&self, method: Method, route: &str, status: StatusCode, latency: Duration) {
+        let mut stats = self.routes.entry((method, route.to_string())).or_default();
+        *stats.statuses.entry(status.as_u16()).or_default() += 1;
+        let latency = latency.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|&bound| latency <= bound) {
+            stats.buckets[bucket] += 1;
+        }
+        stats.latency_sum += latency;
+        stats.count += 1;
+    }
+
+    /// Writes requests, then values and memory of symbols of `registry`.
+    pub fn render(&self, registry: &SymbolRegistry) -> String {
+        let mut routes: Vec<_> = self
+            .routes
+            .iter()
+            .map(|entry| {
+                let (method, route) = entry.key();
+                let labels = format!("method=\"{method}\",route=\"{route}\"");
+                (labels, entry.value().clone())
+            })
+            .collect();
+        routes.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut out = Exposition::default();
+        out.describe(
+            "http_requests_total",
+            "counter",
+            "Requests by route and status.",
+        );
+        for (labels, stats) in &routes {
+            for (status, count) in &stats.statuses {
+                out.sample(&format!("{labels},status=\"{status}\""), count);
+            }
+        }
+        out.describe(
+            "http_request_duration_seconds",
+            "histogram",
+            "Latencies of requests by route, until response headers.",
+        );
+        for (labels, stats) in &routes {
+            let mut cumulative = 0;
+            for (bound, bucket) in LATENCY_BUCKETS.iter().zip(stats.buckets) {
+                cumulative += bucket;
+                out.sample_of("bucket", &format!("{labels},le=\"{bound}\""), cumulative);
+            }
+            out.sample_of("bucket", &format!("{labels},le=\"+Inf\""), stats.count);
+            out.sample_of("sum", labels, stats.latency_sum);
+            out.sample_of("count", labels, stats.count);
+        }
+
+        let (ingested, rejected) = registry.values_ingested();
+        out.describe(
+            "values_ingested_total",
+            "counter",
+            "Values added to symbols.",
+        );
+        out.sample("", ingested);
+        out.describe(
+            "values_rejected_total",
+            "counter",
+            "Values skipped by symbols, as their squares are not finite.",
+        );
+        out.sample("", rejected);
+
+        out.describe("symbols", "gauge", "Number of symbols.");
+        out.sample("", registry.len());
+        out.describe("memory_used_bytes", "gauge", "Bytes used by all symbols.");
+        out.sample("", registry.memory_used());
+
+        // symbols might be evicted meanwhile, so skip the missing ones
+        let (mut entries, mut max_entries) = (0, 0);
+        for symbol in registry.symbols() {
+            let Some(entry) = registry.peek(&symbol) else {
+                continue;
+            };
+            let len = entry.aggregator.lock().unwrap().monotonic_queue_len();
+            entries += len;
+            max_entries = max_entries.max(len);
+        }
+        out.describe(
+            "monotonic_queue_entries",
+            "gauge",
+            "Entries of min and max queues of all symbols.",
+        );
+        out.sample("", entries);
+        out.describe(
+            "monotonic_queue_entries_max",
+            "gauge",
+            "Entries of min and max queues of the symbol with the most of them.",
+        );
+        out.sample("", max_entries);
+
+        out.describe(
+            "best_lookups_total",
+            "counter",
+            "Lookups of min and max by level of queues, served by cached best indexes or not.",
+        );
+        for (level, hits, misses) in BEST_LOOKUPS.counts() {
+            let level = level + 1;
+            out.sample(&format!("level=\"{level}\",result=\"hit\""), hits);
+            out.sample(&format!("level=\"{level}\",result=\"miss\""), misses);
+        }
+
+        let totals = [
+            ("tcp", LINE_TOTALS.tcp.get()),
+            ("udp", LINE_TOTALS.udp.get()),
+        ];
+        out.describe(
+            "line_protocol_lines_total",
+            "counter",
+            "Lines got by the line protocol by transport, malformed ones included.",
+        );
+        for (transport, counters) in &totals {
+            out.sample(&format!("transport=\"{transport}\""), counters.lines);
+        }
+        out.describe(
+            "line_protocol_values_total",
+            "counter",
+            "Values of lines added by the line protocol by transport.",
+        );
+        for (transport, counters) in &totals {
+            out.sample(&format!("transport=\"{transport}\""), counters.values);
+        }
+        out.describe(
+            "line_protocol_errors_total",
+            "counter",
+            "Malformed or rejected lines got by the line protocol by transport.",
+        );
+        for (transport, counters) in &totals {
+            out.sample(&format!("transport=\"{transport}\""), counters.errors);
+        }
+        out.text
+    }
+}
+
+/// Text of metrics, each described before its samples.
+#[derive(Default)]
+struct Exposition {
+    text: String,
+    /// name of the metric described last
+    name: &'static str,
+}
+
+impl Exposition {
+    fn describe(&mut self, name: &'static str, kind: &str, help: &str) {
+        self.name = name;
+        self.line(format_args!("# HELP fast_stats_{name} {help}"));
+        self.line(format_args!("# TYPE fast_stats_{name} {kind}"));
+    }
+
+    fn sample(&mut self, labels: &str, value: impl fmt::Display) {
+        let name = self.name;
+        match labels.is_empty() {
+            true => self.line(format_args!("fast_stats_{name} {value}")),
+            false => self.line(format_args!("fast_stats_{name}{{{labels}}} {value}")),
+        }
+    }
+
+    /// Sample of a part of the metric, e.g. `bucket` of a histogram.
+    fn sample_of(&mut self, suffix: &str, labels: &str, value: impl fmt::Display) {
+        let name = self.name;
+        self.line(format_args!(
+            "fast_stats_{name}_{suffix}{{{labels}}} {value}"
+        ));
+    }
+
+    fn line(&mut self, line: fmt::Arguments<'_>) {
+        writeln!(self.text, "{line}").expect("writing to a string never fails");
+    }
+}
+
+/// Counts requests of matched routes with their latencies.
+pub async fn track(req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string());
+    let method = req.method().clone();
+    let start = Instant::now();
+    let response = next.run(req).await;
+    if let Some(route) = route {
+        METRICS.observe(method, &route, response.status(), start.elapsed());
+    }
+    response
+}
+
+pub async fn get_metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(&SYMBOLS),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::MemoryPolicy;
+    use crate::windows::Windows;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::default();
+        let route = "/stats/";
+        metrics.observe(Method::GET, route, StatusCode::OK, Duration::from_millis(3));
+        metrics.observe(Method::GET, route, StatusCode::OK, Duration::from_secs(5));
+        metrics.observe(Method::GET, route, StatusCode::NOT_FOUND, Duration::ZERO);
+
+        let registry = SymbolRegistry::new(None, MemoryPolicy::default());
+        let windows = Windows::geometric(2, 10);
+        registry
+            .add_batch("AB", &[1.0, f64::MAX, 2.0], None, None, &windows)
+            .unwrap();
+        let out = metrics.render(&registry);
+
+        let labels = "method=\"GET\",route=\"/stats/\"";
+        for line in [
+            format!("fast_stats_http_requests_total{{{labels},status=\"200\"}} 2"),
+            format!("fast_stats_http_requests_total{{{labels},status=\"404\"}} 1"),
+            format!("fast_stats_http_request_duration_seconds_bucket{{{labels},le=\"0.001\"}} 1"),
+            format!("fast_stats_http_request_duration_seconds_bucket{{{labels},le=\"0.005\"}} 2"),
+            format!("fast_stats_http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} 3"),
+            format!("fast_stats_http_request_duration_seconds_count{{{labels}}} 3"),
+            "fast_stats_values_ingested_total 2".into(),
+            "fast_stats_values_rejected_total 1".into(),
+            "fast_stats_symbols 1".into(),
+            // `1, 2` in the min queue, `2` in the max one
+            "fast_stats_monotonic_queue_entries 3".into(),
+        ] {
+            assert!(out.lines().any(|l| l == line), "{line} missing in\n{out}");
+        }
+        assert!(out.contains("# TYPE fast_stats_http_request_duration_seconds histogram\n"));
+        for transport in ["tcp", "udp"] {
+            let sample =
+                format!("fast_stats_line_protocol_errors_total{{transport=\"{transport}\"}} ");
+            assert!(
+                out.lines().any(|l| l.starts_with(&sample)),
+                "{sample} missing in\n{out}"
+            );
+        }
+    }
+}
@@ -0,0 +1,459 @@
+//! Rolling covariance, correlation and beta of a symbol against a benchmark symbol,
+//! see [`PairAggregator`].
+use crate::snapshot::{Decoder, Encoder, Persist, SnapshotError};
+use crate::variance::CoSums;
+use crate::windows::Windows;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+/// How values of the symbol of a pair are matched with values of its benchmark.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Alignment {
+    /// `i`-th value of the symbol with `i`-th value of the benchmark, counting since start
+    #[default]
+    Index,
+    /// values with equal timestamps; values without counterpart are dropped
+    Time,
+}
+
+/// Symbol and its benchmark, given as `SYMBOL:BENCHMARK`, e.g. `AAPL:SPY`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct PairSpec {
+    pub symbol: String,
+    pub benchmark: String,
+}
+
+impl FromStr for PairSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().split_once(':') {
+            Some((symbol, benchmark)) if !symbol.is_empty() && !benchmark.is_empty() => Ok(Self {
+                symbol: symbol.to_string(),
+                benchmark: benchmark.to_string(),
+            }),
+            _ => Err(format!("pair must be `SYMBOL:BENCHMARK`, got {s:?}")),
+        }
+    }
+}
+
+impl TryFrom<String> for PairSpec {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for PairSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.symbol, self.benchmark)
+    }
+}
+
+/// Symbol of a pair which values come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    Symbol = 0,
+    Benchmark = 1,
+}
+
+/// Values of one side of a pair waiting for their counterparts.
+#[derive(Default)]
+struct Pending {
+    /// values with their keys, i.e. indexes or timestamps, oldest first
+    values: VecDeque<(u64, f64)>,
+    /// key of the next value: its index, or the latest timestamp seen
+    next: u64,
+    /// sequence number of the last WAL record pushed, `0` if none
+    wal_seq: u64,
+}
+
+/// Covariance of a symbol and its benchmark over count windows of the symbol.
+///
+/// Values of each side wait until values of the other side with the same key, i.e. index or
+/// timestamp, arrive; a value whose counterpart can no longer arrive, as keys never decrease,
+/// is dropped. Aligned pairs are kept in a ring of the biggest window, and every level keeps
+/// shifted sums of both sides and of their products, so stats are `O(1)`, just like `var`.
+///
+/// At most the biggest window of values waits for the other side, older ones are dropped.
+pub struct PairAggregator {
+    alignment: Alignment,
+    /// windows of the symbol, whose count windows are kept
+    windows: Windows,
+    /// sizes of count windows, smallest first
+    sizes: Vec<usize>,
+    /// aligned pairs `(symbol, benchmark)` of the biggest window, oldest first
+    ring: VecDeque<(f64, f64)>,
+    levels: Vec<CoSums>,
+    /// pending values of the symbol and of the benchmark, see [`Side`]
+    sides: [Pending; 2],
+}
+
+impl PairAggregator {
+    /// Creates pair with count windows of `windows`, which are windows of the symbol.
+    pub fn new(alignment: Alignment, windows: &Windows) -> Self {
+        let sizes: Vec<usize> = windows.sizes().collect();
+        Self {
+            alignment,
+            windows: windows.clone(),
+            levels: vec![CoSums::default(); sizes.len()],
+            sizes,
+            ring: VecDeque::new(),
+            sides: Default::default(),
+        }
+    }
+
+    /// Whether values need timestamps to be aligned.
+    pub fn aligns_by_time(&self) -> bool {
+        self.alignment == Alignment::Time
+    }
+
+    /// Windows of the symbol the pair was created with; stats are kept for count ones only.
+    pub fn windows(&self) -> &Windows {
+        &self.windows
+    }
+
+    /// Whether `other` has the same alignment and windows, so its state can be taken over.
+    pub fn is_compatible(&self, other: &Self) -> bool {
+        self.alignment == other.alignment && self.windows == other.windows
+    }
+
+    /// Adds `values` of `side` observed at non-decreasing `timestamps`, and pairs them with
+    /// pending values of the other side.
+    ///
+    /// Values of a WAL record with sequence number `wal_seq` are skipped if the record was
+    /// pushed already, e.g. when it is replayed on top of a snapshot.
+    pub fn push(
+        &mut self,
+        side: Side,
+        values: &[f64],
+        timestamps: Option<&[u64]>,
+        wal_seq: Option<u64>,
+    ) {
+        let max_pending = self.max_size();
+        let pending = &mut self.sides[side as usize];
+        if let Some(seq) = wal_seq {
+            if seq <= pending.wal_seq {
+                return;
+            }
+            pending.wal_seq = seq;
+        }
+
+        for (i, &val) in values.iter().enumerate() {
+            let key = match (self.alignment, timestamps) {
+                (Alignment::Index, _) => {
+                    pending.next += 1;
+                    pending.next - 1
+                }
+                (Alignment::Time, Some(timestamps)) => {
+                    pending.next = pending.next.max(timestamps[i]);
+                    pending.next
+                }
+                (Alignment::Time, None) => pending.next,
+            };
+            if pending.values.len() == max_pending {
+                pending.values.pop_front();
+            }
+            pending.values.push_back((key, val));
+        }
+        self.align();
+    }
+
+    /// Pairs values of both sides with equal keys, dropping those without counterparts.
+    fn align(&mut self) {
+        loop {
+            let [symbol, benchmark] = &mut self.sides;
+            let (Some(&(key_x, x)), Some(&(key_y, y))) =
+                (symbol.values.front(), benchmark.values.front())
+            else {
+                return;
+            };
+            match key_x.cmp(&key_y) {
+                Ordering::Less => {
+                    symbol.values.pop_front();
+                }
+                Ordering::Greater => {
+                    benchmark.values.pop_front();
+                }
+                Ordering::Equal => {
+                    symbol.values.pop_front();
+                    benchmark.values.pop_front();
+                    self.push_pair(x, y);
+                }
+            }
+        }
+    }
+
+    /// Adds aligned pair to all levels, unless their sums would overflow.
+    fn push_pair(&mut self, x: f64, y: f64) {
+        if !self.levels.iter().all(|sums| sums.fits(x, y)) {
+            return;
+        }
+
+        let len = self.ring.len();
+        for (sums, &size) in self.levels.iter_mut().zip(&self.sizes) {
+            let mut count = len.min(size);
+            if count == size {
+                let (old_x, old_y) = self.ring[len - size];
+                sums.evict(old_x, old_y);
+                count -= 1;
+            }
+            sums.push(x, y, count);
+        }
+        if len == self.max_size() {
+            self.ring.pop_front();
+        }
+        self.ring.push_back((x, y));
+
+        let len = self.ring.len();
+        for (sums, &size) in self.levels.iter_mut().zip(&self.sizes) {
+            let count = len.min(size);
+            if sums.should_recenter(count) {
+                sums.recenter(self.ring.range(len - count..).copied());
+            }
+        }
+    }
+
+    fn max_size(&self) -> usize {
+        self.sizes.last().copied().unwrap_or_default()
+    }
+
+    /// Stats of the `k`-th count window, counting from `1`, or `None` if there is no such window.
+    pub fn stats(&self, k: u32) -> Option<PairStats> {
+        let level = (k as usize).checked_sub(1)?;
+        let size = *self.sizes.get(level)?;
+        let count = self.ring.len().min(size);
+        if count == 0 {
+            return Some(PairStats {
+                count,
+                cov: f64::NAN,
+                corr: f64::NAN,
+                beta: f64::NAN,
+            });
+        }
+
+        let moments = self.levels[level].moments(count);
+        let (cov, var_x, var_y) = (moments.cov, moments.var_x, moments.var_y);
+        let corr = if var_x > 0.0 && var_y > 0.0 {
+            (cov / (var_x * var_y).sqrt()).clamp(-1.0, 1.0)
+        } else {
+            f64::NAN
+        };
+        let beta = if var_y > 0.0 { cov / var_y } else { f64::NAN };
+        Some(PairStats {
+            count,
+            cov,
+            corr,
+            beta,
+        })
+    }
+
+    /// Approximate number of bytes allocated by the ring and pending values.
+    pub fn memory_usage(&self) -> usize {
+        size_of::<Self>()
+            + self.ring.capacity() * size_of::<(f64, f64)>()
+            + self.levels.capacity() * size_of::<CoSums>()
+            + self
+                .sides
+                .iter()
+                .map(|side| side.values.capacity() * size_of::<(u64, f64)>())
+                .sum::<usize>()
+    }
+}
+
+/// Stats of aligned pairs of a window, as returned by `/pair_stats/`.
+///
+/// Without any pairs, all but `count` are `null`; so are `corr` and `beta` of constant values.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct PairStats {
+    /// number of aligned pairs in the window
+    pub count: usize,
+    /// population covariance of the symbol and its benchmark
+    pub cov: f64,
+    /// Pearson correlation
+    pub corr: f64,
+    /// `cov / var` of the benchmark
+    pub beta: f64,
+}
+
+impl Persist for Alignment {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.u8(*self as u8)
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        match dec.u8()? {
+            0 => Ok(Self::Index),
+            1 => Ok(Self::Time),
+            kind => Err(SnapshotError::Corrupted(format!(
+                "unknown alignment {kind}"
+            ))),
+        }
+    }
+}
+
+impl Persist for Pending {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.usize(self.values.len())?;
+        for &(key, val) in &self.values {
+            enc.u64(key)?;
+            enc.f64(val)?;
+        }
+        enc.u64(self.next)?;
+        enc.u64(self.wal_seq)
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        let len = dec.usize()?;
+        let mut values = VecDeque::new();
+        for _ in 0..len {
+            values.push_back((dec.u64()?, dec.f64()?));
+        }
+        Ok(Self {
+            values,
+            next: dec.u64()?,
+            wal_seq: dec.u64()?,
+        })
+    }
+}
+
+impl Persist for PairAggregator {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        self.alignment.encode(enc)?;
+        self.windows.encode(enc)?;
+        for sums in &self.levels {
+            sums.encode(enc)?;
+        }
+        let (xs, ys): (Vec<f64>, Vec<f64>) = self.ring.iter().copied().unzip();
+        enc.f64_slice(&xs)?;
+        enc.f64_slice(&ys)?;
+        for side in &self.sides {
+            side.encode(enc)?;
+        }
+        Ok(())
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        let alignment = Alignment::decode(dec)?;
+        let windows = Windows::decode(dec)?;
+        let sizes: Vec<usize> = windows.sizes().collect();
+        let levels = (0..sizes.len())
+            .map(|_| CoSums::decode(dec))
+            .collect::<Result<_, _>>()?;
+        let (xs, ys) = (dec.f64_vec()?, dec.f64_vec()?);
+        if xs.len() != ys.len() || xs.len() > sizes.last().copied().unwrap_or_default() {
+            return Err(SnapshotError::Corrupted(format!(
+                "{} and {} aligned values of pair",
+                xs.len(),
+                ys.len()
+            )));
+        }
+        Ok(Self {
+            alignment,
+            windows,
+            sizes,
+            ring: xs.into_iter().zip(ys).collect(),
+            levels,
+            sides: [Pending::decode(dec)?, Pending::decode(dec)?],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn windows() -> Windows {
+        Windows::new(["3", "10"].map(|s| s.parse().unwrap())).unwrap()
+    }
+
+    /// Covariance, correlation and beta of `pairs` computed directly.
+    fn naive(pairs: &[(f64, f64)]) -> (f64, f64, f64) {
+        let n = pairs.len() as f64;
+        let mean_x = pairs.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_y = pairs.iter().map(|p| p.1).sum::<f64>() / n;
+        let cov = pairs
+            .iter()
+            .map(|(x, y)| (x - mean_x) * (y - mean_y))
+            .sum::<f64>()
+            / n;
+        let var_x = pairs.iter().map(|p| (p.0 - mean_x).powi(2)).sum::<f64>() / n;
+        let var_y = pairs.iter().map(|p| (p.1 - mean_y).powi(2)).sum::<f64>() / n;
+        (cov, cov / (var_x * var_y).sqrt(), cov / var_y)
+    }
+
+    fn assert_close(stats: PairStats, pairs: &[(f64, f64)]) {
+        let (cov, corr, beta) = naive(pairs);
+        assert_eq!(stats.count, pairs.len());
+        assert!((stats.cov - cov).abs() < 1e-9, "{} != {cov}", stats.cov);
+        assert!((stats.corr - corr).abs() < 1e-9, "{} != {corr}", stats.corr);
+        assert!((stats.beta - beta).abs() < 1e-9, "{} != {beta}", stats.beta);
+    }
+
+    #[test]
+    fn test_aligned_by_index() {
+        let mut pair = PairAggregator::new(Alignment::Index, &windows());
+        assert!(pair.stats(1).unwrap().cov.is_nan());
+
+        let xs: Vec<f64> = (0..20).map(|i| 100.0 + (i * 7 % 5) as f64).collect();
+        let ys: Vec<f64> = (0..20).map(|i| 50.0 + (i * 3 % 4) as f64).collect();
+        // benchmark runs ahead, then the symbol catches up in uneven batches
+        pair.push(Side::Benchmark, &ys[..12], None, None);
+        assert_eq!(pair.stats(2).unwrap().count, 0);
+        pair.push(Side::Symbol, &xs[..5], None, None);
+        pair.push(Side::Symbol, &xs[5..17], None, None);
+        pair.push(Side::Benchmark, &ys[12..], None, None);
+        pair.push(Side::Symbol, &xs[17..], None, None);
+
+        let pairs: Vec<_> = xs.into_iter().zip(ys).collect();
+        assert_close(pair.stats(1).unwrap(), &pairs[17..]);
+        assert_close(pair.stats(2).unwrap(), &pairs[10..]);
+        assert!(pair.stats(3).is_none());
+
+        // perfectly correlated, with benchmark moving half as much
+        let mut pair = PairAggregator::new(Alignment::Index, &windows());
+        pair.push(Side::Symbol, &[1.0, 3.0, 2.0, 5.0], None, None);
+        pair.push(Side::Benchmark, &[10.0, 11.0, 10.5, 12.0], None, None);
+        let stats = pair.stats(2).unwrap();
+        assert_eq!(stats.corr, 1.0);
+        assert!((stats.beta - 2.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_aligned_by_time() {
+        let mut pair = PairAggregator::new(Alignment::Time, &windows());
+        pair.push(
+            Side::Symbol,
+            &[1.0, 2.0, 3.0, 4.0],
+            Some(&[10, 20, 30, 40]),
+            None,
+        );
+        // nothing at `10` and `40`, nor at `25` for the symbol
+        pair.push(Side::Benchmark, &[5.0, 6.0, 7.0], Some(&[20, 25, 30]), None);
+        pair.push(Side::Benchmark, &[9.0, 8.0], Some(&[50, 60]), None);
+        pair.push(Side::Symbol, &[6.0], Some(&[60]), None);
+        assert_close(
+            pair.stats(2).unwrap(),
+            &[(2.0, 5.0), (3.0, 7.0), (6.0, 8.0)],
+        );
+    }
+
+    #[test]
+    fn test_replayed_records_are_skipped() {
+        let mut pair = PairAggregator::new(Alignment::Index, &windows());
+        pair.push(Side::Symbol, &[1.0, 2.0], None, Some(1));
+        pair.push(Side::Benchmark, &[3.0, 5.0], None, Some(2));
+        pair.push(Side::Symbol, &[1.0, 2.0], None, Some(1));
+        pair.push(Side::Benchmark, &[3.0, 5.0], None, Some(2));
+        assert_eq!(pair.stats(1).unwrap().count, 2);
+        assert_eq!(pair.stats(1).unwrap().beta, 0.5);
+    }
+}
@@ -0,0 +1,453 @@
+//! Approximate quantiles of windows too big to keep their values sorted.
+use crate::snapshot::{Decoder, Encoder, Persist, SnapshotError};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+/// Number of items at which a compactor is compacted.
+///
+/// Deterministic rank error of a sketch of `n` values is at most `n * log2(n / K) / K`.
+const K: usize = 1024;
+
+/// Number of buckets a window is split into. The oldest bucket may be partially out
+/// of the window, which adds up to `1 / BUCKETS` of window length to the rank error.
+pub const BUCKETS: u64 = 128;
+
+/// Mergeable quantile sketch of a stream of values, a stack of compactors in the style of
+/// MRL/KLL sketches, but deterministic, so its rank error is a bound rather than a probability.
+///
+/// Items of compactor `h` stand for `2^h` values each. When a compactor reaches [`K`] items,
+/// they are sorted and every other one is promoted to the next compactor, alternating
+/// between odd and even ones. Each compaction changes rank of any value by at most
+/// the weight of single item, which is tracked in `rank_error`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QuantileSketch {
+    /// items of compactor `h` have weight `2^h`; unsorted between compactions
+    compactors: Vec<Vec<f64>>,
+    /// bit `h` is set if compactor `h` promotes odd items at its next compaction
+    odd: u64,
+    /// number of values summarized, i.e. total weight of items
+    len: u64,
+    /// upper bound of the difference between estimated and true rank of any value
+    rank_error: u64,
+}
+
+impl QuantileSketch {
+    pub fn insert(&mut self, val: f64) {
+        if self.compactors.is_empty() {
+            self.compactors.push(Vec::new());
+        }
+        self.compactors[0].push(val);
+        self.len += 1;
+
+        let mut h = 0;
+        while h < self.compactors.len() && self.compactors[h].len() >= K {
+            self.compact(h);
+            h += 1;
+        }
+    }
+
+    /// Adds all values of `other` to this sketch.
+    pub fn merge(&mut self, other: &Self) {
+        if self.compactors.len() < other.compactors.len() {
+            self.compactors
+                .resize_with(other.compactors.len(), Vec::new);
+        }
+        for (items, other_items) in self.compactors.iter_mut().zip(&other.compactors) {
+            items.extend_from_slice(other_items);
+        }
+        self.len += other.len;
+        self.rank_error += other.rank_error;
+
+        let mut h = 0;
+        while h < self.compactors.len() {
+            if self.compactors[h].len() >= K {
+                self.compact(h);
+            }
+            h += 1;
+        }
+    }
+
+    /// Halves compactor `h` by promoting every other sorted item to compactor `h + 1`.
+    fn compact(&mut self, h: usize) {
+        if h + 1 == self.compactors.len() {
+            self.compactors.push(Vec::new());
+        }
+        let (lower, upper) = self.compactors.split_at_mut(h + 1);
+        let items = &mut lower[h];
+        items.sort_unstable_by(f64::total_cmp);
+        // odd item out stays, so weights still sum up to `len`
+        let kept = if items.len() % 2 == 1 {
+            items.pop()
+        } else {
+            None
+        };
+        let offset = ((self.odd >> h) & 1) as usize;
+        upper[0].extend(items.iter().skip(offset).step_by(2));
+        items.clear();
+        items.extend(kept);
+
+        self.odd ^= 1 << h;
+        self.rank_error += 1 << h;
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn rank_error(&self) -> u64 {
+        self.rank_error
+    }
+
+    /// Items with their weights, in no particular order.
+    pub fn items(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        self.compactors
+            .iter()
+            .enumerate()
+            .flat_map(|(h, items)| items.iter().map(move |&val| (val, 1 << h)))
+    }
+
+    /// Approximate number of bytes allocated by compactors.
+    pub fn memory_usage(&self) -> usize {
+        self.compactors.capacity() * size_of::<Vec<f64>>()
+            + self
+                .compactors
+                .iter()
+                .map(|items| items.capacity() * size_of::<f64>())
+                .sum::<usize>()
+    }
+}
+
+impl Persist for QuantileSketch {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.usize(self.compactors.len())?;
+        for items in &self.compactors {
+            enc.f64_slice(items)?;
+        }
+        enc.u64(self.odd)?;
+        enc.u64(self.len)?;
+        enc.u64(self.rank_error)
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        let levels = dec.usize()?;
+        if levels > u64::BITS as usize {
+            return Err(SnapshotError::Corrupted(format!(
+                "quantile sketch with {levels} compactors"
+            )));
+        }
+        let mut compactors = Vec::new();
+        for _ in 0..levels {
+            compactors.push(dec.f64_vec()?);
+        }
+        let sketch = Self {
+            compactors,
+            odd: dec.u64()?,
+            len: dec.u64()?,
+            rank_error: dec.u64()?,
+        };
+        if sketch.items().map(|(_, weight)| weight).sum::<u64>() != sketch.len {
+            return Err(SnapshotError::Corrupted(
+                "quantile sketch weights do not match its length".into(),
+            ));
+        }
+        Ok(sketch)
+    }
+}
+
+/// Values of consecutive positions, e.g. indexes of values pushed to the ring.
+#[derive(Debug, Clone, PartialEq)]
+struct Bucket {
+    /// buckets are split when id changes, e.g. every `size / BUCKETS` positions
+    id: u64,
+    /// position of the first value
+    start: u64,
+    sketch: QuantileSketch,
+}
+
+impl Bucket {
+    fn end(&self) -> u64 {
+        self.start + self.sketch.len()
+    }
+}
+
+/// Quantile sketches of a sliding window, split into buckets of consecutive values.
+///
+/// Buckets are dropped once all their values are out of the window, so the oldest bucket may
+/// still have some values which are not. Those are counted in the rank error.
+///
+/// Sketches of all buckets but the newest are merged lazily for queries and cached until
+/// a bucket is added or dropped, so queries do not merge all buckets each time.
+/// Merging compacts as well, so the cache is as small as a single sketch.
+/// Sorted items of the newest bucket are cached as well, until the next push.
+#[derive(Debug, Clone, Default)]
+pub struct SlidingQuantiles {
+    /// oldest first
+    buckets: VecDeque<Bucket>,
+    /// items of merged sketches of all buckets but the newest, sorted, with cumulative weights
+    sealed: Option<Vec<(f64, u64)>>,
+    /// rank error of all buckets but the newest
+    sealed_rank_error: u64,
+    /// items of the newest bucket, sorted, with cumulative weights
+    newest: Option<Vec<(f64, u64)>>,
+}
+
+impl SlidingQuantiles {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pushes `val` at `position`, which follows the previous one, to bucket `bucket`.
+    pub fn push(&mut self, bucket: u64, position: u64, val: f64) {
+        if self.buckets.back().is_none_or(|back| back.id != bucket) {
+            self.buckets.push_back(Bucket {
+                id: bucket,
+                start: position,
+                sketch: QuantileSketch::default(),
+            });
+            self.sealed = None;
+        }
+        let back = self.buckets.back_mut().expect("pushed above");
+        back.sketch.insert(val);
+        self.newest = None;
+    }
+
+    /// Drops buckets whose values are all before `window_start` position.
+    pub fn evict(&mut self, window_start: u64) {
+        while let Some(front) = self.buckets.front()
+            && front.end() <= window_start
+        {
+            self.buckets.pop_front();
+            self.sealed = None;
+        }
+    }
+
+    /// Quantiles of the window starting at `window_start` position, till the latest value.
+    pub fn summary(&mut self, window_start: u64) -> QuantileSummary<'_> {
+        self.evict(window_start);
+        let Some(newest) = self.buckets.back() else {
+            return QuantileSummary::default();
+        };
+
+        if self.sealed.is_none() {
+            let mut merged = QuantileSketch::default();
+            for bucket in self.buckets.range(..self.buckets.len() - 1) {
+                merged.merge(&bucket.sketch);
+            }
+            let mut sealed: Vec<(f64, u64)> = merged.items().collect();
+            cumulate(&mut sealed);
+            self.sealed_rank_error = merged.rank_error();
+            self.sealed = Some(sealed);
+        }
+
+        let newest_items = self.newest.get_or_insert_with(|| {
+            let mut items: Vec<(f64, u64)> = newest.sketch.items().collect();
+            cumulate(&mut items);
+            items
+        });
+
+        let front = self.buckets.front().expect("newest exists");
+        let len = newest.end() - front.start;
+        let window_len = newest.end().saturating_sub(window_start).max(1);
+        // values of the oldest bucket out of the window are counted as if they were in it
+        let outside = window_start.saturating_sub(front.start);
+        let rank_error = outside + self.sealed_rank_error + newest.sketch.rank_error();
+
+        QuantileSummary {
+            sealed: self.sealed.as_deref().unwrap_or_default(),
+            newest: newest_items,
+            len,
+            rank_error: rank_error as f64 / window_len as f64,
+        }
+    }
+
+    /// Approximate number of bytes allocated by buckets and merged items.
+    pub fn memory_usage(&self) -> usize {
+        self.buckets.capacity() * size_of::<Bucket>()
+            + self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.sketch.memory_usage())
+                .sum::<usize>()
+            + self
+                .sealed
+                .as_ref()
+                .map_or(0, |sealed| sealed.capacity() * size_of::<(f64, u64)>())
+            + self
+                .newest
+                .as_ref()
+                .map_or(0, |newest| newest.capacity() * size_of::<(f64, u64)>())
+    }
+}
+
+impl Persist for SlidingQuantiles {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.usize(self.buckets.len())?;
+        for bucket in &self.buckets {
+            enc.u64(bucket.id)?;
+            enc.u64(bucket.start)?;
+            bucket.sketch.encode(enc)?;
+        }
+        Ok(())
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        let len = dec.usize()?;
+        let mut quantiles = Self::new();
+        for _ in 0..len {
+            let bucket = Bucket {
+                id: dec.u64()?,
+                start: dec.u64()?,
+                sketch: QuantileSketch::decode(dec)?,
+            };
+            if let Some(back) = quantiles.buckets.back()
+                && back.end() != bucket.start
+            {
+                return Err(SnapshotError::Corrupted(format!(
+                    "quantile bucket {} starts at {}, but previous ends at {}",
+                    bucket.id,
+                    bucket.start,
+                    back.end()
+                )));
+            }
+            quantiles.buckets.push_back(bucket);
+        }
+        Ok(quantiles)
+    }
+}
+
+/// Sorts weighted items by value and turns weights into cumulative ones.
+fn cumulate(items: &mut [(f64, u64)]) {
+    items.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+    let mut total = 0;
+    for (_, weight) in items.iter_mut() {
+        total += *weight;
+        *weight = total;
+    }
+}
+
+/// Approximate quantiles of a window, see [`SlidingQuantiles::summary`].
+#[derive(Debug, Default)]
+pub struct QuantileSummary<'a> {
+    /// sorted items with cumulative weights
+    sealed: &'a [(f64, u64)],
+    newest: &'a [(f64, u64)],
+    /// total weight of items
+    len: u64,
+    /// upper bound of rank error of returned quantiles, as a fraction of window length
+    pub rank_error: f64,
+}
+
+impl QuantileSummary<'_> {
+    /// Approximate quantile `q` in `[0, 1]`, `NaN` if there are no values.
+    pub fn quantile(&self, q: f64) -> f64 {
+        if self.len == 0 {
+            return f64::NAN;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.len as f64).ceil() as u64).max(1);
+
+        // smallest value of either list, with at least `rank` values not greater than it
+        let below = |items: &[(f64, u64)]| {
+            let i = items.partition_point(|&(val, _)| self.rank(val) < rank);
+            items.get(i).map(|&(val, _)| val)
+        };
+        match (below(self.sealed), below(self.newest)) {
+            (Some(a), Some(b)) => a.min(b),
+            (a, b) => a.or(b).unwrap_or(f64::NAN),
+        }
+    }
+
+    /// Estimated number of values not greater than `val`.
+    fn rank(&self, val: f64) -> u64 {
+        let rank_in = |items: &[(f64, u64)]| {
+            let i = items.partition_point(|&(item, _)| item.total_cmp(&val).is_le());
+            if i == 0 { 0 } else { items[i - 1].1 }
+        };
+        rank_in(self.sealed) + rank_in(self.newest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// True rank error of `estimate` for quantile `q` of sorted `values`, as a fraction.
+    fn rank_error(values: &[f64], q: f64, estimate: f64) -> f64 {
+        let rank = (q * values.len() as f64).ceil().max(1.0);
+        let lower = values.partition_point(|&v| v < estimate) as f64;
+        let upper = values.partition_point(|&v| v <= estimate) as f64;
+        let error = if rank < lower {
+            lower - rank + 1.0
+        } else if rank > upper {
+            rank - upper
+        } else {
+            0.0
+        };
+        error / values.len() as f64
+    }
+
+    #[test]
+    fn test_sketch_within_rank_error() {
+        let values: Vec<f64> = (0..200_000)
+            .map(|i| ((i * 7919) % 200_003) as f64)
+            .collect();
+        let (mut sketch, mut other) = (QuantileSketch::default(), QuantileSketch::default());
+        for &val in &values[..150_000] {
+            sketch.insert(val);
+        }
+        for &val in &values[150_000..] {
+            other.insert(val);
+        }
+        sketch.merge(&other);
+        assert_eq!(sketch.len(), values.len() as u64);
+        assert!(sketch.items().count() < values.len() / 10);
+
+        let mut quantiles = SlidingQuantiles::new();
+        quantiles.buckets.push_back(Bucket {
+            id: 0,
+            start: 0,
+            sketch,
+        });
+        let summary = quantiles.summary(0);
+        assert!(summary.rank_error > 0.0 && summary.rank_error < 0.02);
+
+        let mut sorted = values.clone();
+        sorted.sort_by(f64::total_cmp);
+        for q in [0.0, 0.01, 0.25, 0.5, 0.9, 0.999, 1.0] {
+            let estimate = summary.quantile(q);
+            assert!(rank_error(&sorted, q, estimate) <= summary.rank_error);
+        }
+        assert_eq!(summary.quantile(0.0), sorted[0]);
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let size = 10_000;
+        let bucket_size = size / BUCKETS;
+        let values: Vec<f64> = (0..50_000).map(|i| ((i * 31) % 10_007) as f64).collect();
+        let mut quantiles = SlidingQuantiles::new();
+        for (position, &val) in values.iter().enumerate() {
+            let position = position as u64;
+            quantiles.push(position / bucket_size, position, val);
+        }
+
+        let window_start = values.len() as u64 - size;
+        let summary = quantiles.summary(window_start);
+        // up to one bucket of evicted values, besides compactions
+        assert!(summary.rank_error <= 1.0 / BUCKETS as f64 + 0.01);
+
+        let mut window = values[window_start as usize..].to_vec();
+        window.sort_by(f64::total_cmp);
+        for q in [0.05, 0.5, 0.95] {
+            let estimate = summary.quantile(q);
+            assert!(rank_error(&window, q, estimate) <= summary.rank_error);
+        }
+
+        // all values evicted
+        assert!(
+            quantiles
+                .summary(values.len() as u64)
+                .quantile(0.5)
+                .is_nan()
+        );
+    }
+}
@@ -0,0 +1,823 @@
+use crate::alerts::Alerts;
+use crate::clock::{Clock, SystemClock};
+use crate::error::Error;
+use crate::pairs::{PairAggregator, PairSpec, Side};
+use crate::symbol_aggregator::{AggregatorOptions, SymbolAggregator};
+use crate::wal::{Wal, WalRecord};
+use crate::windows::Windows;
+use clap::ValueEnum;
+use dashmap::DashMap;
+use dashmap::mapref::one::Ref;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::watch;
+
+/// What to do when admitting values would exceed the memory budget.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum MemoryPolicy {
+    /// drop least recently used symbols until values fit
+    #[default]
+    EvictLru,
+    /// refuse values which do not fit
+    Reject,
+}
+
+/// Values of a symbol to add, observed at optional `timestamps` and of optional `weights`,
+/// one per value, see [`SymbolRegistry::add_batch`].
+#[derive(Debug, Clone, Copy)]
+pub struct Batch<'a> {
+    pub symbol: &'a str,
+    pub values: &'a [f64],
+    pub timestamps: Option<&'a [u64]>,
+    pub weights: Option<&'a [f64]>,
+}
+
+/// Aggregator of single symbol, with bookkeeping needed by [`SymbolRegistry`].
+pub struct SymbolEntry {
+    pub aggregator: Mutex<SymbolAggregator>,
+    /// registry tick of the last access, for LRU eviction
+    last_used: AtomicU64,
+    /// bytes accounted to this symbol in the registry
+    memory: AtomicUsize,
+    /// sequence number of the last WAL record applied to the aggregator, `0` if none;
+    /// updated while holding the aggregator lock
+    wal_seq: AtomicU64,
+}
+
+impl SymbolEntry {
+    /// New entry, whose memory is not accounted yet.
+    fn new(aggregator: SymbolAggregator, last_used: u64, wal_seq: u64) -> Self {
+        Self {
+            memory: AtomicUsize::new(0),
+            aggregator: Mutex::new(aggregator),
+            last_used: AtomicU64::new(last_used),
+            wal_seq: AtomicU64::new(wal_seq),
+        }
+    }
+
+    /// Sequence number of the last WAL record applied; read it while holding the aggregator lock.
+    pub fn wal_seq(&self) -> u64 {
+        self.wal_seq.load(Ordering::Relaxed)
+    }
+}
+
+/// Registered pair of symbols, see [`PairAggregator`].
+pub struct PairEntry {
+    pub spec: PairSpec,
+    pub aggregator: Mutex<PairAggregator>,
+}
+
+/// All symbols with their aggregators, kept within optional memory budget.
+///
+/// Memory is accounted per symbol after each batch, so the budget is soft:
+/// concurrent batches of different symbols may overshoot it slightly.
+/// Before a batch is applied, the registry makes room for the growth of the values ring,
+/// which dominates the memory usage, according to [`MemoryPolicy`].
+pub struct SymbolRegistry {
+    /// shared with atomic batches of many symbols while they hold their locks
+    symbols: DashMap<String, Arc<SymbolEntry>>,
+    /// max number of bytes for all symbols, unlimited if `None`
+    budget: Option<usize>,
+    policy: MemoryPolicy,
+    /// bytes used by all symbols
+    used: AtomicUsize,
+    /// values added to aggregators, including replayed ones
+    ingested: AtomicU64,
+    /// values skipped by aggregators, as their squares are not finite
+    rejected: AtomicU64,
+    /// logical clock for LRU; bumped on every access
+    tick: AtomicU64,
+    /// log of accepted batches, if enabled
+    wal: OnceLock<Wal>,
+    /// time of batches without timestamps and of queries of time windows
+    clock: Arc<dyn Clock>,
+    /// options of newly created aggregators
+    options: AggregatorOptions,
+    /// pairs fed by batches of their symbols; there are few of them, so they are just scanned
+    pairs: Vec<PairEntry>,
+    /// notified after every batch of symbols with subscribers, see [`SymbolRegistry::subscribe`]
+    updates: DashMap<String, watch::Sender<()>>,
+    /// rules evaluated after every new batch, but not replayed ones
+    alerts: Alerts,
+}
+
+impl SymbolRegistry {
+    pub fn new(budget: Option<usize>, policy: MemoryPolicy) -> Self {
+        Self {
+            symbols: DashMap::new(),
+            budget,
+            policy,
+            used: AtomicUsize::new(0),
+            ingested: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            tick: AtomicU64::new(0),
+            wal: OnceLock::new(),
+            clock: Arc::new(SystemClock),
+            options: AggregatorOptions::default(),
+            pairs: Vec::new(),
+            updates: DashMap::new(),
+            alerts: Alerts::default(),
+        }
+    }
+
+    /// Replaces alerts without rules, e.g. with ones saved to a file.
+    pub fn with_alerts(mut self, alerts: Alerts) -> Self {
+        self.alerts = alerts;
+        self
+    }
+
+    pub fn alerts(&self) -> &Alerts {
+        &self.alerts
+    }
+
+    /// Replaces the system clock, e.g. with a manual one in tests.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Sets options of aggregators created for new symbols.
+    pub fn with_options(mut self, options: AggregatorOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Registers pairs, whose aggregators are fed by every later batch of their symbols.
+    ///
+    /// Pairs are not accounted in the memory budget.
+    pub fn with_pairs(
+        mut self,
+        pairs: impl IntoIterator<Item = (PairSpec, PairAggregator)>,
+    ) -> Self {
+        self.pairs
+            .extend(pairs.into_iter().map(|(spec, aggregator)| PairEntry {
+                spec,
+                aggregator: Mutex::new(aggregator),
+            }));
+        self
+    }
+
+    /// Aggregator of registered pair of `symbol` and `benchmark`.
+    pub fn pair(&self, symbol: &str, benchmark: &str) -> Option<&Mutex<PairAggregator>> {
+        self.pairs
+            .iter()
+            .find(|pair| pair.spec.symbol == symbol && pair.spec.benchmark == benchmark)
+            .map(|pair| &pair.aggregator)
+    }
+
+    /// All registered pairs.
+    pub fn pairs(&self) -> &[PairEntry] {
+        &self.pairs
+    }
+
+    /// Replaces state of registered pair `spec`, e.g. restored from a snapshot.
+    ///
+    /// Returns `false` if the pair is not registered, or has different alignment or windows now.
+    pub fn restore_pair(&self, spec: &PairSpec, aggregator: PairAggregator) -> bool {
+        let Some(pair) = self.pair(&spec.symbol, &spec.benchmark) else {
+            return false;
+        };
+        let mut pair = pair.lock().unwrap();
+        if !pair.is_compatible(&aggregator) {
+            return false;
+        }
+        *pair = aggregator;
+        true
+    }
+
+    /// Pairs of `symbol`, with its side in each.
+    fn pairs_of<'a>(&'a self, symbol: &'a str) -> impl Iterator<Item = (Side, &'a PairEntry)> + 'a {
+        self.pairs.iter().filter_map(move |pair| {
+            if pair.spec.symbol == symbol {
+                Some((Side::Symbol, pair))
+            } else if pair.spec.benchmark == symbol {
+                Some((Side::Benchmark, pair))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Current time in milliseconds since unix epoch.
+    pub fn now(&self) -> u64 {
+        self.clock.now_millis()
+    }
+
+    /// Starts logging every accepted batch to `wal` before applying it.
+    ///
+    /// Fails if the registry already has a WAL.
+    pub fn set_wal(&self, wal: Wal) -> Result<(), Wal> {
+        self.wal.set(wal)
+    }
+
+    pub fn wal(&self) -> Option<&Wal> {
+        self.wal.get()
+    }
+
+    /// Gets symbol entry, marking it as recently used.
+    pub fn get(&self, symbol: &str) -> Option<Ref<'_, String, Arc<SymbolEntry>>> {
+        let entry = self.symbols.get(symbol)?;
+        self.touch(&entry);
+        Some(entry)
+    }
+
+    /// Gets symbol entry without affecting LRU order.
+    pub fn peek(&self, symbol: &str) -> Option<Ref<'_, String, Arc<SymbolEntry>>> {
+        self.symbols.get(symbol)
+    }
+
+    /// Names of all symbols.
+    pub fn symbols(&self) -> Vec<String> {
+        self.symbols
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect()
+    }
+
+    /// Inserts (or replaces) aggregator of a symbol, e.g. restored from a snapshot,
+    /// with sequence number of the last WAL record it includes.
+    ///
+    /// Memory budget is not enforced here.
+    pub fn insert(&self, symbol: String, aggregator: SymbolAggregator, wal_seq: u64) {
+        let usage = aggregator.memory_usage();
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed);
+        let entry = SymbolEntry::new(aggregator, tick, wal_seq);
+        entry.memory.store(usage, Ordering::Relaxed);
+        self.used.fetch_add(usage, Ordering::Relaxed);
+        if let Some(old) = self.symbols.insert(symbol, Arc::new(entry)) {
+            self.used
+                .fetch_sub(old.memory.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    /// Inserts aggregator of a symbol restored from a snapshot, unless it was built with other
+    /// `windows` or options than aggregators of new symbols are.
+    ///
+    /// Returns `false` if it was not inserted.
+    pub fn restore_symbol(
+        &self,
+        symbol: String,
+        aggregator: SymbolAggregator,
+        wal_seq: u64,
+        windows: &Windows,
+    ) -> bool {
+        if aggregator.windows() != windows || *aggregator.options() != self.options {
+            return false;
+        }
+        self.insert(symbol, aggregator, wal_seq);
+        true
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Number of bytes used by all symbols.
+    pub fn memory_used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Numbers of values added to aggregators and skipped by them so far.
+    pub fn values_ingested(&self) -> (u64, u64) {
+        (
+            self.ingested.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Adds `values` to the `symbol` aggregator, creating one with `windows` if needed.
+    ///
+    /// Values are observed at `timestamps` (milliseconds since unix epoch), one per value,
+    /// or now if there are none. Values have `weights`, e.g. volumes, or none at all.
+    /// With WAL enabled, the batch is logged before it is applied.
+    /// Values are then pushed to pairs of the symbol, if any.
+    /// Fails with [`Error::MemoryBudgetExceeded`] if values do not fit into the budget.
+    pub fn add_batch(
+        &self,
+        symbol: &str,
+        values: &[f64],
+        timestamps: Option<&[u64]>,
+        weights: Option<&[f64]>,
+        windows: &Windows,
+    ) -> Result<(), Error> {
+        let batch = Batch {
+            symbol,
+            values,
+            timestamps,
+            weights,
+        };
+        self.apply(&batch, windows, None)
+    }
+
+    /// Adds `batches` of distinct symbols atomically: either all of them, or none if any
+    /// does not fit into the budget, creating aggregators with `windows_for` their symbols.
+    ///
+    /// Room for all batches is made up front, aggregators of all symbols are locked at once,
+    /// and batches are logged as a single WAL group, so replay applies all of them, or none.
+    pub fn add_batches<'w>(
+        &self,
+        batches: &[Batch<'_>],
+        windows_for: impl Fn(&str) -> &'w Windows,
+    ) -> Result<(), Error> {
+        // locked in order of symbols, so concurrent atomic batches never wait for each other in a cycle
+        let mut batches: Vec<&Batch> = batches.iter().collect();
+        batches.sort_by_key(|batch| batch.symbol);
+        debug_assert!(
+            batches
+                .windows(2)
+                .all(|pair| pair[0].symbol < pair[1].symbol)
+        );
+        let symbols: Vec<&str> = batches.iter().map(|batch| batch.symbol).collect();
+
+        let needed = batches
+            .iter()
+            .map(|batch| self.needed_for(batch, windows_for(batch.symbol)))
+            .sum();
+        self.make_room(&symbols, needed)?;
+
+        // entries are shared rather than borrowed from the map, as holding references to many
+        // entries could deadlock with writers to the map; shared entries are never evicted
+        let entries = loop {
+            for batch in &batches {
+                self.entry_of(batch.symbol, windows_for(batch.symbol));
+            }
+            let entries: Option<Vec<_>> = symbols
+                .iter()
+                .map(|symbol| self.symbols.get(*symbol).map(|entry| Arc::clone(&entry)))
+                .collect();
+            // otherwise some were evicted by concurrent batches before they got shared
+            if let Some(entries) = entries {
+                break entries;
+            }
+        };
+        let mut aggs: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                self.touch(entry);
+                entry.aggregator.lock().unwrap()
+            })
+            .collect();
+
+        let timestamps: Vec<_> = batches
+            .iter()
+            .zip(&aggs)
+            .map(|(batch, agg)| self.timestamps_for(batch, agg))
+            .collect();
+        let seqs = match self.wal.get() {
+            Some(wal) => {
+                let logged: Vec<Batch> = batches
+                    .iter()
+                    .zip(&timestamps)
+                    .map(|(batch, timestamps)| Batch {
+                        timestamps: timestamps.as_deref(),
+                        ..**batch
+                    })
+                    .collect();
+                let seqs = wal
+                    .append_group(&logged)
+                    .map_err(|err| anyhow::anyhow!("Unable to write WAL: {err}"))?;
+                Some(seqs)
+            }
+            None => None,
+        };
+
+        for (i, (batch, agg)) in batches.iter().zip(&mut aggs).enumerate() {
+            let seq = seqs.as_ref().map(|seqs| seqs.start + i as u64);
+            self.apply_locked(&entries[i], agg, batch, timestamps[i].as_deref(), seq);
+            self.alerts.evaluate(batch.symbol, agg, self.now());
+        }
+        drop(aggs);
+        for symbol in symbols {
+            self.notify(symbol);
+        }
+        Ok(())
+    }
+
+    /// Applies batch read from the WAL at startup, unless the aggregator already includes it.
+    pub fn replay(&self, record: &WalRecord, windows: &Windows) -> Result<(), Error> {
+        if let Some(entry) = self.symbols.get(&record.symbol)
+            && entry.wal_seq() >= record.seq
+        {
+            return Ok(());
+        }
+        let batch = Batch {
+            symbol: &record.symbol,
+            values: &record.values,
+            timestamps: record.timestamps.as_deref(),
+            weights: record.weights.as_deref(),
+        };
+        self.apply(&batch, windows, Some(record.seq))
+    }
+
+    /// Adds values of a new batch, or of a `replayed` WAL record with given sequence number.
+    fn apply(
+        &self,
+        batch: &Batch<'_>,
+        windows: &Windows,
+        replayed: Option<u64>,
+    ) -> Result<(), Error> {
+        // estimate without holding the entry, as eviction needs write access to the map
+        let needed = self.needed_for(batch, windows);
+        self.make_room(&[batch.symbol], needed)?;
+
+        let entry = self.entry_of(batch.symbol, windows);
+        self.touch(&entry);
+
+        // log while holding the lock, so the WAL has batches of a symbol in the order they are applied
+        let mut agg = entry.aggregator.lock().unwrap();
+        let timestamps = self.timestamps_for(batch, &agg);
+        let seq = match (replayed, self.wal.get()) {
+            (Some(seq), _) => Some(seq),
+            (None, Some(wal)) => Some(
+                wal.append(
+                    batch.symbol,
+                    batch.values,
+                    timestamps.as_deref(),
+                    batch.weights,
+                )
+                .map_err(|err| anyhow::anyhow!("Unable to write WAL: {err}"))?,
+            ),
+            (None, None) => None,
+        };
+        self.apply_locked(&entry, &mut agg, batch, timestamps.as_deref(), seq);
+        if replayed.is_none() {
+            self.alerts.evaluate(batch.symbol, &mut agg, self.now());
+        }
+        drop(agg);
+        self.notify(batch.symbol);
+        Ok(())
+    }
+
+    /// Receiver marked as changed after every batch of `symbol` applied from now on.
+    ///
+    /// Batches applied before the receiver sees a change are coalesced into it.
+    pub fn subscribe(&self, symbol: &str) -> watch::Receiver<()> {
+        self.updates
+            .entry(symbol.to_string())
+            .or_insert_with(|| watch::channel(()).0)
+            .subscribe()
+    }
+
+    fn notify(&self, symbol: &str) {
+        let Some(sender) = self.updates.get(symbol) else {
+            return;
+        };
+        let unsubscribed = sender.send(()).is_err();
+        drop(sender);
+        // all receivers are gone, unless some subscribed meanwhile
+        if unsubscribed {
+            self.updates
+                .remove_if(symbol, |_, sender| sender.receiver_count() == 0);
+        }
+    }
+
+    /// Bytes needed to add `batch`, including a new aggregator with `windows` if there is none.
+    fn needed_for(&self, batch: &Batch<'_>, windows: &Windows) -> usize {
+        let (n, weighted) = (batch.values.len(), batch.weights.is_some());
+        match self.symbols.get(batch.symbol) {
+            Some(entry) => {
+                let agg = entry.aggregator.lock().unwrap();
+                agg.estimated_growth(n, weighted)
+            }
+            None => {
+                let agg = SymbolAggregator::with_options(windows.clone(), self.options.clone());
+                agg.memory_usage() + agg.estimated_growth(n, weighted)
+            }
+        }
+    }
+
+    /// Entry of `symbol`, created with `windows` if there is none.
+    fn entry_of(&self, symbol: &str, windows: &Windows) -> Ref<'_, String, Arc<SymbolEntry>> {
+        self.symbols
+            .entry(symbol.to_string())
+            .or_insert_with(|| {
+                let agg = SymbolAggregator::with_options(windows.clone(), self.options.clone());
+                Arc::new(SymbolEntry::new(agg, 0, 0))
+            })
+            .downgrade()
+    }
+
+    /// Timestamps of `batch` resolved by `agg` up front, so replay gets exactly the same ones.
+    fn timestamps_for(&self, batch: &Batch<'_>, agg: &SymbolAggregator) -> Option<Vec<u64>> {
+        let n = batch.values.len();
+        let resolved = agg.timestamps_for(n, batch.timestamps, self.now());
+        if resolved.is_none()
+            && self
+                .pairs_of(batch.symbol)
+                .any(|(_, pair)| pair.aggregator.lock().unwrap().aligns_by_time())
+        {
+            // aggregators without time windows ignore them, but pairs aligned by time need them
+            return Some(
+                batch
+                    .timestamps
+                    .map_or_else(|| vec![self.now(); n], <[u64]>::to_vec),
+            );
+        }
+        resolved
+    }
+
+    /// Adds `batch` with resolved `timestamps` to `agg` of `entry`, locked by the caller,
+    /// and to pairs of the symbol; `seq` is the sequence number of its WAL record, if any.
+    fn apply_locked(
+        &self,
+        entry: &SymbolEntry,
+        agg: &mut SymbolAggregator,
+        batch: &Batch<'_>,
+        timestamps: Option<&[u64]>,
+        seq: Option<u64>,
+    ) {
+        let index = agg.index();
+        agg.add_batch_with(batch.values, timestamps, batch.weights);
+        let ingested = agg.index() - index;
+        self.ingested.fetch_add(ingested, Ordering::Relaxed);
+        self.rejected
+            .fetch_add(batch.values.len() as u64 - ingested, Ordering::Relaxed);
+        if let Some(seq) = seq {
+            entry.wal_seq.store(seq, Ordering::Relaxed);
+        }
+        // still holding the aggregator lock, so a snapshot written after the symbol has its
+        // batches in pairs as well, and pairs skip them on replay
+        for (side, pair) in self.pairs_of(batch.symbol) {
+            let mut pair = pair.aggregator.lock().unwrap();
+            pair.push(side, batch.values, timestamps, seq);
+        }
+
+        let usage = agg.memory_usage();
+        let accounted = entry.memory.swap(usage, Ordering::Relaxed);
+        if usage >= accounted {
+            self.used.fetch_add(usage - accounted, Ordering::Relaxed);
+        } else {
+            self.used.fetch_sub(accounted - usage, Ordering::Relaxed);
+        }
+    }
+
+    fn touch(&self, entry: &SymbolEntry) {
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed);
+        entry.last_used.store(tick, Ordering::Relaxed);
+    }
+
+    /// Ensures `needed` more bytes fit into the budget, evicting symbols other than `keep`
+    /// if allowed.
+    fn make_room(&self, keep: &[&str], needed: usize) -> Result<(), Error> {
+        let Some(budget) = self.budget else {
+            return Ok(());
+        };
+
+        while self.memory_used() + needed > budget {
+            if self.policy == MemoryPolicy::Reject || !self.evict_lru(keep) {
+                let symbols = keep.join(", ");
+                tracing::warn!(
+                    "symbols {symbols} need {needed}B, but {}B of {budget}B is used",
+                    self.memory_used()
+                );
+                return Err(Error::MemoryBudgetExceeded(symbols));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes least recently used symbol other than `keep`, and not shared by an atomic batch.
+    ///
+    /// Returns `false` if there was nothing to evict, or the symbol was used meanwhile.
+    fn evict_lru(&self, keep: &[&str]) -> bool {
+        let lru = self
+            .symbols
+            .iter()
+            .filter(|entry| {
+                !keep.contains(&entry.key().as_str()) && Arc::strong_count(entry.value()) == 1
+            })
+            .map(|entry| {
+                let last_used = entry.last_used.load(Ordering::Relaxed);
+                (last_used, entry.key().clone())
+            })
+            .min();
+        let Some((last_used, lru_symbol)) = lru else {
+            return false;
+        };
+
+        // the symbol might have been used meanwhile, or got shared by an atomic batch
+        let removed = self.symbols.remove_if(&lru_symbol, |_, entry| {
+            entry.last_used.load(Ordering::Relaxed) == last_used && Arc::strong_count(entry) == 1
+        });
+        let Some((_, entry)) = removed else {
+            return false;
+        };
+        let memory = entry.memory.load(Ordering::Relaxed);
+        self.used.fetch_sub(memory, Ordering::Relaxed);
+        tracing::info!("evicted least recently used symbol {lru_symbol}, freed {memory}B");
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::symbol_aggregator::StatsOptions;
+    use crate::windows::Span;
+    use std::sync::LazyLock;
+    use std::time::Duration;
+
+    fn windows() -> Windows {
+        Windows::geometric(2, 10)
+    }
+
+    static WINDOWS: LazyLock<Windows> = LazyLock::new(windows);
+
+    /// memory used by a symbol with `100` equal values
+    fn symbol_usage() -> usize {
+        let mut agg = SymbolAggregator::new(windows());
+        agg.add_batch(&[1.0; 100]);
+        agg.memory_usage()
+    }
+
+    #[test]
+    fn test_memory_accounting() {
+        let registry = SymbolRegistry::new(None, MemoryPolicy::EvictLru);
+        registry
+            .add_batch("A", &[1.0, 2.0], None, None, &windows())
+            .unwrap();
+        registry
+            .add_batch("B", &[1.0, 2.0], None, None, &windows())
+            .unwrap();
+
+        let a = registry
+            .get("A")
+            .unwrap()
+            .aggregator
+            .lock()
+            .unwrap()
+            .memory_usage();
+        let b = registry
+            .get("B")
+            .unwrap()
+            .aggregator
+            .lock()
+            .unwrap()
+            .memory_usage();
+        assert_eq!(registry.memory_used(), a + b);
+        assert_eq!(registry.len(), 2);
+    }
+
+    #[test]
+    fn test_evict_lru() {
+        let budget = 2 * symbol_usage() + symbol_usage() / 2;
+        let registry = SymbolRegistry::new(Some(budget), MemoryPolicy::EvictLru);
+        registry
+            .add_batch("A", &[1.0; 100], None, None, &windows())
+            .unwrap();
+        registry
+            .add_batch("B", &[1.0; 100], None, None, &windows())
+            .unwrap();
+        // `A` is used more recently than `B` now
+        assert!(registry.get("A").is_some());
+
+        registry
+            .add_batch("C", &[1.0; 100], None, None, &windows())
+            .unwrap();
+        assert!(registry.get("A").is_some());
+        assert!(registry.get("B").is_none());
+        assert!(registry.get("C").is_some());
+        assert_eq!(registry.memory_used(), 2 * symbol_usage());
+    }
+
+    #[test]
+    fn test_shared_symbol_not_evicted() {
+        let budget = symbol_usage() + symbol_usage() / 2;
+        let registry = SymbolRegistry::new(Some(budget), MemoryPolicy::EvictLru);
+        registry
+            .add_batch("A", &[1.0; 100], None, None, &windows())
+            .unwrap();
+
+        // as by an atomic batch in progress
+        let shared = registry.symbols.get("A").map(|entry| Arc::clone(&entry));
+        let err = registry
+            .add_batch("B", &[1.0; 100], None, None, &windows())
+            .unwrap_err();
+        assert!(matches!(err, Error::MemoryBudgetExceeded(symbol) if symbol == "B"));
+        assert!(registry.get("A").is_some());
+
+        drop(shared);
+        registry
+            .add_batch("B", &[1.0; 100], None, None, &windows())
+            .unwrap();
+        assert!(registry.get("A").is_none());
+    }
+
+    #[test]
+    fn test_reject() {
+        let budget = symbol_usage() + symbol_usage() / 2;
+        let registry = SymbolRegistry::new(Some(budget), MemoryPolicy::Reject);
+        registry
+            .add_batch("A", &[1.0; 100], None, None, &windows())
+            .unwrap();
+
+        let err = registry
+            .add_batch("B", &[1.0; 100], None, None, &windows())
+            .unwrap_err();
+        assert!(matches!(err, Error::MemoryBudgetExceeded(symbol) if symbol == "B"));
+        assert!(registry.get("A").is_some());
+        assert!(registry.get("B").is_none());
+
+        // existing symbol still accepts values which do not need more memory
+        registry
+            .add_batch("A", &[2.0; 10], None, None, &windows())
+            .unwrap();
+    }
+
+    #[test]
+    fn test_add_batches_all_or_none() {
+        let budget = 2 * symbol_usage() + symbol_usage() / 2;
+        let registry = SymbolRegistry::new(Some(budget), MemoryPolicy::EvictLru);
+        registry
+            .add_batch("A", &[1.0; 100], None, None, &windows())
+            .unwrap();
+        let batch = |symbol| Batch {
+            symbol,
+            values: &[1.0; 100],
+            timestamps: None,
+            weights: None,
+        };
+
+        // `A` is evicted to make room for both
+        registry
+            .add_batches(&[batch("C"), batch("B")], |_| &WINDOWS)
+            .unwrap();
+        assert!(registry.get("A").is_none());
+        assert_eq!(registry.len(), 2);
+
+        // no single symbol to evict makes room for three
+        let err = registry
+            .add_batches(&[batch("A"), batch("B"), batch("C")], |_| &WINDOWS)
+            .unwrap_err();
+        assert!(matches!(err, Error::MemoryBudgetExceeded(symbols) if symbols == "A, B, C"));
+        assert!(registry.get("A").is_none());
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.memory_used(), 2 * symbol_usage());
+    }
+
+    #[test]
+    fn test_clock_stamps_batches() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let registry = SymbolRegistry::new(None, MemoryPolicy::EvictLru).with_clock(clock.clone());
+        let windows = Windows::new(["10", "1s"].map(|s| s.parse().unwrap())).unwrap();
+
+        registry
+            .add_batch("A", &[1.0, 2.0], None, None, &windows)
+            .unwrap();
+        clock.advance(Duration::from_millis(500));
+        registry
+            .add_batch("A", &[3.0], Some(&[1_400]), None, &windows)
+            .unwrap();
+
+        let entry = registry.get("A").unwrap();
+        let mut agg = entry.aggregator.lock().unwrap();
+        let options = StatsOptions {
+            now: registry.now(),
+            ..StatsOptions::default()
+        };
+        assert_eq!(agg.get_stats_with(2, &options).unwrap().avg, 2.0);
+        clock.advance(Duration::from_millis(500));
+        // `(1s, 2s]` has only the explicitly timestamped value
+        let options = StatsOptions {
+            now: registry.now(),
+            ..StatsOptions::default()
+        };
+        assert_eq!(agg.get_stats_with(2, &options).unwrap().avg, 3.0);
+    }
+
+    #[test]
+    fn test_clock_stamps_ewma() {
+        let clock = Arc::new(ManualClock::new(1_000));
+        let options = AggregatorOptions {
+            ewma_half_lives: vec![Span::Time(1000)],
+            ..AggregatorOptions::default()
+        };
+        let registry = SymbolRegistry::new(None, MemoryPolicy::EvictLru)
+            .with_clock(clock.clone())
+            .with_options(options);
+
+        // no time windows, still values are stamped for the time half-life
+        registry
+            .add_batch("A", &[1.0], None, None, &windows())
+            .unwrap();
+        clock.advance(Duration::from_secs(1));
+        registry
+            .add_batch("A", &[3.0], None, None, &windows())
+            .unwrap();
+
+        let entry = registry.get("A").unwrap();
+        let mut agg = entry.aggregator.lock().unwrap();
+        assert_eq!(agg.now(), Some(2_000));
+        // weights `0.5` and `1`
+        let ewma = &agg.get_stats(1).unwrap().ewma[0];
+        assert_eq!(ewma.half_life, "1s");
+        assert!((ewma.avg - 3.5 / 1.5).abs() < 1e-15);
+    }
+}
@@ -0,0 +1,211 @@
+//! Returns between consecutive values, e.g. prices, see [`ReturnKind`] and [`LevelReturns`].
+use crate::shared_monotonic_queue::{MaxCmp, MinCmp, SharedMonotonicQueue};
+use crate::snapshot::{Decoder, Encoder, Persist, SnapshotError};
+use crate::variance::ShiftedSums;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+/// How return of a value is computed from the previous one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReturnKind {
+    /// `ln(val / prev)`, for positive values only
+    Log,
+    /// `val / prev - 1`
+    Simple,
+}
+
+impl ReturnKind {
+    /// Return of `val` after `prev`, or `None` if it is not defined,
+    /// e.g. log return of a non-positive value, or its square overflows.
+    pub fn of(self, prev: f64, val: f64) -> Option<f64> {
+        let ret = match self {
+            Self::Log if prev > 0.0 && val > 0.0 => (val / prev).ln(),
+            Self::Log => return None,
+            Self::Simple => val / prev - 1.0,
+        };
+        (ret * ret).is_finite().then_some(ret)
+    }
+}
+
+impl Persist for Option<ReturnKind> {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.u8(match self {
+            None => 0,
+            Some(ReturnKind::Log) => 1,
+            Some(ReturnKind::Simple) => 2,
+        })
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        match dec.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(ReturnKind::Log)),
+            2 => Ok(Some(ReturnKind::Simple)),
+            kind => Err(SnapshotError::Corrupted(format!(
+                "unknown return kind {kind}"
+            ))),
+        }
+    }
+}
+
+/// Returns of values of the aggregator ring, shared by all count levels, just like values.
+///
+/// Level of `n` values has `n - 1` returns, so monotonic queues have windows of `n - 1`
+/// returns, indexed by indexes of their values.
+pub struct Returns {
+    pub kind: ReturnKind,
+    /// ring parallel to values: return of each value after the previous one; `NaN` if undefined
+    pub ring: Vec<f64>,
+    pub minq: SharedMonotonicQueue<MinCmp>,
+    pub maxq: SharedMonotonicQueue<MaxCmp>,
+    /// smallest indexes of `minq` and `maxq` entries evicted by pushes since the last eviction
+    minq_evicted_idx: Option<usize>,
+    maxq_evicted_idx: Option<usize>,
+}
+
+impl Returns {
+    /// Creates returns for count levels of given `sizes`, sorted ascending.
+    pub fn new(kind: ReturnKind, sizes: &[u64]) -> Self {
+        let sizes = sizes.iter().map(|size| size - 1);
+        Self {
+            kind,
+            ring: Vec::new(),
+            minq: SharedMonotonicQueue::new(sizes.clone()),
+            maxq: SharedMonotonicQueue::new(sizes),
+            minq_evicted_idx: None,
+            maxq_evicted_idx: None,
+        }
+    }
+
+    /// Pushes return of value at absolute `index` to monotonic queues.
+    pub fn push(&mut self, index: u64, ret: f64) {
+        self.minq.push(index, ret, &mut self.minq_evicted_idx);
+        self.maxq.push(index, ret, &mut self.maxq_evicted_idx);
+    }
+
+    /// Evicts too old returns from monotonic queues, once all values of a batch are pushed.
+    pub fn evict(&mut self, current_index: u64) {
+        self.minq.evict(current_index, self.minq_evicted_idx.take());
+        self.maxq.evict(current_index, self.maxq_evicted_idx.take());
+    }
+
+    /// Approximate number of bytes allocated by the ring and monotonic queues.
+    pub fn memory_usage(&self) -> usize {
+        self.ring.capacity() * size_of::<f64>()
+            + self.minq.memory_usage()
+            + self.maxq.memory_usage()
+    }
+}
+
+impl Persist for Returns {
+    /// Kind is not stored, as it comes from options.
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.f64_slice(&self.ring)?;
+        // evictions pending from pushes are applied at the end of every batch
+        self.minq.encode(enc)?;
+        self.maxq.encode(enc)
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            // set by the aggregator from its options
+            kind: ReturnKind::Log,
+            ring: dec.f64_vec()?,
+            minq: SharedMonotonicQueue::decode(dec)?,
+            maxq: SharedMonotonicQueue::decode(dec)?,
+            minq_evicted_idx: None,
+            maxq_evicted_idx: None,
+        })
+    }
+}
+
+/// Sums of returns of a count level, for their `avg` and `var`.
+///
+/// Level of `n` values has returns of all of them but the oldest one, since return
+/// of the oldest value depends on a value outside of the level. Undefined returns are
+/// left out, so there may be even fewer of them.
+#[derive(Clone, Default)]
+pub struct LevelReturns {
+    /// number of returns summed
+    pub count: usize,
+    pub sums: ShiftedSums,
+}
+
+impl LevelReturns {
+    pub fn push(&mut self, ret: f64) {
+        self.sums.push(ret, self.count);
+        self.count += 1;
+    }
+
+    pub fn evict(&mut self, ret: f64) {
+        self.sums.evict(ret);
+        self.count -= 1;
+    }
+
+    /// Stats of returns, with `volatility` and `sharpe` annualized by `periods_per_year`.
+    ///
+    /// `min` and `max` come from monotonic queues of the aggregator.
+    pub fn stats(&self, min: f64, max: f64, periods_per_year: f64) -> ReturnStats {
+        let avg = self.sums.avg(self.count);
+        let var = self.sums.var(self.count);
+        let mean_sq = var + avg * avg;
+        ReturnStats {
+            count: self.count,
+            avg,
+            var,
+            min,
+            max,
+            volatility: (mean_sq * periods_per_year).sqrt(),
+            sharpe: avg / var.sqrt() * periods_per_year.sqrt(),
+        }
+    }
+}
+
+impl Persist for LevelReturns {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.usize(self.count)?;
+        self.sums.encode(enc)
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            count: dec.usize()?,
+            sums: ShiftedSums::decode(dec)?,
+        })
+    }
+}
+
+/// Stats of returns of a window, as returned by `/stats/` with `returns=true`.
+///
+/// Without any returns, all but `count` are `null`; so is `sharpe` of constant returns.
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ReturnStats {
+    pub count: usize,
+    pub avg: f64,
+    pub var: f64,
+    pub min: f64,
+    pub max: f64,
+    /// realized volatility: root mean square of returns, annualized
+    pub volatility: f64,
+    /// `avg / std` of returns, annualized, without risk-free rate
+    pub sharpe: f64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_return_kinds() {
+        assert_eq!(
+            ReturnKind::Simple.of(100.0, 110.0),
+            Some(0.10000000000000009)
+        );
+        assert_eq!(ReturnKind::Log.of(1.0, std::f64::consts::E), Some(1.0));
+        assert_eq!(ReturnKind::Log.of(-1.0, 1.0), None);
+        assert_eq!(ReturnKind::Simple.of(0.0, 1.0), None);
+        assert_eq!(ReturnKind::Simple.of(1e-300, 1e300), None);
+    }
+}
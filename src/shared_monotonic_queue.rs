@@ -1,4 +1,44 @@
+use crate::snapshot::{Decoder, Encoder, Persist, SnapshotError};
 use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of levels with own counters of [`BEST_LOOKUPS`]; higher levels share the last one.
+pub const COUNTED_LEVELS: usize = 64;
+
+/// Lookups of best values of all queues, per level.
+pub static BEST_LOOKUPS: BestLookups = BestLookups::new();
+
+/// Counters of lookups by `best_or_refresh` served by cached best indexes, or refreshing them.
+/// The top level needs no cache, so its lookups always hit.
+pub struct BestLookups {
+    hits: [AtomicU64; COUNTED_LEVELS],
+    misses: [AtomicU64; COUNTED_LEVELS],
+}
+
+impl BestLookups {
+    const fn new() -> Self {
+        Self {
+            hits: [const { AtomicU64::new(0) }; COUNTED_LEVELS],
+            misses: [const { AtomicU64::new(0) }; COUNTED_LEVELS],
+        }
+    }
+
+    fn record(&self, level: usize, hit: bool) {
+        let counters = if hit { &self.hits } else { &self.misses };
+        counters[level.min(COUNTED_LEVELS - 1)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Hits and misses of levels looked up so far, by level.
+    pub fn counts(&self) -> impl Iterator<Item = (usize, u64, u64)> + '_ {
+        (0..COUNTED_LEVELS)
+            .map(|level| {
+                let hits = self.hits[level].load(Ordering::Relaxed);
+                (level, hits, self.misses[level].load(Ordering::Relaxed))
+            })
+            .filter(|&(_, hits, misses)| hits + misses > 0)
+    }
+}
 
 /// Strictly monotonic ring of values ordered by given `Comparator`.
 ///
@@ -20,9 +60,9 @@ use std::collections::VecDeque;
 ///
 /// Logical index is not reset. `u64::MAX` is big enough for the server to operate
 /// for few hundred years, even under heavy load, until it will overflow.
-pub struct SharedMonotonicQueue<C: Comparator, const LEVELS: usize, const RADIX: usize> {
+pub struct SharedMonotonicQueue<C: Comparator> {
     pub entries: VecDeque<(u64, f64)>,
-    pub views: [LevelView; LEVELS], // we do not need last view, but LEVELS-1 would not compile
+    pub views: Vec<LevelView>, // we do not need last view, but keep it for uniform indexing
     _cmp: std::marker::PhantomData<C>,
 }
 
@@ -72,21 +112,28 @@ pub struct LevelView {
     pub best_idx: Option<usize>,
 }
 
-impl<C: Comparator, const LEVELS: usize, const RADIX: usize>
-    SharedMonotonicQueue<C, LEVELS, RADIX>
-{
-    pub fn new(window_sizes: [u64; LEVELS]) -> Self {
+impl<C: Comparator> SharedMonotonicQueue<C> {
+    /// Creates queue for given window sizes, sorted ascending; the last one is the top level.
+    pub fn new(window_sizes: impl IntoIterator<Item = u64>) -> Self {
         Self {
             entries: VecDeque::new(),
-            views: std::array::from_fn(|i| LevelView {
-                id: i,
-                window_size: window_sizes[i],
-                best_idx: None,
-            }),
+            views: window_sizes
+                .into_iter()
+                .enumerate()
+                .map(|(id, window_size)| LevelView {
+                    id,
+                    window_size,
+                    best_idx: None,
+                })
+                .collect(),
             _cmp: std::marker::PhantomData,
         }
     }
 
+    fn top_level(&self) -> usize {
+        self.views.len() - 1
+    }
+
     /// Pushes single value to the `deque` preserving strict monotonic invariant.
     ///
     /// Evicts (from the back) all values worse than given one.
@@ -126,26 +173,29 @@ impl<C: Comparator, const LEVELS: usize, const RADIX: usize>
         // first invalidate level best indexes cache if needed
         if let Some(min_evicted_idx) = min_evicted_idx {
             tracing::debug!(
-                "{}, validating push-evicted best indexes before {min_evicted_idx}",
+                "{}, validating push-evicted best indexes after {min_evicted_idx}",
                 C::name()
             );
-            // we do not need to update last LEVEL, because it is full queue
-            for view in self.views.iter_mut().take(LEVELS - 1) {
-                if let Some(idx) = view.best_idx {
-                    if idx < min_evicted_idx {
-                        tracing::debug!(
-                            "{}, invalidating push-evicted best index:{idx} level {}",
-                            C::name(),
-                            view.id,
-                        );
-                        view.best_idx = None;
-                    }
+            // we do not need to update last level, because it is full queue
+            let top_level = self.top_level();
+            for view in self.views.iter_mut().take(top_level) {
+                // entries before the first evicted one are intact, and the one pushed in its place
+                // is better than all evicted ones, so only best indexes after it are stale
+                if let Some(idx) = view.best_idx
+                    && idx > min_evicted_idx
+                {
+                    tracing::debug!(
+                        "{}, invalidating push-evicted best index:{idx} level {}",
+                        C::name(),
+                        view.id,
+                    );
+                    view.best_idx = None;
                 }
             }
         }
 
         // now evict to old values
-        let max_window = RADIX.pow(LEVELS as u32) as u64;
+        let max_window = self.views[self.top_level()].window_size;
         let oldest_allowed = current_index.saturating_sub(max_window);
 
         tracing::trace!(
@@ -169,21 +219,22 @@ impl<C: Comparator, const LEVELS: usize, const RADIX: usize>
                 C::name(),
                 self.debug_best_indexes(),
             );
-            // we do not need to update last LEVEL, because it is full queue
-            for view in self.views.iter_mut().take(LEVELS - 1) {
+            // we do not need to update last level, because it is full queue
+            let top_level = self.top_level();
+            for view in self.views.iter_mut().take(top_level) {
                 let min_index = current_index.saturating_sub(view.window_size);
                 if let Some(ref mut idx) = view.best_idx {
                     *idx -= front_evicted;
-                    if let Some((index, _)) = self.entries.get(*idx) {
-                        if *index < min_index {
-                            // invalidate best index as too old; will be set by `best_or_refresh`
-                            tracing::debug!(
-                                "{}, evicted: invalidating too old best index:{idx} level {}",
-                                C::name(),
-                                view.id,
-                            );
-                            view.best_idx = None;
-                        }
+                    if let Some((index, _)) = self.entries.get(*idx)
+                        && *index < min_index
+                    {
+                        // invalidate best index as too old; will be set by `best_or_refresh`
+                        tracing::debug!(
+                            "{}, evicted: invalidating too old best index:{idx} level {}",
+                            C::name(),
+                            view.id,
+                        );
+                        view.best_idx = None;
                     }
                 }
             }
@@ -195,7 +246,8 @@ impl<C: Comparator, const LEVELS: usize, const RADIX: usize>
     /// Last level is special and has O(1) cost.
     /// Other levels are O(1) or O(log(n)) if best index was invalidated.
     pub fn best_or_refresh(&mut self, level: usize, current_index: u64) -> Option<f64> {
-        if level == LEVELS - 1 {
+        if level == self.top_level() {
+            BEST_LOOKUPS.record(level, true);
             let front = self.entries.front();
             tracing::debug!(
                 "{}, best: front: {:?} of {:?}",
@@ -214,26 +266,22 @@ impl<C: Comparator, const LEVELS: usize, const RADIX: usize>
             C::name(),
             view.best_idx,
         );
-        if let Some(idx) = view.best_idx {
-            if let Some((index, value)) = self.entries.get(idx) {
-                if *index >= min_index {
-                    tracing::debug!(
-                        "{}, best: cached index:{idx} level {level}: {:?}",
-                        C::name(),
-                        self.entries
-                    );
-                    return Some(*value);
-                }
-            }
-        }
-
-        match self
-            .entries
-            .binary_search_by_key(&min_index, |&(idx, _)| idx)
+        if let Some(idx) = view.best_idx
+            && let Some((index, value)) = self.entries.get(idx)
+            && *index >= min_index
         {
-            Ok(idx) => view.best_idx = Some(idx),
-            Err(idx) => view.best_idx = Some(idx),
+            tracing::debug!(
+                "{}, best: cached index:{idx} level {level}: {:?}",
+                C::name(),
+                self.entries
+            );
+            BEST_LOOKUPS.record(level, true);
+            return Some(*value);
         }
+        BEST_LOOKUPS.record(level, false);
+
+        // first entry inside the window; indexes may repeat when they are timestamps
+        view.best_idx = Some(self.entries.partition_point(|&(idx, _)| idx < min_index));
 
         tracing::debug!(
             "{}, best: index:{} level {level}: {:?}",
@@ -246,8 +294,54 @@ impl<C: Comparator, const LEVELS: usize, const RADIX: usize>
             .and_then(|i| self.entries.get(i).map(|&(_, v)| v))
     }
 
+    /// Approximate number of bytes allocated by entries and views.
+    pub fn memory_usage(&self) -> usize {
+        self.entries.capacity() * size_of::<(u64, f64)>()
+            + self.views.capacity() * size_of::<LevelView>()
+    }
+
     #[allow(dead_code)]
-    pub fn debug_best_indexes(&self) -> [Option<usize>; LEVELS] {
-        std::array::from_fn(|i| self.views[i].best_idx)
+    pub fn debug_best_indexes(&self) -> Vec<Option<usize>> {
+        self.views.iter().map(|view| view.best_idx).collect()
+    }
+}
+
+impl<C: Comparator> Persist for SharedMonotonicQueue<C> {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.usize(self.entries.len())?;
+        for &(index, value) in &self.entries {
+            enc.u64(index)?;
+            enc.f64(value)?;
+        }
+        enc.usize(self.views.len())?;
+        for view in &self.views {
+            enc.u64(view.window_size)?;
+            enc.opt_usize(view.best_idx)?;
+        }
+        Ok(())
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        let len = dec.usize()?;
+        let mut entries = VecDeque::new();
+        for _ in 0..len {
+            entries.push_back((dec.u64()?, dec.f64()?));
+        }
+        let len = dec.usize()?;
+        let mut views = Vec::new();
+        for id in 0..len {
+            let window_size = dec.u64()?;
+            let best_idx = dec.opt_usize()?;
+            views.push(LevelView {
+                id,
+                window_size,
+                best_idx,
+            });
+        }
+        Ok(Self {
+            entries,
+            views,
+            _cmp: std::marker::PhantomData,
+        })
     }
 }
@@ -0,0 +1,715 @@
+//! Compact binary snapshot of all aggregators, so stats survive restarts.
+//!
+//! File layout, all numbers little endian:
+//! ```txt
+//! | magic `FSTSNAP\0` | version: u32 | (1u8, name, wal seq: u64, aggregator)... |
+//! | (2u8, symbol, benchmark, pair aggregator)... | 0u8 | crc32: u32 |
+//! ```
+//! Symbols and then pairs are streamed one by one, each prefixed by a marker byte.
+//! CRC covers everything before it. Each component encodes its own state via [`Persist`], storing floats by their
+//! bits, so restored stats are bit-for-bit identical.
+//!
+//! Snapshot is written to a temporary file first and renamed, so a crash during writing
+//! never leaves a truncated snapshot behind.
+//!
+//! With WAL enabled, each symbol stores sequence number of the last WAL record it includes,
+//! so replay skips records already in the snapshot. Pairs are written after all symbols,
+//! so they include at least the records of their symbols, and skip replayed ones on their own. Once a snapshot is saved, WAL segments
+//! written before it started are removed.
+use crate::pairs::{PairAggregator, PairSpec};
+use crate::registry::SymbolRegistry;
+use crate::symbol_aggregator::SymbolAggregator;
+use crate::windows::Windows;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+pub const MAGIC: &[u8; 8] = b"FSTSNAP\0";
+pub const VERSION: u32 = 15;
+
+/// Floats are converted in chunks, to not hash and write them one by one.
+const F64_CHUNK: usize = 4096;
+
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("Not a snapshot file")]
+    BadMagic,
+
+    #[error("Unsupported snapshot version {0}, expected {VERSION}")]
+    UnsupportedVersion(u32),
+
+    #[error("Snapshot checksum mismatch")]
+    ChecksumMismatch,
+
+    #[error("Corrupted snapshot: {0}")]
+    Corrupted(String),
+}
+
+/// State which can be written to and restored from a snapshot.
+pub trait Persist: Sized {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()>;
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError>;
+}
+
+/// Writes primitives while computing checksum of everything written.
+pub struct Encoder<W: Write> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.hasher.update(bytes);
+        self.inner.write_all(bytes)
+    }
+
+    pub fn u8(&mut self, value: u8) -> io::Result<()> {
+        self.bytes(&[value])
+    }
+
+    pub fn u32(&mut self, value: u32) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, value: u64) -> io::Result<()> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    pub fn usize(&mut self, value: usize) -> io::Result<()> {
+        self.u64(value as u64)
+    }
+
+    pub fn f64(&mut self, value: f64) -> io::Result<()> {
+        self.u64(value.to_bits())
+    }
+
+    /// `None` is stored as `u64::MAX`, which is never a valid index.
+    pub fn opt_usize(&mut self, value: Option<usize>) -> io::Result<()> {
+        self.u64(value.map_or(u64::MAX, |v| v as u64))
+    }
+
+    pub fn str(&mut self, value: &str) -> io::Result<()> {
+        self.usize(value.len())?;
+        self.bytes(value.as_bytes())
+    }
+
+    /// Writes length followed by all values.
+    pub fn f64_slice(&mut self, values: &[f64]) -> io::Result<()> {
+        self.usize(values.len())?;
+        let mut bytes = Vec::with_capacity(F64_CHUNK * 8);
+        for chunk in values.chunks(F64_CHUNK) {
+            bytes.clear();
+            bytes.extend(chunk.iter().flat_map(|v| v.to_le_bytes()));
+            self.bytes(&bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the checksum and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let crc = self.hasher.clone().finalize();
+        self.inner.write_all(&crc.to_le_bytes())?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads primitives written by [`Encoder`], verifying the checksum in [`Decoder::finish`].
+pub struct Decoder<R: Read> {
+    inner: R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: crc32fast::Hasher::new(),
+        }
+    }
+
+    pub fn bytes(&mut self, buf: &mut [u8]) -> Result<(), SnapshotError> {
+        self.inner.read_exact(buf)?;
+        self.hasher.update(buf);
+        Ok(())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        let mut buf = [0; 1];
+        self.bytes(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        let mut buf = [0; 4];
+        self.bytes(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        let mut buf = [0; 8];
+        self.bytes(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, SnapshotError> {
+        let value = self.u64()?;
+        to_usize(value)
+    }
+
+    pub fn f64(&mut self) -> Result<f64, SnapshotError> {
+        Ok(f64::from_bits(self.u64()?))
+    }
+
+    pub fn opt_usize(&mut self) -> Result<Option<usize>, SnapshotError> {
+        match self.u64()? {
+            u64::MAX => Ok(None),
+            value => to_usize(value).map(Some),
+        }
+    }
+
+    pub fn str(&mut self) -> Result<String, SnapshotError> {
+        let len = self.usize()?;
+        let mut buf = Vec::new();
+        self.inner.by_ref().take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() != len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.hasher.update(&buf);
+        String::from_utf8(buf).map_err(|e| SnapshotError::Corrupted(e.to_string()))
+    }
+
+    /// Reads values written by [`Encoder::f64_slice`].
+    ///
+    /// Memory is not reserved up front from the stored length, as it might be corrupted.
+    pub fn f64_vec(&mut self) -> Result<Vec<f64>, SnapshotError> {
+        let len = self.usize()?;
+        let mut values = Vec::new();
+        let mut bytes = vec![0; F64_CHUNK * 8];
+        while values.len() < len {
+            let n = (len - values.len()).min(F64_CHUNK);
+            self.bytes(&mut bytes[..n * 8])?;
+            values.extend(
+                bytes[..n * 8]
+                    .chunks_exact(8)
+                    .map(|b| f64::from_le_bytes(b.try_into().expect("chunk of 8 bytes"))),
+            );
+        }
+        Ok(values)
+    }
+
+    /// Verifies the checksum of everything read so far.
+    pub fn finish(mut self) -> Result<(), SnapshotError> {
+        let expected = self.hasher.clone().finalize();
+        let mut buf = [0; 4];
+        self.inner.read_exact(&mut buf)?;
+        if u32::from_le_bytes(buf) != expected {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+        Ok(())
+    }
+}
+
+fn to_usize(value: u64) -> Result<usize, SnapshotError> {
+    usize::try_from(value).map_err(|_| SnapshotError::Corrupted(format!("{value} is too big")))
+}
+
+/// Content of a snapshot.
+pub struct Snapshot {
+    /// symbols with sequence numbers of their last WAL records
+    pub symbols: Vec<(String, u64, SymbolAggregator)>,
+    pub pairs: Vec<(PairSpec, PairAggregator)>,
+}
+
+/// Writes all symbols and pairs of the `registry` to `writer`.
+///
+/// Each symbol or pair is locked only while it is written, so ingestion of others continues.
+/// Returns number of written symbols.
+pub fn write_to<W: Write>(registry: &SymbolRegistry, writer: W) -> Result<usize, SnapshotError> {
+    let mut enc = Encoder::new(writer);
+    enc.bytes(MAGIC)?;
+    enc.u32(VERSION)?;
+
+    // symbols might be evicted meanwhile, so skip the missing ones
+    let mut count = 0;
+    for symbol in registry.symbols() {
+        let Some(entry) = registry.peek(&symbol) else {
+            continue;
+        };
+        let agg = entry.aggregator.lock().unwrap();
+        enc.u8(1)?;
+        enc.str(&symbol)?;
+        enc.u64(entry.wal_seq())?;
+        agg.encode(&mut enc)?;
+        count += 1;
+    }
+    for pair in registry.pairs() {
+        let agg = pair.aggregator.lock().unwrap();
+        enc.u8(2)?;
+        enc.str(&pair.spec.symbol)?;
+        enc.str(&pair.spec.benchmark)?;
+        agg.encode(&mut enc)?;
+    }
+    enc.u8(0)?;
+    enc.finish()?;
+    Ok(count)
+}
+
+/// Reads symbols and pairs written by [`write_to`].
+pub fn read_from<R: Read>(reader: R) -> Result<Snapshot, SnapshotError> {
+    let mut dec = Decoder::new(reader);
+    let mut magic = [0; 8];
+    dec.bytes(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = dec.u32()?;
+    if version != VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    let mut symbols = Vec::new();
+    let mut pairs = Vec::new();
+    loop {
+        match dec.u8()? {
+            0 => break,
+            1 => {
+                let symbol = dec.str()?;
+                let wal_seq = dec.u64()?;
+                let agg = SymbolAggregator::decode(&mut dec)?;
+                symbols.push((symbol, wal_seq, agg));
+            }
+            2 => {
+                let spec = PairSpec {
+                    symbol: dec.str()?,
+                    benchmark: dec.str()?,
+                };
+                pairs.push((spec, PairAggregator::decode(&mut dec)?));
+            }
+            marker => return Err(SnapshotError::Corrupted(format!("unknown marker {marker}"))),
+        }
+    }
+    dec.finish()?;
+    Ok(Snapshot { symbols, pairs })
+}
+
+/// Atomically replaces snapshot at `path` with current state of the `registry`,
+/// then removes WAL segments it covers.
+///
+/// Returns number of written symbols.
+pub fn save(registry: &SymbolRegistry, path: &Path) -> Result<usize, SnapshotError> {
+    // every record before the checkpoint is applied before its symbol can be written
+    let checkpoint = registry.wal().map(|wal| wal.rotate()).transpose()?;
+
+    let tmp_path = tmp_path(path);
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    let count = write_to(registry, &mut writer)?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+
+    if let (Some(wal), Some(checkpoint)) = (registry.wal(), checkpoint) {
+        let removed = wal.remove_segments_before(checkpoint)?;
+        tracing::debug!("removed {removed} WAL segments covered by the snapshot");
+    }
+    Ok(count)
+}
+
+/// Loads snapshot from `path` into the `registry`, if the snapshot exists and is of this version.
+///
+/// Symbols whose windows, as given by `windows_for`, or options of aggregators changed start over,
+/// just like pairs which are no longer registered, or changed alignment or windows.
+/// Returns number of restored symbols.
+pub fn restore<'w>(
+    registry: &SymbolRegistry,
+    path: &Path,
+    windows_for: impl Fn(&str) -> &'w Windows,
+) -> Result<usize, SnapshotError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    let snapshot = match read_from(BufReader::new(file)) {
+        // written by another version of the service, so symbols start over from the WAL
+        Err(err @ SnapshotError::UnsupportedVersion(_)) => {
+            tracing::warn!("skipping snapshot {path:?}: {err}");
+            return Ok(0);
+        }
+        snapshot => snapshot?,
+    };
+    let mut count = 0;
+    let mut changed = 0;
+    for (symbol, wal_seq, agg) in snapshot.symbols {
+        let windows = windows_for(&symbol);
+        match registry.restore_symbol(symbol, agg, wal_seq, windows) {
+            true => count += 1,
+            false => changed += 1,
+        }
+    }
+    if changed > 0 {
+        tracing::warn!(
+            "{changed} symbols of the snapshot have other windows or options, start over"
+        );
+    }
+    for (spec, agg) in snapshot.pairs {
+        if !registry.restore_pair(&spec, agg) {
+            tracing::warn!("pair {spec} of the snapshot is not registered with the same settings");
+        }
+    }
+    Ok(count)
+}
+
+/// Saves snapshot every `interval`, forever.
+pub async fn run_periodic(registry: &'static SymbolRegistry, path: PathBuf, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // first tick completes immediately, and there is nothing new to save right after start
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let path = path.clone();
+        match tokio::task::spawn_blocking(move || save(registry, &path)).await {
+            Ok(Ok(count)) => tracing::info!("snapshot of {count} symbols saved"),
+            Ok(Err(err)) => tracing::error!("unable to save snapshot: {err}"),
+            Err(err) => tracing::error!("snapshot task failed: {err}"),
+        }
+    }
+}
+
+/// Path the file is written to before it atomically replaces the one at `path`.
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tmp.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::candles::Candle;
+    use crate::pairs::Alignment;
+    use crate::registry::MemoryPolicy;
+    use crate::returns::ReturnKind;
+    use crate::symbol_aggregator::{AggregatorOptions, StatsOptions};
+    use crate::wal::{FsyncPolicy, Wal};
+    use crate::windows::{Span, Windows};
+
+    /// Deterministic, but irregular prices.
+    fn prices(n: usize, seed: u64) -> Vec<f64> {
+        let mut state = seed;
+        (0..n)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                10_000.0 + (state >> 11) as f64 / (1u64 << 53) as f64 * 100.0
+            })
+            .collect()
+    }
+
+    /// Count and time windows.
+    fn windows() -> Windows {
+        Windows::new(["10", "100", "1000", "1s", "1h"].map(|s| s.parse().unwrap())).unwrap()
+    }
+
+    /// Empty registry of aggregators with EWMAs and returns.
+    fn empty_registry() -> SymbolRegistry {
+        let options = AggregatorOptions {
+            ewma_half_lives: vec![Span::Count(100), Span::Time(1000)],
+            returns: Some(ReturnKind::Log),
+            ..AggregatorOptions::default()
+        };
+        SymbolRegistry::new(None, MemoryPolicy::EvictLru).with_options(options)
+    }
+
+    /// Every other symbol is weighted.
+    fn registry_with(symbols: &[&str]) -> SymbolRegistry {
+        let registry = empty_registry();
+        for (i, symbol) in symbols.iter().enumerate() {
+            let values = prices(1500, i as u64);
+            let weights = (i % 2 == 1).then(|| prices(1500, 7));
+            registry
+                .add_batch(symbol, &values, None, weights.as_deref(), &windows())
+                .unwrap();
+        }
+        registry
+    }
+
+    /// Bits of stats of all levels, so even `NaN`s are compared exactly.
+    fn stats_bits(registry: &SymbolRegistry, symbol: &str) -> Vec<Vec<u64>> {
+        let entry = registry.get(symbol).unwrap();
+        let mut agg = entry.aggregator.lock().unwrap();
+        let options = StatsOptions {
+            moments: true,
+            returns: true,
+            ..StatsOptions::default()
+        };
+        (1..=agg.windows().len() as u32)
+            .map(|k| {
+                let s = agg.get_stats_with(k, &options).unwrap();
+                let (skew, kurtosis) = (s.skew.unwrap(), s.kurtosis.unwrap());
+                let ewma = s.ewma.iter().flat_map(|ewma| [ewma.avg, ewma.var]);
+                let weighted = [s.vwap, s.weighted_var, s.volume].into_iter().flatten();
+                let returns = s.returns.iter().flat_map(|r| [r.avg, r.var, r.min, r.max]);
+                [s.min, s.max, s.last, s.avg, s.var, s.median, skew, kurtosis]
+                    .into_iter()
+                    .chain(ewma)
+                    .chain(weighted)
+                    .chain(returns)
+                    .map(f64::to_bits)
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Closed and forming candles of all levels.
+    fn candles(registry: &SymbolRegistry, symbol: &str) -> Vec<(Vec<Candle>, Option<Candle>)> {
+        let entry = registry.get(symbol).unwrap();
+        let mut agg = entry.aggregator.lock().unwrap();
+        (1..=agg.windows().len() as u32)
+            .map(|k| {
+                let candles = agg.candles(k, 0).unwrap();
+                let closed = candles.closed(usize::MAX).cloned().collect();
+                (closed, candles.forming().cloned())
+            })
+            .collect()
+    }
+
+    fn assert_same_stats(a: &SymbolRegistry, b: &SymbolRegistry, symbol: &str) {
+        assert_eq!(stats_bits(a, symbol), stats_bits(b, symbol));
+        assert_eq!(candles(a, symbol), candles(b, symbol));
+    }
+
+    #[test]
+    fn test_roundtrip_is_bit_for_bit() {
+        let registry = registry_with(&["A", "B"]);
+
+        let mut bytes = Vec::new();
+        assert_eq!(write_to(&registry, &mut bytes).unwrap(), 2);
+
+        let restored = SymbolRegistry::new(None, MemoryPolicy::EvictLru);
+        for (symbol, wal_seq, agg) in read_from(bytes.as_slice()).unwrap().symbols {
+            restored.insert(symbol, agg, wal_seq);
+        }
+        assert_eq!(restored.len(), 2);
+        // restored collections are allocated exactly, without spare capacity
+        assert!(restored.memory_used() <= registry.memory_used());
+
+        // weighted stats are restored too
+        assert_eq!(
+            stats_bits(&restored, "B")[0].len(),
+            stats_bits(&restored, "A")[0].len() + 3
+        );
+
+        // same state now, and after more values wrapping the ring
+        for symbol in ["A", "B"] {
+            assert_same_stats(&registry, &restored, symbol);
+            let values = prices(700, 42);
+            // same time for both, as time EWMAs decay by the time passed since the snapshot
+            let timestamps = vec![registry.now(); values.len()];
+            let weights = (symbol == "B").then(|| vec![2.0; values.len()]);
+            let windows = windows();
+            for registry in [&registry, &restored] {
+                registry
+                    .add_batch(
+                        symbol,
+                        &values,
+                        Some(&timestamps),
+                        weights.as_deref(),
+                        &windows,
+                    )
+                    .unwrap();
+            }
+            assert_same_stats(&registry, &restored, symbol);
+        }
+    }
+
+    #[test]
+    fn test_corrupted_snapshot() {
+        let registry = registry_with(&["A"]);
+        let mut bytes = Vec::new();
+        write_to(&registry, &mut bytes).unwrap();
+
+        // somewhere in the values ring
+        let mut corrupted = bytes.clone();
+        corrupted[bytes.len() / 2] ^= 1;
+        assert!(matches!(
+            read_from(corrupted.as_slice()),
+            Err(SnapshotError::ChecksumMismatch | SnapshotError::Corrupted(_))
+        ));
+
+        let truncated = &bytes[..bytes.len() - 10];
+        assert!(matches!(read_from(truncated), Err(SnapshotError::Io(_))));
+
+        assert!(matches!(
+            read_from(&b"NOTSNAP\0\x01\0\0\0"[..]),
+            Err(SnapshotError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn test_save_and_restore_file() {
+        let path = std::env::temp_dir().join(format!("fast-stats-{}.snap", std::process::id()));
+        let registry = registry_with(&["A", "B", "C"]);
+        assert_eq!(save(&registry, &path).unwrap(), 3);
+        let windows = windows();
+
+        let restored = empty_registry();
+        assert_eq!(restore(&restored, &path, |_| &windows).unwrap(), 3);
+        assert_same_stats(&registry, &restored, "C");
+
+        // symbols with other windows or options than configured now start over
+        let other_windows = Windows::geometric(3, 10);
+        let restored = empty_registry();
+        let windows_for = |symbol: &str| match symbol {
+            "B" => &other_windows,
+            _ => &windows,
+        };
+        assert_eq!(restore(&restored, &path, windows_for).unwrap(), 2);
+        assert!(restored.peek("B").is_none());
+        let restored = SymbolRegistry::new(None, MemoryPolicy::EvictLru);
+        assert_eq!(restore(&restored, &path, |_| &windows).unwrap(), 0);
+
+        // snapshot of another version is skipped, rather than failing the startup
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+        let restored = empty_registry();
+        assert_eq!(restore(&restored, &path, |_| &windows).unwrap(), 0);
+        assert_eq!(restored.len(), 0);
+        std::fs::remove_file(&path).unwrap();
+
+        // missing snapshot is not an error, server just starts empty
+        assert_eq!(restore(&restored, &path, |_| &windows).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_wal_replayed_on_top_of_snapshot() {
+        let dir = std::env::temp_dir().join(format!("fast-stats-wal-snap-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("stats.snap");
+        let windows = windows();
+        let open_wal = |registry: &SymbolRegistry| {
+            let wal = Wal::open(&dir.join("wal"), FsyncPolicy::Never, 1 << 20, |record| {
+                registry.replay(&record, &windows).unwrap();
+            })
+            .unwrap();
+            assert!(registry.set_wal(wal).is_ok());
+        };
+
+        let registry = SymbolRegistry::new(None, MemoryPolicy::EvictLru);
+        open_wal(&registry);
+        registry
+            .add_batch("A", &prices(800, 1), None, None, &windows)
+            .unwrap();
+        save(&registry, &path).unwrap();
+        // after the snapshot, only in the WAL
+        registry
+            .add_batch("A", &prices(300, 2), None, None, &windows)
+            .unwrap();
+        registry
+            .add_batch("B", &prices(50, 3), None, None, &windows)
+            .unwrap();
+
+        let restored = SymbolRegistry::new(None, MemoryPolicy::EvictLru);
+        restore(&restored, &path, |_| &windows).unwrap();
+        open_wal(&restored);
+        assert_same_stats(&registry, &restored, "A");
+        assert_same_stats(&registry, &restored, "B");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_wal_numbering_continues_after_snapshot() {
+        let dir = std::env::temp_dir().join(format!("fast-stats-wal-seq-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("stats.snap");
+        let windows = windows();
+        let restart = || {
+            let registry = SymbolRegistry::new(None, MemoryPolicy::EvictLru);
+            restore(&registry, &path, |_| &windows).unwrap();
+            let wal = Wal::open(&dir.join("wal"), FsyncPolicy::Never, 1 << 20, |record| {
+                registry.replay(&record, &windows).unwrap();
+            })
+            .unwrap();
+            assert!(registry.set_wal(wal).is_ok());
+            registry
+        };
+
+        let registry = restart();
+        registry
+            .add_batch("A", &prices(800, 1), None, None, &windows)
+            .unwrap();
+        // only an empty WAL segment is left after it
+        save(&registry, &path).unwrap();
+        drop(registry);
+
+        let registry = restart();
+        registry
+            .add_batch("A", &prices(300, 2), None, None, &windows)
+            .unwrap();
+        let restarted = restart();
+        assert_same_stats(&registry, &restarted, "A");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pairs_restored_and_replayed() {
+        let dir = std::env::temp_dir().join(format!("fast-stats-pairs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let path = dir.join("stats.snap");
+        let windows = windows();
+        let registry_with_pair = || {
+            let pair = PairAggregator::new(Alignment::Index, &windows);
+            SymbolRegistry::new(None, MemoryPolicy::EvictLru)
+                .with_pairs([("A:B".parse().unwrap(), pair)])
+        };
+        let open_wal = |registry: &SymbolRegistry| {
+            let wal = Wal::open(&dir.join("wal"), FsyncPolicy::Never, 1 << 20, |record| {
+                registry.replay(&record, &windows).unwrap();
+            })
+            .unwrap();
+            assert!(registry.set_wal(wal).is_ok());
+        };
+        let pair_bits = |registry: &SymbolRegistry| -> Vec<u64> {
+            let pair = registry.pair("A", "B").unwrap().lock().unwrap();
+            (1..=3)
+                .flat_map(|k| {
+                    let s = pair.stats(k).unwrap();
+                    [s.count as f64, s.cov, s.corr, s.beta].map(f64::to_bits)
+                })
+                .collect()
+        };
+
+        let registry = registry_with_pair();
+        open_wal(&registry);
+        for (symbol, seed) in [("A", 1), ("B", 2), ("A", 3)] {
+            registry
+                .add_batch(symbol, &prices(700, seed), None, None, &windows)
+                .unwrap();
+        }
+        save(&registry, &path).unwrap();
+        // after the snapshot, only in the WAL
+        registry
+            .add_batch("B", &prices(900, 4), None, None, &windows)
+            .unwrap();
+        assert_eq!(pair_bits(&registry)[8], 1000f64.to_bits());
+
+        let restored = registry_with_pair();
+        restore(&restored, &path, |_| &windows).unwrap();
+        open_wal(&restored);
+        assert_eq!(pair_bits(&registry), pair_bits(&restored));
+        let pair = restored.pair("A", "B").unwrap().lock().unwrap();
+        assert_eq!(pair.windows(), &windows);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
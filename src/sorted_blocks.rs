@@ -0,0 +1,199 @@
+//! Sorted multiset of window values for exact order statistics.
+
+/// Target number of values per block; blocks are split when they get twice as big,
+/// and merged with the next one when they get four times smaller.
+const BLOCK_SIZE: usize = 512;
+
+/// Multiset of values kept in sorted blocks, so a sliding window can keep its values sorted.
+///
+/// With `n` values and block size `B`:
+/// * insert and remove are `O(log n + B + n / B)`: binary search for the block,
+///   then shifting values within it, and occasionally splitting or merging blocks
+/// * selecting `k`-th smallest value is `O(n / B)`, walking block lengths
+///
+/// So for windows up to `10^5` values, a quantile costs walking a few hundred block lengths.
+///
+/// Values are compared by [`f64::total_cmp`]. `NaN`s are never inserted, as aggregators skip them.
+#[derive(Debug, Clone, Default)]
+pub struct SortedBlocks {
+    /// non-empty blocks, each sorted, and all sorted across blocks
+    blocks: Vec<Vec<f64>>,
+    /// last value of each block, to find blocks without touching them
+    maxes: Vec<f64>,
+    len: usize,
+}
+
+impl SortedBlocks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Index of the first block which may contain `val`, i.e. whose last value is not smaller.
+    fn block_for(&self, val: f64) -> usize {
+        let idx = self
+            .maxes
+            .partition_point(|max| max.total_cmp(&val).is_lt());
+        idx.min(self.blocks.len().saturating_sub(1))
+    }
+
+    pub fn insert(&mut self, val: f64) {
+        self.len += 1;
+        if self.blocks.is_empty() {
+            let mut block = Vec::with_capacity(2 * BLOCK_SIZE);
+            block.push(val);
+            self.blocks.push(block);
+            self.maxes.push(val);
+            return;
+        }
+
+        let idx = self.block_for(val);
+        let block = &mut self.blocks[idx];
+        let pos = block.partition_point(|v| v.total_cmp(&val).is_lt());
+        block.insert(pos, val);
+        self.maxes[idx] = block[block.len() - 1];
+
+        if block.len() >= 2 * BLOCK_SIZE {
+            let mut tail = Vec::with_capacity(2 * BLOCK_SIZE);
+            tail.extend(block.drain(BLOCK_SIZE..));
+            self.maxes.insert(idx, block[block.len() - 1]);
+            self.blocks.insert(idx + 1, tail);
+        }
+    }
+
+    /// Removes single occurrence of `val`, returning whether it was present.
+    pub fn remove(&mut self, val: f64) -> bool {
+        if self.blocks.is_empty() {
+            return false;
+        }
+
+        let idx = self.block_for(val);
+        let block = &mut self.blocks[idx];
+        let pos = block.partition_point(|v| v.total_cmp(&val).is_lt());
+        if block.get(pos).is_none_or(|v| v.total_cmp(&val).is_ne()) {
+            return false;
+        }
+        block.remove(pos);
+        self.len -= 1;
+
+        let len = block.len();
+        if len == 0 {
+            self.blocks.remove(idx);
+            self.maxes.remove(idx);
+        } else if len < BLOCK_SIZE / 4
+            && self
+                .blocks
+                .get(idx + 1)
+                .is_some_and(|next| len + next.len() < 2 * BLOCK_SIZE)
+        {
+            let next = self.blocks.remove(idx + 1);
+            self.blocks[idx].extend(next);
+            self.maxes.remove(idx);
+        } else {
+            self.maxes[idx] = self.blocks[idx][len - 1];
+        }
+        true
+    }
+
+    /// `k`-th smallest value, counting from `0`.
+    pub fn select(&self, mut k: usize) -> Option<f64> {
+        for block in &self.blocks {
+            if k < block.len() {
+                return Some(block[k]);
+            }
+            k -= block.len();
+        }
+        None
+    }
+
+    /// Quantile `q` in `[0, 1]`, linearly interpolated between closest ranks.
+    ///
+    /// `NaN` if there are no values.
+    pub fn quantile(&self, q: f64) -> f64 {
+        if self.is_empty() {
+            return f64::NAN;
+        }
+        let rank = q.clamp(0.0, 1.0) * (self.len - 1) as f64;
+        let lower = rank.floor() as usize;
+        let fraction = rank - lower as f64;
+        let low = self.select(lower).expect("rank is within len");
+        if fraction == 0.0 {
+            return low;
+        }
+        let high = self.select(lower + 1).expect("rank is within len");
+        low + (high - low) * fraction
+    }
+
+    /// Number of bytes blocks will additionally allocate when `n` more values are inserted,
+    /// assuming blocks are about half full.
+    pub fn estimated_growth(&self, n: usize) -> usize {
+        let blocks = (self.len + n).div_ceil(BLOCK_SIZE);
+        blocks.saturating_sub(self.blocks.len()) * 2 * BLOCK_SIZE * size_of::<f64>()
+            + blocks.saturating_sub(self.blocks.capacity()) * size_of::<Vec<f64>>()
+    }
+
+    /// Approximate number of bytes allocated by blocks.
+    pub fn memory_usage(&self) -> usize {
+        self.blocks.capacity() * size_of::<Vec<f64>>()
+            + self.maxes.capacity() * size_of::<f64>()
+            + self
+                .blocks
+                .iter()
+                .map(|block| block.capacity() * size_of::<f64>())
+                .sum::<usize>()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sorted_across_blocks() {
+        let mut set = SortedBlocks::new();
+        // irregular order, with duplicates, spanning several blocks
+        let values: Vec<f64> = (0..5000).map(|i| ((i * 7919) % 3001) as f64).collect();
+        for &val in &values {
+            set.insert(val);
+        }
+        assert!(set.blocks.len() > 1);
+
+        let mut sorted = values.clone();
+        sorted.sort_by(f64::total_cmp);
+        for (k, &expected) in sorted.iter().enumerate() {
+            assert_eq!(set.select(k), Some(expected));
+        }
+        assert_eq!(set.select(sorted.len()), None);
+
+        for &val in &values[..4000] {
+            assert!(set.remove(val));
+        }
+        assert!(!set.remove(-1.0));
+        let mut rest = values[4000..].to_vec();
+        rest.sort_by(f64::total_cmp);
+        assert_eq!(set.len, rest.len());
+        for (k, &expected) in rest.iter().enumerate() {
+            assert_eq!(set.select(k), Some(expected));
+        }
+    }
+
+    #[test]
+    fn test_quantile() {
+        let mut set = SortedBlocks::new();
+        assert!(set.quantile(0.5).is_nan());
+        for val in [5.0, 1.0, 4.0, 2.0, 3.0] {
+            set.insert(val);
+        }
+        assert_eq!(set.quantile(0.0), 1.0);
+        assert_eq!(set.quantile(0.5), 3.0);
+        assert_eq!(set.quantile(1.0), 5.0);
+        assert_eq!(set.quantile(0.25), 2.0);
+        assert_eq!(set.quantile(0.1), 1.4);
+
+        set.insert(6.0);
+        assert_eq!(set.quantile(0.5), 3.5);
+    }
+}
@@ -0,0 +1,88 @@
+//! `/stats/stream` endpoint emitting Server-Sent Events with stats of a symbol after its batches.
+use crate::api::Level;
+use crate::error::Error;
+use crate::subscriptions::{Subscriber, Subscription};
+use axum::extract::Query;
+use axum::http::HeaderMap;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use futures_util::Stream;
+use futures_util::stream;
+use serde::Deserialize;
+use std::convert::Infallible;
+
+/// Subscription given by query parameters, with comma separated `k`, e.g. `k=1,3,5`,
+/// and `fields`, e.g. `fields=avg,last`.
+#[derive(Deserialize)]
+pub struct StreamRequest {
+    pub symbol: String,
+    pub k: Option<String>,
+    pub fields: Option<String>,
+    pub throttle_ms: Option<u64>,
+    pub q: Option<String>,
+    pub moments: Option<bool>,
+    pub returns: Option<bool>,
+    pub periods_per_year: Option<f64>,
+}
+
+impl TryFrom<StreamRequest> for Subscription {
+    type Error = Error;
+
+    fn try_from(req: StreamRequest) -> Result<Self, Self::Error> {
+        let list = |text: Option<String>| -> Vec<String> {
+            text.iter()
+                .flat_map(|text| text.split(','))
+                .map(|item| item.trim().to_string())
+                .filter(|item| !item.is_empty())
+                .collect()
+        };
+        let levels = list(req.k)
+            .iter()
+            .map(|k| k.parse::<Level>())
+            .collect::<Result<_, _>>()
+            .map_err(Error::InvalidRequest)?;
+        Ok(Self {
+            symbol: req.symbol,
+            levels,
+            fields: list(req.fields),
+            throttle_ms: req.throttle_ms,
+            q: req.q,
+            moments: req.moments,
+            returns: req.returns,
+            periods_per_year: req.periods_per_year,
+        })
+    }
+}
+
+/// Streams an event after batches of the symbol, with id of the number of its values
+/// added so far. With `Last-Event-ID` of a reconnecting client, there is no event until
+/// the symbol gets values past it.
+pub async fn stream_stats(
+    headers: HeaderMap,
+    Query(req): Query<StreamRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Error> {
+    tracing::info!(
+        "GET /stats/stream - symbol: {}, k: {:?}, fields: {:?}",
+        req.symbol,
+        req.k,
+        req.fields
+    );
+
+    let mut subscriber = Subscriber::new(req.try_into()?)?;
+    // ids are the ones sent before, so anything else is ignored
+    let last_event_id = headers
+        .get("last-event-id")
+        .and_then(|id| id.to_str().ok()?.parse().ok());
+    if let Some(index) = last_event_id {
+        subscriber = subscriber.resume_after(index);
+    }
+
+    let events = stream::unfold(subscriber, |mut subscriber| async move {
+        let update = subscriber.next().await;
+        let event = Event::default()
+            .id(update.index.to_string())
+            .json_data(&update)
+            .expect("updates are serialized as JSON");
+        Some((Ok(event), subscriber))
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
@@ -0,0 +1,232 @@
+//! Stats pushed to subscribers after batches of their symbols, see [`Subscriber`].
+use crate::api::{Level, StatsResult, stats_options};
+use crate::app_state::{SYMBOLS, config};
+use crate::error::Error;
+use crate::symbol_aggregator::StatsOptions;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Stats of a symbol to push: windows of `levels`, or all of them if there are none, with just
+/// `fields` of stats, or all of them if there are none. Options of stats are those of `/stats/`.
+///
+/// Updates come at most once per `throttle_ms`, or per the configured throttle if it is longer.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Subscription {
+    pub symbol: String,
+    #[serde(default)]
+    pub levels: Vec<Level>,
+    #[serde(default)]
+    pub fields: Vec<String>,
+    pub throttle_ms: Option<u64>,
+    pub q: Option<String>,
+    pub moments: Option<bool>,
+    pub returns: Option<bool>,
+    pub periods_per_year: Option<f64>,
+}
+
+/// Stats of the subscribed windows of a symbol, keyed by their levels.
+#[derive(Debug, Serialize)]
+pub struct Update {
+    pub symbol: String,
+    /// number of values of the symbol added so far, see [`SymbolAggregator::index`]
+    ///
+    /// [`SymbolAggregator::index`]: crate::symbol_aggregator::SymbolAggregator::index
+    pub index: u64,
+    pub stats: BTreeMap<u32, Map<String, Value>>,
+}
+
+/// Waits for batches of the subscribed symbol, turning them into [`Update`]s.
+///
+/// Batches applied while an update is throttled, or not taken yet, are coalesced into the next one.
+pub struct Subscriber {
+    subscription: Subscription,
+    options: StatsOptions,
+    /// `None` for all levels
+    levels: Option<Vec<u32>>,
+    throttle: Duration,
+    changes: watch::Receiver<()>,
+    last_update: Option<Instant>,
+    /// index of the latest update, so there are none without new values
+    last_index: Option<u64>,
+}
+
+impl Subscriber {
+    /// Subscribes to batches of the symbol; the first update has the current stats, if any.
+    pub fn new(subscription: Subscription) -> Result<Self, Error> {
+        if subscription.symbol.trim().is_empty() {
+            return Err(Error::InvalidRequest("Symbol is empty".into()));
+        }
+        let options = stats_options(
+            subscription.q.as_deref(),
+            subscription.moments,
+            subscription.returns,
+            subscription.periods_per_year,
+        )?;
+        let levels = (!subscription.levels.contains(&Level::All)
+            && !subscription.levels.is_empty())
+        .then(|| {
+            subscription
+                .levels
+                .iter()
+                .filter_map(|level| match level {
+                    Level::All => None,
+                    Level::K(k) => Some(*k),
+                })
+                .collect()
+        });
+        let throttle = subscription
+            .throttle_ms
+            .map_or(Duration::ZERO, Duration::from_millis)
+            .max(config().ws_throttle);
+
+        let mut changes = SYMBOLS.subscribe(&subscription.symbol);
+        changes.mark_changed();
+        Ok(Self {
+            subscription,
+            options,
+            levels,
+            throttle,
+            changes,
+            last_update: None,
+            last_index: None,
+        })
+    }
+
+    /// Skips updates until the symbol has values past `index`, e.g. of the latest update
+    /// a client got before reconnecting.
+    pub fn resume_after(mut self, index: u64) -> Self {
+        self.last_index = Some(index);
+        self
+    }
+
+    pub fn symbol(&self) -> &str {
+        &self.subscription.symbol
+    }
+
+    /// Waits for the next update, no sooner than the throttle after the previous one.
+    pub async fn next(&mut self) -> Update {
+        loop {
+            // the registry keeps senders as long as there are receivers
+            let _ = self.changes.changed().await;
+            if let Some(last_update) = self.last_update {
+                tokio::time::sleep_until(last_update + self.throttle).await;
+            }
+            self.changes.mark_unchanged();
+            self.last_update = Some(Instant::now());
+            // nothing to push until the symbol gets values
+            if let Some(update) = self.update()
+                && self.last_index != Some(update.index)
+            {
+                self.last_index = Some(update.index);
+                return update;
+            }
+        }
+    }
+
+    /// Current stats of the subscribed windows, or `None` if the symbol has no values.
+    pub fn update(&mut self) -> Option<Update> {
+        let entry = SYMBOLS.get(&self.subscription.symbol)?;
+        self.options.now = SYMBOLS.now();
+        // locked once, so stats of all levels come from the same state
+        let mut agg = entry.aggregator.lock().unwrap();
+        let index = agg.index();
+        let stats = match &self.levels {
+            None => agg.get_all_stats_with(&self.options)?,
+            Some(levels) => levels
+                .iter()
+                .filter_map(|&k| Some((k, agg.get_stats_with(k, &self.options)?)))
+                .collect(),
+        };
+        drop(agg);
+
+        let stats = stats
+            .into_iter()
+            .map(|(k, stats)| (k, self.fields_of(stats)))
+            .collect();
+        Some(Update {
+            symbol: self.subscription.symbol.clone(),
+            index,
+            stats,
+        })
+    }
+
+    fn fields_of(&self, stats: StatsResult) -> Map<String, Value> {
+        let Ok(Value::Object(mut stats)) = serde_json::to_value(stats) else {
+            unreachable!("stats are serialized as an object");
+        };
+        if !self.subscription.fields.is_empty() {
+            stats.retain(|field, _| self.subscription.fields.contains(field));
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::windows::Windows;
+
+    fn subscription(symbol: &str) -> Subscription {
+        serde_json::from_value(serde_json::json!({
+            "symbol": symbol,
+            "levels": [1],
+            "fields": ["last", "avg"],
+            "throttle_ms": 50,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_updates_coalesced_and_throttled() {
+        let windows = Windows::geometric(2, 10);
+        let mut subscriber = Subscriber::new(subscription("SUBSCRIBED")).unwrap();
+        SYMBOLS
+            .add_batch("SUBSCRIBED", &[1.0], None, None, &windows)
+            .unwrap();
+
+        let update = subscriber.next().await;
+        assert_eq!(update.stats.len(), 1);
+        assert_eq!(update.stats[&1].len(), 2);
+        assert_eq!(update.stats[&1]["last"], 1.0);
+
+        let start = Instant::now();
+        for val in [2.0, 3.0] {
+            SYMBOLS
+                .add_batch("SUBSCRIBED", &[val], None, None, &windows)
+                .unwrap();
+        }
+        let update = subscriber.next().await;
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        assert_eq!(update.stats[&1]["last"], 3.0);
+        assert_eq!(update.stats[&1]["avg"], 2.0);
+        assert_eq!(update.index, 3);
+
+        // nothing is pushed without new values
+        SYMBOLS
+            .add_batch("SUBSCRIBED", &[], None, None, &windows)
+            .unwrap();
+        let next = tokio::time::timeout(Duration::from_millis(100), subscriber.next()).await;
+        assert!(next.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resumed_after_index() {
+        let windows = Windows::geometric(2, 10);
+        SYMBOLS
+            .add_batch("RESUMED", &[1.0, 2.0], None, None, &windows)
+            .unwrap();
+        let subscriber = || Subscriber::new(subscription("RESUMED")).unwrap();
+
+        let mut resumed = subscriber().resume_after(2);
+        let next = tokio::time::timeout(Duration::from_millis(100), resumed.next()).await;
+        assert!(next.is_err());
+
+        // missed values are told by the gap between indexes
+        let mut resumed = subscriber().resume_after(1);
+        assert_eq!(resumed.next().await.index, 2);
+    }
+}
@@ -1,7 +1,80 @@
 use crate::api::StatsResult;
-use crate::kahan::NeumaierSum;
+use crate::candles::Candles;
+use crate::ewma::Ewma;
+use crate::quantile_sketch::{BUCKETS, SlidingQuantiles};
+use crate::returns::{LevelReturns, ReturnKind, Returns};
 // use crate::monotonic_queue::MonotonicQueue;
 use crate::shared_monotonic_queue::{MaxCmp, MinCmp, SharedMonotonicQueue};
+use crate::snapshot::{Decoder, Encoder, Persist, SnapshotError};
+use crate::sorted_blocks::SortedBlocks;
+use crate::time_levels::TimeLevels;
+use crate::variance::{ShiftedSums, WeightedSums};
+use crate::windows::{Span, Windows};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+
+/// Initial allocation of values ring.
+const MIN_BUFFER_CAPACITY: usize = 1024;
+
+pub const DEFAULT_EXACT_QUANTILES_MAX_WINDOW: usize = 100_000;
+
+pub const DEFAULT_CANDLES: usize = 100;
+
+/// Optional stats maintained by [`SymbolAggregator`], besides its windows.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AggregatorOptions {
+    /// count windows up to this size keep their values sorted for exact quantiles;
+    /// `0` disables them
+    pub exact_quantiles_max_window: usize,
+    /// bigger count windows and time windows keep quantile sketches
+    pub approx_quantiles: bool,
+    /// half-lives of exponentially weighted `avg` and `var`, in values or time
+    pub ewma_half_lives: Vec<Span>,
+    /// closed candles kept per window; `0` disables candles
+    pub candles: usize,
+    /// returns between consecutive values maintained by count windows, if any
+    pub returns: Option<ReturnKind>,
+}
+
+impl Default for AggregatorOptions {
+    fn default() -> Self {
+        Self {
+            exact_quantiles_max_window: DEFAULT_EXACT_QUANTILES_MAX_WINDOW,
+            approx_quantiles: true,
+            ewma_half_lives: Vec::new(),
+            candles: DEFAULT_CANDLES,
+            returns: None,
+        }
+    }
+}
+
+/// What to include in stats, and when.
+#[derive(Debug, Clone)]
+pub struct StatsOptions {
+    /// time to evaluate time windows at, in milliseconds since unix epoch;
+    /// the latest time seen is used if it is later
+    pub now: u64,
+    /// quantiles to return besides the summary ones
+    pub quantiles: Vec<f64>,
+    /// whether to return `std`, `skew` and `kurtosis`
+    pub moments: bool,
+    /// whether to return stats of returns, if maintained
+    pub returns: bool,
+    /// number of returns per year, to annualize volatility and Sharpe ratio of returns
+    pub periods_per_year: f64,
+}
+
+impl Default for StatsOptions {
+    fn default() -> Self {
+        Self {
+            now: 0,
+            quantiles: Vec::new(),
+            moments: false,
+            returns: false,
+            periods_per_year: 1.0,
+        }
+    }
+}
 
 /// The core of this service. Maintains all data per symbol to provide fast stats:
 /// * cyclic buffer of values to get `last`, `avg` and `var`, shared for all levels
@@ -10,6 +83,9 @@ use crate::shared_monotonic_queue::{MaxCmp, MinCmp, SharedMonotonicQueue};
 /// Space complexity is `O(n)` where `n` is top level size. Constant is small ~`2`:
 /// * `1n` for all values, and amortised `1n` for `min` and `max` queues.
 ///
+/// The ring is allocated lazily: it starts small and doubles, up to top level size,
+/// so fresh or quiet symbols do not pay for the whole top window up front.
+///
 /// Adding batch has `O(n)` time complexity. Eviction from buffer is online,
 /// as we are overwriting old values and need to update stats.
 /// Eviction from deques is online for worse values, but for too old values it is once,
@@ -24,27 +100,63 @@ use crate::shared_monotonic_queue::{MaxCmp, MinCmp, SharedMonotonicQueue};
 /// * `O(log n)` pessimistic for lower levels `min` and `max` stats
 ///   * `O(1)` if cache is hit for lower levels `min` and `max`
 ///
+/// Exact quantiles of count levels up to [`AggregatorOptions::exact_quantiles_max_window`]
+/// come from a sorted copy of level values, see [`SortedBlocks`]. Each such level costs
+/// another `1n` of its size, and `O(log n + sqrt n)` per pushed value.
+/// Bigger levels keep [`SlidingQuantiles`] sketches instead, if
+/// [`AggregatorOptions::approx_quantiles`] is set, which are much smaller but approximate.
+///
+/// [`Ewma`] of each of [`AggregatorOptions::ewma_half_lives`] is updated with every value,
+/// independently of windows.
+///
+/// Weights of values, e.g. volumes, are kept in a second ring parallel to values, and each level
+/// keeps [`WeightedSums`] for `vwap`, `weighted_var` and `volume`. Both are set up by the first
+/// weighted batch, so symbols without weights do not pay for them.
+///
+/// Each window also has [`Candles`] of its consecutive blocks, unless
+/// [`AggregatorOptions::candles`] is `0`.
+///
+/// With [`AggregatorOptions::returns`], [`Returns`] of values are kept in another ring with own
+/// monotonic queues, and each count level keeps [`LevelReturns`] sums, all updated just like values.
+/// Time levels do not maintain returns.
+///
 /// Impl note:
-/// Const generics are used to facilitate testing.
-pub struct SymbolAggregator<const LEVELS: usize, const RADIX: usize> {
-    /// ring of values
+/// Levels are defined at runtime by [`Windows`], smallest first. By default those are
+/// `10^k` windows for `1 <= k <= 8`. Time windows, if any, come after count windows
+/// and are maintained by [`TimeLevels`].
+pub struct SymbolAggregator {
+    /// windows the levels were built from; used to resolve window names
+    windows: Windows,
+    options: AggregatorOptions,
+    /// ring of values; grows until its length reaches `capacity`, then it wraps
     buffer: Vec<f64>,
-    /// capacity of the whole buffer (equals top level window size: `10^8`)
+    /// ring of weights parallel to `buffer`, since the first weighted batch;
+    /// values added before it, or without weights, weigh `1`
+    weights: Option<Vec<f64>>,
+    /// capacity of the whole ring (equals top level window size, by default `10^8`)
     capacity: usize,
     /// index of the `last` value inserted to the `buffer`
     ///
-    /// fresh struct have it set to `capacity` which is logically `-1`
+    /// fresh struct have it set to `capacity - 1` which is logically `-1`
     tip: usize,
     /// number of values in the `buffer`
     len: usize,
     /// total number of elements added to the ring from the service start; never resets
     index: u64,
     /// Each level has own precomputed stats to get `avg` and `var` in `O(1)`
-    levels: [LevelStats; LEVELS],
+    levels: Vec<LevelStats>,
     /// Single ring of precomputed stats to get `min` in `O(1)` or `O(log n)`
-    minq: SharedMonotonicQueue<MinCmp, LEVELS, RADIX>,
+    minq: SharedMonotonicQueue<MinCmp>,
     /// Ditto, just for `max`
-    maxq: SharedMonotonicQueue<MaxCmp, LEVELS, RADIX>,
+    maxq: SharedMonotonicQueue<MaxCmp>,
+    /// time-based levels, if there are any time windows
+    time: Option<TimeLevels>,
+    /// exponentially weighted stats, one per half-life of options
+    ewmas: Vec<Ewma>,
+    /// candles of each window, count ones first; empty if disabled
+    candles: Vec<Candles>,
+    /// returns of values, if enabled by options
+    returns: Option<Returns>,
 }
 
 /// Maintains sum of values and their squares for fast `avg` and `var` stats at single level.
@@ -55,10 +167,16 @@ pub struct LevelStats {
     pub size: usize,
     /// number of elements currently in the level
     pub count: usize,
-    /// sum of those elements
-    pub sum: NeumaierSum,
-    /// sum of square roots of those elements
-    pub sum_sq: NeumaierSum,
+    /// sums of those elements and their squares, shifted for numerical stability
+    pub sums: ShiftedSums,
+    /// weighted sums of those elements, if the aggregator has weights
+    pub weighted: Option<WeightedSums>,
+    /// sums of returns of those elements, if the aggregator keeps returns
+    pub returns: Option<LevelReturns>,
+    /// sorted copy of level values, for exact quantiles of small enough levels
+    quantiles: Option<SortedBlocks>,
+    /// sketches for approximate quantiles of bigger levels
+    sketch: Option<SlidingQuantiles>,
     // pub minq: MonotonicQueue<MinCmp>,
     // pub maxq: MonotonicQueue<MaxCmp>,
 }
@@ -68,72 +186,231 @@ impl LevelStats {
         self.count == self.size
     }
 
-    /// Push new value (and its square root) to this level stats, possibly evicting `oldest_value`.
-    fn push(&mut self, val: f64, val_sq: f64, oldest_value: f64) {
-        self.evict_oldest(oldest_value);
+    /// Push new value of `weight` to this level stats, possibly evicting `oldest_value`
+    /// of `oldest_weight`.
+    ///
+    /// `index` is the absolute index of the value, which buckets sketched values.
+    fn push(&mut self, index: u64, val: f64, weight: f64, oldest_value: f64, oldest_weight: f64) {
+        self.evict_oldest(oldest_value, oldest_weight);
+        self.sums.push(val, self.count);
+        if let Some(weighted) = &mut self.weighted {
+            weighted.push(val, weight, self.count);
+        }
         self.count += 1;
-        self.sum += val;
-        self.sum_sq += val_sq;
+        if let Some(quantiles) = &mut self.quantiles {
+            quantiles.insert(val);
+        }
+        let bucket = index / self.bucket_size();
+        if let Some(sketch) = &mut self.sketch {
+            sketch.push(bucket, index, val);
+        }
+    }
+
+    /// Number of consecutive values sketched together.
+    fn bucket_size(&self) -> u64 {
+        (self.size as u64).div_ceil(BUCKETS)
     }
 
     /// Evicts oldest value from level stats if full, as a preparation to push new value.
-    fn evict_oldest(&mut self, oldest_value: f64) {
+    fn evict_oldest(&mut self, oldest_value: f64, oldest_weight: f64) {
         if !self.is_full() {
             // nothing to do
             return;
         }
 
         tracing::trace!("evicting oldest value {oldest_value} of level {}", self.id);
-        self.sum += -oldest_value;
-        self.sum_sq += -(oldest_value * oldest_value);
+        self.sums.evict(oldest_value);
+        if let Some(weighted) = &mut self.weighted {
+            weighted.evict(oldest_value, oldest_weight);
+        }
         self.count = self.count.saturating_sub(1);
+        if let Some(quantiles) = &mut self.quantiles {
+            quantiles.remove(oldest_value);
+        }
     }
 }
 
-impl<const LEVELS: usize, const RADIX: usize> SymbolAggregator<LEVELS, RADIX> {
-    pub fn new() -> Self {
-        let capacity = RADIX.pow(LEVELS as u32);
-        let sizes = std::array::from_fn(|i| (RADIX as u64).pow((i + 1) as u32));
+impl Default for SymbolAggregator {
+    fn default() -> Self {
+        Self::new(Windows::default())
+    }
+}
+
+impl SymbolAggregator {
+    pub fn new(windows: Windows) -> Self {
+        Self::with_options(windows, AggregatorOptions::default())
+    }
+
+    pub fn with_options(windows: Windows, options: AggregatorOptions) -> Self {
+        let capacity = windows.max_size();
+        let sizes: Vec<u64> = windows.sizes().map(|size| size as u64).collect();
+        let time = (windows.durations().next().is_some())
+            .then(|| TimeLevels::new(windows.durations(), options.approx_quantiles));
 
         Self {
-            buffer: vec![0.0; capacity],
+            buffer: Vec::new(),
+            weights: None,
             capacity,
-            tip: capacity, // logically -1
+            tip: capacity - 1, // logically -1
             len: 0,
             index: 0,
-            levels: std::array::from_fn(|i| {
-                let size = RADIX.pow((i + 1) as u32);
-                LevelStats {
+            levels: windows
+                .sizes()
+                .enumerate()
+                .map(|(i, size)| LevelStats {
                     id: i,
                     size,
                     count: 0,
-                    sum: 0f64.into(),
-                    sum_sq: 0f64.into(),
+                    sums: ShiftedSums::default(),
+                    weighted: None,
+                    returns: options.returns.map(|_| LevelReturns::default()),
+                    quantiles: (size <= options.exact_quantiles_max_window).then(SortedBlocks::new),
+                    sketch: (size > options.exact_quantiles_max_window && options.approx_quantiles)
+                        .then(SlidingQuantiles::new),
                     // minq: MonotonicQueue::new(),
                     // maxq: MonotonicQueue::new(),
-                }
-            }),
-            minq: SharedMonotonicQueue::<MinCmp, LEVELS, RADIX>::new(sizes),
-            maxq: SharedMonotonicQueue::<MaxCmp, LEVELS, RADIX>::new(sizes),
+                })
+                .collect(),
+            minq: SharedMonotonicQueue::new(sizes.iter().copied()),
+            maxq: SharedMonotonicQueue::new(sizes.iter().copied()),
+            returns: options.returns.map(|kind| Returns::new(kind, &sizes)),
+            time,
+            ewmas: options
+                .ewma_half_lives
+                .iter()
+                .copied()
+                .map(Ewma::new)
+                .collect(),
+            candles: match options.candles {
+                0 => Vec::new(),
+                history => sizes
+                    .iter()
+                    .copied()
+                    .chain(windows.durations())
+                    .map(|span| Candles::new(span, history))
+                    .collect(),
+            },
+            windows,
+            options,
+        }
+    }
+
+    pub fn windows(&self) -> &Windows {
+        &self.windows
+    }
+
+    pub fn options(&self) -> &AggregatorOptions {
+        &self.options
+    }
+
+    /// Number of values added so far.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    /// Latest time seen by time windows or time EWMAs, in milliseconds since unix epoch;
+    /// `None` without any of them.
+    pub fn now(&self) -> Option<u64> {
+        match &self.time {
+            Some(time) => Some(time.now()),
+            None => self.ewmas.iter().filter_map(Ewma::now).max(),
         }
     }
 
+    /// Timestamps to add a batch of `n` values with: `requested` ones,
+    /// or `now` for each value if there are none.
+    ///
+    /// Timestamps are made non-decreasing and not older than the latest time seen,
+    /// so they are exactly the ones the values end up with.
+    /// Returns `None` without time windows and time EWMAs, as timestamps are not needed then.
+    pub fn timestamps_for(
+        &self,
+        n: usize,
+        requested: Option<&[u64]>,
+        now: u64,
+    ) -> Option<Vec<u64>> {
+        let mut latest = self.now()?;
+        let timestamps = match requested {
+            Some(requested) => requested
+                .iter()
+                .map(|&timestamp| {
+                    latest = latest.max(timestamp);
+                    latest
+                })
+                .collect(),
+            None => vec![latest.max(now); n],
+        };
+        Some(timestamps)
+    }
+
     /// Add values to the batch.
     ///
     /// We skip values which square root are too big (infinity).
+    /// With time windows, values are added at the latest time seen, see [`Self::add_batch_at`].
     pub fn add_batch(&mut self, values: &[f64]) {
-        tracing::debug!("add_batch: {values:?}");
+        self.add_batch_at(values, None);
+    }
+
+    /// Add values observed at given `timestamps` (milliseconds since unix epoch), one per value.
+    ///
+    /// Timestamps matter only for time windows and time EWMAs. Those older than the latest time seen
+    /// are treated as the latest time; without timestamps all values get the latest time.
+    pub fn add_batch_at(&mut self, values: &[f64], timestamps: Option<&[u64]>) {
+        self.add_batch_with(values, timestamps, None);
+    }
+
+    /// Add values observed at `timestamps`, see [`Self::add_batch_at`], of given non-negative
+    /// `weights`, one per value.
+    ///
+    /// The first weighted batch sets up weighted stats, with all values seen before weighing `1`,
+    /// just like values of later batches without weights.
+    pub fn add_batch_with(
+        &mut self,
+        values: &[f64],
+        timestamps: Option<&[u64]>,
+        weights: Option<&[f64]>,
+    ) {
+        tracing::debug!("add_batch: {values:?} at {timestamps:?} of {weights:?}");
+        debug_assert!(timestamps.is_none_or(|timestamps| timestamps.len() == values.len()));
+        debug_assert!(weights.is_none_or(|weights| weights.len() == values.len()));
+        if weights.is_some() && self.weights.is_none() {
+            self.enable_weights();
+        }
 
         let mut min_minq_evicted_idx = None;
         let mut min_maxq_evicted_idx = None;
 
-        for &val in values {
-            if !self.try_push(val) {
+        for (i, &val) in values.iter().enumerate() {
+            let weight = weights.map_or(1.0, |weights| weights[i]);
+            if !self.try_push(val, weight) {
                 continue;
             }
             self.minq.push(self.index, val, &mut min_minq_evicted_idx);
             self.maxq.push(self.index, val, &mut min_maxq_evicted_idx);
             self.index += 1;
+
+            let timestamp = timestamps.map(|timestamps| timestamps[i]);
+            if let Some(time) = &mut self.time {
+                time.push(timestamp.unwrap_or(time.now()), val, weight);
+            }
+            if !self.candles.is_empty() {
+                let (counts, times) = self.candles.split_at_mut(self.levels.len());
+                for candles in counts {
+                    candles.push(self.index - 1, val, weight);
+                    // the last value of a block closes it
+                    candles.advance(self.index);
+                }
+                let now = self.time.as_ref().map_or(0, TimeLevels::now);
+                for candles in times {
+                    candles.push(now, val, weight);
+                }
+            }
+            for ewma in self.ewmas.iter_mut() {
+                ewma.push(val, timestamp);
+            }
+        }
+        if let Some(time) = &mut self.time {
+            time.advance(time.now());
         }
 
         // for level in self.levels.iter_mut() {
@@ -145,9 +422,17 @@ impl<const LEVELS: usize, const RADIX: usize> SymbolAggregator<LEVELS, RADIX> {
         // eviction after adding whole batch
         self.minq.evict(self.index, min_minq_evicted_idx);
         self.maxq.evict(self.index, min_maxq_evicted_idx);
+        if let Some(returns) = &mut self.returns {
+            returns.evict(self.index);
+        }
+        for level in self.levels.iter_mut() {
+            if let Some(sketch) = &mut level.sketch {
+                sketch.evict(self.index - level.count as u64);
+            }
+        }
     }
 
-    /// Tries to push single `val` to the ring and all level stats for `avg` and `var`.
+    /// Tries to push single `val` to the ring and all count level stats for `avg` and `var`.
     ///
     /// Potentially evicting the oldest value for each level,
     /// and overriding for top level, if buffer is full.
@@ -155,17 +440,67 @@ impl<const LEVELS: usize, const RADIX: usize> SymbolAggregator<LEVELS, RADIX> {
     /// Shifts `tip` and, if buffer is not full, increases `len`.
     ///
     /// Returns weather push was successful: might not be if value or sum of squares is too big.
-    fn try_push(&mut self, val: f64) -> bool {
+    fn try_push(&mut self, val: f64, weight: f64) -> bool {
         let val_sq = val * val;
-        let max_sum_sq = (self.levels[LEVELS - 1].sum_sq.clone() + val_sq).sum();
-        if max_sum_sq.is_nan() || max_sum_sq.is_infinite() {
-            tracing::warn!("ignoring {val} since its square root brings sum to {max_sum_sq}");
+        if val_sq.is_nan() || val_sq.is_infinite() {
+            tracing::warn!("ignoring {val} since its square is {val_sq}");
+            return false;
+        }
+        // shifted sums of powers overflow sooner if shift is far from the value,
+        // so recenter before giving up
+        for level in self.levels.iter_mut() {
+            if level.sums.fits(val) {
+                continue;
+            }
+            level
+                .sums
+                .recenter(window(&self.buffer, self.tip, level.count));
+            let sum_sq = level.sums.sum_sq_with(val);
+            if !sum_sq.is_finite() {
+                tracing::warn!("ignoring {val} since its square brings sum to {sum_sq}");
+                return false;
+            }
+            if !level.sums.fits(val) {
+                tracing::debug!("{val} overflows higher moments of level {}", level.id);
+            }
+        }
+        if let Some(weights) = &self.weights {
+            for level in self.levels.iter_mut() {
+                let Some(weighted) = &mut level.weighted else {
+                    continue;
+                };
+                if weighted.fits(val, weight) {
+                    continue;
+                }
+                weighted.recenter(window(&self.buffer, self.tip, level.count).zip(window(
+                    weights,
+                    self.tip,
+                    level.count,
+                )));
+                if !weighted.fits(val, weight) {
+                    tracing::warn!(
+                        "ignoring {val} of weight {weight} since it overflows weighted sums"
+                    );
+                    return false;
+                }
+            }
+        }
+        if let Some(time) = &mut self.time
+            && !time.fits(val, weight)
+        {
+            tracing::warn!("ignoring {val} since its square overflows sum of a time level");
+            return false;
+        }
+        if !self.ewmas.iter().all(|ewma| ewma.fits(val)) {
+            tracing::warn!("ignoring {val} since it overflows EWMA variance");
             return false;
         }
+        let ret = self.return_of(val);
 
         let tip_plus_cap = self.tip + self.capacity;
+        let index = self.index;
         for level in self.levels.iter_mut() {
-            let oldest_level_value = if level.is_full() {
+            let (oldest_level_value, oldest_level_weight) = if level.is_full() {
                 // tip: 13
                 // level size: 10
                 // offset: 4
@@ -174,11 +509,33 @@ impl<const LEVELS: usize, const RADIX: usize> SymbolAggregator<LEVELS, RADIX> {
                 // level size: 1000
                 // offset: 99_999_002
                 let oldest_level_idx = (tip_plus_cap - level.size + 1) % self.capacity;
-                self.buffer[oldest_level_idx]
+                let oldest_weight = self
+                    .weights
+                    .as_ref()
+                    .map_or(1.0, |weights| weights[oldest_level_idx]);
+                (self.buffer[oldest_level_idx], oldest_weight)
             } else {
-                0.
+                (0., 1.)
             };
-            level.push(val, val_sq, oldest_level_value);
+            let is_full = level.is_full();
+            if let (Some(level_returns), Some(returns)) = (&mut level.returns, &self.returns) {
+                // the value after the oldest one becomes the oldest, so its return leaves the level
+                if is_full && level.size > 1 {
+                    let oldest = returns.ring[(tip_plus_cap - level.size + 2) % self.capacity];
+                    if !oldest.is_nan() {
+                        level_returns.evict(oldest);
+                    }
+                }
+                let has_previous = if is_full {
+                    level.size > 1
+                } else {
+                    level.count > 0
+                };
+                if has_previous && let Some(ret) = ret {
+                    level_returns.push(ret);
+                }
+            }
+            level.push(index, val, weight, oldest_level_value, oldest_level_weight);
         }
 
         if !self.is_full() {
@@ -186,10 +543,210 @@ impl<const LEVELS: usize, const RADIX: usize> SymbolAggregator<LEVELS, RADIX> {
         }
         self.tip = (self.tip + 1) % self.capacity;
         tracing::trace!("adding value: {val} @ {} / {}", self.tip, self.capacity);
-        self.buffer[self.tip] = val;
+        if self.tip < self.buffer.len() {
+            self.buffer[self.tip] = val;
+            if let Some(weights) = &mut self.weights {
+                weights[self.tip] = weight;
+            }
+            if let Some(returns) = &mut self.returns {
+                returns.ring[self.tip] = ret.unwrap_or(f64::NAN);
+            }
+        } else {
+            // not wrapped yet, so `tip` is always the next free slot
+            self.grow_if_needed();
+            self.buffer.push(val);
+            if let Some(weights) = &mut self.weights {
+                weights.push(weight);
+            }
+            if let Some(returns) = &mut self.returns {
+                returns.ring.push(ret.unwrap_or(f64::NAN));
+            }
+        }
+
+        for level in self.levels.iter_mut() {
+            if level.sums.should_recenter(level.count) {
+                level
+                    .sums
+                    .recenter(window(&self.buffer, self.tip, level.count));
+            }
+            if let (Some(weighted), Some(weights)) = (&mut level.weighted, &self.weights)
+                && weighted.should_recenter(level.count)
+            {
+                weighted.recenter(window(&self.buffer, self.tip, level.count).zip(window(
+                    weights,
+                    self.tip,
+                    level.count,
+                )));
+            }
+            if let (Some(level_returns), Some(returns)) = (&mut level.returns, &self.returns)
+                && level_returns.sums.should_recenter(level_returns.count)
+            {
+                level_returns.sums.recenter(level_window_returns(
+                    &returns.ring,
+                    self.tip,
+                    level.count,
+                ));
+            }
+        }
+        if let (Some(returns), Some(ret)) = (&mut self.returns, ret) {
+            returns.push(index, ret);
+        }
         true
     }
 
+    /// Return of `val` after the last value, if returns are kept and it is defined.
+    ///
+    /// Return which would overflow sums of returns of any level, even after recentering,
+    /// is left out as undefined, while `val` itself is still added.
+    fn return_of(&mut self, val: f64) -> Option<f64> {
+        let last = self.get_last()?;
+        let returns = self.returns.as_ref()?;
+        let ret = returns.kind.of(last, val)?;
+        for level in self.levels.iter_mut() {
+            let Some(level_returns) = &mut level.returns else {
+                continue;
+            };
+            if level_returns.sums.fits(ret) {
+                continue;
+            }
+            level_returns
+                .sums
+                .recenter(level_window_returns(&returns.ring, self.tip, level.count));
+            if !level_returns.sums.sum_sq_with(ret).is_finite() {
+                tracing::warn!(
+                    "leaving out return {ret} of {val} since it overflows sums of returns"
+                );
+                return None;
+            }
+        }
+        Some(ret)
+    }
+
+    /// Sets up the ring of weights and weighted sums of all levels, with weight `1`
+    /// for all values seen so far.
+    fn enable_weights(&mut self) {
+        tracing::debug!("enabling weights of {} values", self.len);
+        let mut weights = Vec::with_capacity(self.buffer.capacity());
+        weights.resize(self.buffer.len(), 1.0);
+        for level in self.levels.iter_mut() {
+            let mut weighted = WeightedSums::default();
+            weighted.recenter(window(&self.buffer, self.tip, level.count).map(|val| (val, 1.0)));
+            level.weighted = Some(weighted);
+        }
+        self.weights = Some(weights);
+        if let Some(time) = &mut self.time {
+            time.enable_weights();
+        }
+    }
+
+    /// Makes room for at least one more value, doubling allocation up to `capacity`.
+    ///
+    /// `reserve_exact` is used, so the ring never allocates more than top level needs.
+    fn grow_if_needed(&mut self) {
+        if self.buffer.len() < self.buffer.capacity() {
+            return;
+        }
+        let new_capacity = self.next_buffer_capacity(self.buffer.capacity());
+        tracing::debug!(
+            "growing buffer from {} to {new_capacity} / {}",
+            self.buffer.capacity(),
+            self.capacity
+        );
+        self.buffer.reserve_exact(new_capacity - self.buffer.len());
+        if let Some(weights) = &mut self.weights {
+            weights.reserve_exact(new_capacity - weights.len());
+        }
+        if let Some(returns) = &mut self.returns {
+            returns
+                .ring
+                .reserve_exact(new_capacity - returns.ring.len());
+        }
+    }
+
+    fn next_buffer_capacity(&self, current: usize) -> usize {
+        (current * 2).max(MIN_BUFFER_CAPACITY).min(self.capacity)
+    }
+
+    /// Number of entries of all monotonic queues: of values, returns and time levels.
+    pub fn monotonic_queue_len(&self) -> usize {
+        self.minq.entries.len()
+            + self.maxq.entries.len()
+            + self.returns.as_ref().map_or(0, |returns| {
+                returns.minq.entries.len() + returns.maxq.entries.len()
+            })
+            + self
+                .time
+                .as_ref()
+                .map_or(0, TimeLevels::monotonic_queue_len)
+    }
+
+    /// Approximate number of bytes allocated by this aggregator:
+    /// the rings, level stats with sorted values or sketches, both monotonic queues and time levels.
+    pub fn memory_usage(&self) -> usize {
+        size_of::<Self>()
+            + self.buffer.capacity() * size_of::<f64>()
+            + self.weights.as_ref().map_or(0, Vec::capacity) * size_of::<f64>()
+            + self.returns.as_ref().map_or(0, Returns::memory_usage)
+            + self.levels.capacity() * size_of::<LevelStats>()
+            + self
+                .levels
+                .iter()
+                .filter_map(|level| level.quantiles.as_ref())
+                .map(SortedBlocks::memory_usage)
+                .sum::<usize>()
+            + self
+                .levels
+                .iter()
+                .filter_map(|level| level.sketch.as_ref())
+                .map(SlidingQuantiles::memory_usage)
+                .sum::<usize>()
+            + self.minq.memory_usage()
+            + self.maxq.memory_usage()
+            + self.time.as_ref().map_or(0, TimeLevels::memory_usage)
+            + self.ewmas.capacity() * size_of::<Ewma>()
+            + self
+                .candles
+                .iter()
+                .map(Candles::memory_usage)
+                .sum::<usize>()
+    }
+
+    /// Number of bytes the rings, sorted level values and time levels will additionally allocate
+    /// when `n` more values are pushed, `weighted` or not.
+    ///
+    /// Monotonic queues, sketches and candles are not included, since their growth depends on
+    /// the values, and they stay much smaller than the ring anyway.
+    pub fn estimated_growth(&self, n: usize, weighted: bool) -> usize {
+        let quantiles_growth: usize = self
+            .levels
+            .iter()
+            .filter_map(|level| {
+                let quantiles = level.quantiles.as_ref()?;
+                Some(quantiles.estimated_growth(n.min(level.size - level.count)))
+            })
+            .sum();
+        let target = (self.buffer.len() + n).min(self.capacity);
+        let mut capacity = self.buffer.capacity();
+        while capacity < target {
+            capacity = self.next_buffer_capacity(capacity);
+        }
+        let weights_growth = match &self.weights {
+            Some(weights) => capacity.saturating_sub(weights.capacity()),
+            None if weighted => capacity,
+            None => 0,
+        };
+        let returns_growth = self.returns.as_ref().map_or(0, |returns| {
+            capacity.saturating_sub(returns.ring.capacity())
+        });
+        let time_growth = self
+            .time
+            .as_ref()
+            .map_or(0, |time| time.estimated_growth(n, weighted));
+        (capacity - self.buffer.capacity() + weights_growth + returns_growth) * size_of::<f64>()
+            + quantiles_growth
+            + time_growth
+    }
+
     fn is_full(&self) -> bool {
         self.len == self.capacity
     }
@@ -202,31 +759,46 @@ impl<const LEVELS: usize, const RADIX: usize> SymbolAggregator<LEVELS, RADIX> {
         None
     }
 
-    /// Get stats for given level `k`.
+    /// Get stats for given level `k`, i.e. the `k`-th window, counting from `1`.
+    ///
+    /// Time windows are evaluated at the latest time seen, see [`Self::get_stats_with`].
+    pub fn get_stats(&mut self, k: u32) -> Option<StatsResult> {
+        self.get_stats_with(k, &StatsOptions::default())
+    }
+
+    /// Get stats for given level `k`, i.e. the `k`-th window, counting from `1`,
+    /// as specified by `options`.
     ///
     /// We might hit infinity when calculating variance. In such a case `var` will
     /// be `null` in response. Later when too big values are evicted, `var` will be
-    /// returned again.
-    pub fn get_stats(&mut self, k: u32) -> Option<StatsResult> {
-        let Some(last) = self.get_last() else {
-            return None;
-        };
+    /// returned again. Stats of a time window without values are `null` as well,
+    /// and so are quantiles of windows which do not maintain them,
+    /// and `skew` and `kurtosis` while powers of values in the window overflow.
+    pub fn get_stats_with(&mut self, k: u32, options: &StatsOptions) -> Option<StatsResult> {
+        let last = self.get_last()?;
 
         let k = k as usize;
-        if !(1..=LEVELS).contains(&k) {
+        if !(1..=self.windows.len()).contains(&k) {
             return None;
         }
+        if k > self.levels.len() {
+            let time = self
+                .time
+                .as_mut()
+                .expect("levels after count ones are time levels");
+            let mut stats = time.get_stats(k - 1 - self.levels.len(), options);
+            stats.ewma = self.ewmas.iter().map(Ewma::stats).collect();
+            return Some(stats);
+        }
 
         let level = &self.levels[k - 1];
 
         // self.minq.refresh_best(k - 1, self.index);
         // self.maxq.refresh_best(k - 1, self.index);
 
-        let n = level.count as f64;
-        let sum = level.sum.sum();
-        let sum_sq = level.sum_sq.sum();
-        let avg = sum / n;
-        let var = (sum_sq / n) - (avg * avg);
+        let n = level.count;
+        let avg = level.sums.avg(n);
+        let var = level.sums.var(n);
         if var.is_infinite() || var.is_nan() {
             tracing::warn!("variance not available: it is {var}");
         }
@@ -239,7 +811,7 @@ impl<const LEVELS: usize, const RADIX: usize> SymbolAggregator<LEVELS, RADIX> {
         // assert_eq!(min, min1);
         // assert_eq!(max, max1);
 
-        tracing::debug!("get_stats: count: {n} sum: {sum} for level: {}", level.id);
+        tracing::debug!("get_stats: count: {n} avg: {avg} for level: {}", level.id);
         tracing::trace!(
             "get_stats: min best indexes: {:?}",
             self.minq.debug_best_indexes()
@@ -249,12 +821,339 @@ impl<const LEVELS: usize, const RADIX: usize> SymbolAggregator<LEVELS, RADIX> {
             self.maxq.debug_best_indexes()
         );
 
-        Some(StatsResult {
+        let mut stats = StatsResult {
             min,
             max,
             last,
             avg,
             var,
+            ..StatsResult::default()
+        };
+        if options.moments {
+            stats.set_moments(&level.sums, n);
+        }
+        if let Some(weighted) = &level.weighted {
+            stats.set_weighted(weighted);
+        }
+        if options.returns
+            && let (Some(level_returns), Some(returns)) = (&level.returns, &mut self.returns)
+        {
+            let min = returns.minq.best_or_refresh(k - 1, self.index);
+            let max = returns.maxq.best_or_refresh(k - 1, self.index);
+            stats.returns = Some(level_returns.stats(
+                min.unwrap_or(f64::NAN),
+                max.unwrap_or(f64::NAN),
+                options.periods_per_year,
+            ));
+        }
+        let level = &mut self.levels[k - 1];
+        if let Some(quantiles) = &level.quantiles {
+            stats.rank_error = 0.0;
+            stats.set_quantiles(&options.quantiles, |q| quantiles.quantile(q));
+        } else if let Some(sketch) = &mut level.sketch {
+            let summary = sketch.summary(self.index - level.count as u64);
+            stats.rank_error = summary.rank_error;
+            stats.set_quantiles(&options.quantiles, |q| summary.quantile(q));
+        } else {
+            stats.set_quantiles(&options.quantiles, |_| f64::NAN);
+        }
+        stats.ewma = self.ewmas.iter().map(Ewma::stats).collect();
+        Some(stats)
+    }
+
+    /// Get stats of all levels, keyed by `k`, as specified by `options`,
+    /// or `None` if there are no values yet.
+    pub fn get_all_stats_with(
+        &mut self,
+        options: &StatsOptions,
+    ) -> Option<BTreeMap<u32, StatsResult>> {
+        (1..=self.windows.len() as u32)
+            .map(|k| Some((k, self.get_stats_with(k, options)?)))
+            .collect()
+    }
+
+    /// Candles of the `k`-th window, counting from `1`, or `None` if there is no such window
+    /// or candles are disabled.
+    ///
+    /// Candles of time windows are closed at time `now`, or the latest time seen if it is later.
+    pub fn candles(&mut self, k: u32, now: u64) -> Option<&Candles> {
+        let level = (k as usize).checked_sub(1)?;
+        let now = now.max(self.time.as_ref().map_or(0, TimeLevels::now));
+        let is_time = level >= self.levels.len();
+        let candles = self.candles.get_mut(level)?;
+        if is_time {
+            candles.advance(now);
+        }
+        Some(candles)
+    }
+}
+
+/// The last `count` values of the ring, whose last value is at `tip`.
+///
+/// Ring of `buffer.len()` values is either full or not wrapped yet, so it wraps at its length.
+fn window(buffer: &[f64], tip: usize, count: usize) -> impl Iterator<Item = f64> + Clone + '_ {
+    (0..count).map(move |i| buffer[(tip + buffer.len() - i) % buffer.len()])
+}
+
+/// Defined returns of a level of the last `count` values, see [`LevelReturns`].
+fn level_window_returns(
+    ring: &[f64],
+    tip: usize,
+    count: usize,
+) -> impl Iterator<Item = f64> + Clone + '_ {
+    window(ring, tip, count.saturating_sub(1)).filter(|ret| !ret.is_nan())
+}
+
+impl Persist for LevelStats {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.usize(self.count)?;
+        self.sums.encode(enc)?;
+        match &self.weighted {
+            Some(weighted) => {
+                enc.u8(1)?;
+                weighted.encode(enc)?;
+            }
+            None => enc.u8(0)?,
+        }
+        // present iff the aggregator keeps returns
+        if let Some(returns) = &self.returns {
+            returns.encode(enc)?;
+        }
+        Ok(())
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            // set by the aggregator from its windows
+            id: 0,
+            size: 0,
+            count: dec.usize()?,
+            sums: ShiftedSums::decode(dec)?,
+            weighted: match dec.u8()? {
+                0 => None,
+                _ => Some(WeightedSums::decode(dec)?),
+            },
+            // decoded by the aggregator, which knows whether it keeps returns
+            returns: None,
+            // rebuilt by the aggregator from its ring
+            quantiles: None,
+            sketch: None,
         })
     }
 }
+
+impl Persist for SymbolAggregator {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        self.windows.encode(enc)?;
+        enc.usize(self.options.exact_quantiles_max_window)?;
+        enc.u8(self.options.approx_quantiles as u8)?;
+        enc.usize(self.options.candles)?;
+        self.options.returns.encode(enc)?;
+        enc.usize(self.tip)?;
+        enc.usize(self.len)?;
+        enc.u64(self.index)?;
+        enc.f64_slice(&self.buffer)?;
+        match &self.weights {
+            Some(weights) => {
+                enc.u8(1)?;
+                enc.f64_slice(weights)?;
+            }
+            None => enc.u8(0)?,
+        }
+        for level in &self.levels {
+            level.encode(enc)?;
+            if let Some(sketch) = &level.sketch {
+                sketch.encode(enc)?;
+            }
+        }
+        self.minq.encode(enc)?;
+        self.maxq.encode(enc)?;
+        if let Some(returns) = &self.returns {
+            returns.encode(enc)?;
+        }
+        if let Some(time) = &self.time {
+            time.encode(enc)?;
+        }
+        enc.usize(self.ewmas.len())?;
+        for ewma in &self.ewmas {
+            ewma.encode(enc)?;
+        }
+        for candles in &self.candles {
+            candles.encode(enc)?;
+        }
+        Ok(())
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        let windows = Windows::decode(dec)?;
+        let options = AggregatorOptions {
+            exact_quantiles_max_window: dec.usize()?,
+            approx_quantiles: dec.u8()? != 0,
+            candles: dec.usize()?,
+            returns: Option::<ReturnKind>::decode(dec)?,
+            // decoded with EWMAs
+            ewma_half_lives: Vec::new(),
+        };
+        let mut agg = Self::with_options(windows, options);
+        agg.tip = dec.usize()?;
+        agg.len = dec.usize()?;
+        agg.index = dec.u64()?;
+        agg.buffer = dec.f64_vec()?;
+        agg.weights = match dec.u8()? {
+            0 => None,
+            _ => Some(dec.f64_vec()?),
+        };
+        if agg
+            .weights
+            .as_ref()
+            .is_some_and(|weights| weights.len() != agg.buffer.len())
+        {
+            return Err(SnapshotError::Corrupted(
+                "weights do not match values of the ring".into(),
+            ));
+        }
+        for level in agg.levels.iter_mut() {
+            let LevelStats {
+                count,
+                sums,
+                weighted,
+                ..
+            } = LevelStats::decode(dec)?;
+            if count > level.size || count > agg.len {
+                return Err(SnapshotError::Corrupted(format!(
+                    "level {} has {count} values, but its size is {} and ring has {}",
+                    level.id, level.size, agg.len
+                )));
+            }
+            if weighted.is_some() != agg.weights.is_some() {
+                return Err(SnapshotError::Corrupted(format!(
+                    "level {} weighted sums do not match weights of the ring",
+                    level.id
+                )));
+            }
+            level.count = count;
+            level.sums = sums;
+            level.weighted = weighted;
+            if level.returns.is_some() {
+                level.returns = Some(LevelReturns::decode(dec)?);
+            }
+            if let Some(sketch) = &mut level.sketch {
+                *sketch = SlidingQuantiles::decode(dec)?;
+            }
+        }
+        agg.minq = SharedMonotonicQueue::decode(dec)?;
+        agg.maxq = SharedMonotonicQueue::decode(dec)?;
+        if let Some(returns) = &mut agg.returns {
+            let mut decoded = Returns::decode(dec)?;
+            if decoded.ring.len() != agg.buffer.len()
+                || decoded.minq.views.len() != agg.levels.len()
+                || decoded.maxq.views.len() != agg.levels.len()
+            {
+                return Err(SnapshotError::Corrupted(
+                    "returns do not match values of the ring".into(),
+                ));
+            }
+            decoded.kind = returns.kind;
+            *returns = decoded;
+        }
+        if let Some(time) = &mut agg.time {
+            let decoded = TimeLevels::decode(dec)?;
+            if !decoded.durations().eq(agg.windows.durations()) {
+                return Err(SnapshotError::Corrupted(
+                    "time levels do not match windows".into(),
+                ));
+            }
+            if decoded.is_weighted() != agg.weights.is_some() {
+                return Err(SnapshotError::Corrupted(
+                    "time level weights do not match weights of the ring".into(),
+                ));
+            }
+            *time = decoded;
+        }
+        let len = dec.usize()?;
+        for _ in 0..len {
+            let ewma = Ewma::decode(dec)?;
+            agg.options.ewma_half_lives.push(ewma.half_life());
+            agg.ewmas.push(ewma);
+        }
+        for candles in agg.candles.iter_mut() {
+            let decoded = Candles::decode(dec)?;
+            candles.restore(decoded);
+        }
+
+        if agg.len > agg.capacity || agg.buffer.len() != agg.len || agg.tip >= agg.capacity {
+            return Err(SnapshotError::Corrupted(format!(
+                "ring of {} values with len {}, tip {} and capacity {}",
+                agg.buffer.len(),
+                agg.len,
+                agg.tip,
+                agg.capacity
+            )));
+        }
+        if agg.minq.views.len() != agg.levels.len() || agg.maxq.views.len() != agg.levels.len() {
+            return Err(SnapshotError::Corrupted(
+                "monotonic queues do not match levels".into(),
+            ));
+        }
+
+        // sorted level values are not stored, as those are the most recent values of the ring
+        for level in agg.levels.iter_mut() {
+            if let Some(quantiles) = &mut level.quantiles {
+                window(&agg.buffer, agg.tip, level.count).for_each(|val| quantiles.insert(val));
+            }
+        }
+        Ok(agg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_grows_lazily() {
+        let windows = Windows::new(["10", "3000"].map(|s| s.parse().unwrap())).unwrap();
+        // only the ring grows
+        let options = AggregatorOptions {
+            exact_quantiles_max_window: 0,
+            approx_quantiles: false,
+            ..AggregatorOptions::default()
+        };
+        let mut agg = SymbolAggregator::with_options(windows, options);
+        assert_eq!(agg.buffer.capacity(), 0);
+
+        agg.add_batch(&[1.0; 10]);
+        assert_eq!(agg.buffer.capacity(), MIN_BUFFER_CAPACITY);
+
+        let values: Vec<f64> = (0..2000).map(f64::from).collect();
+        assert_eq!(
+            agg.estimated_growth(2000, false),
+            MIN_BUFFER_CAPACITY * size_of::<f64>()
+        );
+        agg.add_batch(&values);
+        assert_eq!(agg.buffer.capacity(), 2 * MIN_BUFFER_CAPACITY);
+        assert_eq!(agg.estimated_growth(100_000, false), 952 * size_of::<f64>());
+        // weights would take as much as values
+        assert_eq!(
+            agg.estimated_growth(100_000, true),
+            (952 + 3000) * size_of::<f64>()
+        );
+
+        // capped at top level size, then wraps
+        agg.add_batch(&values);
+        assert_eq!(agg.buffer.capacity(), 3000);
+        assert_eq!(agg.buffer.len(), 3000);
+
+        let stats = agg.get_stats(2).unwrap();
+        assert_eq!(stats.min, 0.0);
+        assert_eq!(stats.max, 1999.0);
+        assert_eq!(stats.last, 1999.0);
+        // 1000..2000 from the first batch, then whole second batch
+        let expected_sum: f64 = (1000..2000).chain(0..2000).map(f64::from).sum();
+        assert_eq!(stats.avg, expected_sum / 3000.0);
+
+        let stats = agg.get_stats(1).unwrap();
+        assert_eq!(stats.min, 1990.0);
+        assert_eq!(stats.avg, 1994.5);
+    }
+}
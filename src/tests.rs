@@ -1,11 +1,31 @@
+#[cfg(feature = "test")]
+pub fn generate_random_data(n: usize, base: f64, drift: f64, volatility: f64) -> Vec<f64> {
+    use rand_distr::{Distribution, Normal};
+    let mut rng = rand::rng();
+    let normal = Normal::new(drift, volatility).unwrap();
+
+    let mut prices = Vec::with_capacity(n);
+    let mut price = base;
+    for _ in 0..n {
+        let delta = normal.sample(&mut rng);
+        price += delta;
+        prices.push((price * 100.0).round() / 100.0); // round to 2 decimals
+    }
+
+    prices
+}
+
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
-    use crate::symbol_aggregator::SymbolAggregator;
-    use std::mem;
+    use crate::api::Quantile;
+    use crate::returns::ReturnKind;
+    use crate::symbol_aggregator::{AggregatorOptions, StatsOptions, SymbolAggregator};
+    use crate::windows::{Span, Windows};
 
     #[test]
     fn test_small_stats() {
-        let mut agg: SymbolAggregator<4, 2> = SymbolAggregator::new();
+        let mut agg = SymbolAggregator::new(Windows::geometric(4, 2));
         agg.add_batch(&[1.0, 2.0, 3.0, 4.0, 5.0]);
 
         // two last elems
@@ -45,7 +65,7 @@ mod tests {
     fn test_eviction() {
         // tracing_subscriber::fmt::init();
 
-        let mut agg: SymbolAggregator<3, 2> = SymbolAggregator::new();
+        let mut agg = SymbolAggregator::new(Windows::geometric(3, 2));
         agg.add_batch(&[3., 1., 2., 4., 5.]);
 
         // 2 last elems
@@ -96,7 +116,7 @@ mod tests {
         assert_eq!(stats.max, 9.0);
         assert_eq!(stats.last, 9.0);
         assert_eq!(stats.avg, 4.285714285714286);
-        assert_eq!(stats.var, 6.204081632653065);
+        assert_eq!(stats.var, 6.204081632653061);
 
         // `1.0` should now be evicted from all buffers
         agg.add_batch(&[5., 6., 7.]);
@@ -153,9 +173,191 @@ mod tests {
         assert_eq!(stats.var, 2.4375);
     }
 
+    #[test]
+    fn test_custom_windows() {
+        let windows = Windows::new(["5", "fast=3"].map(|s| s.parse().unwrap())).unwrap();
+        let mut agg = SymbolAggregator::new(windows);
+        agg.add_batch(&[7., 1., 2., 3., 4., 5.]);
+
+        // 3 last elems
+        assert_eq!(agg.windows().level_of("fast"), Some(0));
+        let stats = agg.get_stats(1).unwrap();
+        assert_eq!(stats.min, 3.0);
+        assert_eq!(stats.max, 5.0);
+        assert_eq!(stats.last, 5.0);
+        assert_eq!(stats.avg, 4.0);
+
+        // 5 last elems, `7.0` evicted
+        let stats = agg.get_stats(2).unwrap();
+        assert_eq!(stats.min, 1.0);
+        assert_eq!(stats.max, 5.0);
+        assert_eq!(stats.last, 5.0);
+        assert_eq!(stats.avg, 3.0);
+        assert_eq!(stats.var, 2.0);
+
+        assert!(agg.get_stats(3).is_none());
+    }
+
+    #[test]
+    fn test_exact_quantiles() {
+        let windows = Windows::new(["5", "2000", "4000"].map(|s| s.parse().unwrap())).unwrap();
+        let options = AggregatorOptions {
+            exact_quantiles_max_window: 2000,
+            approx_quantiles: false,
+            ..AggregatorOptions::default()
+        };
+        let mut agg = SymbolAggregator::with_options(windows, options);
+        // the ring wraps, so early values are evicted from every level
+        let values: Vec<f64> = (0..5000).map(|i| ((i * 7919) % 5000) as f64).collect();
+        agg.add_batch(&values);
+
+        let mut last = values[values.len() - 5..].to_vec();
+        last.sort_by(f64::total_cmp);
+        let stats = agg.get_stats(1).unwrap();
+        assert_eq!(stats.median, last[2]);
+        assert_eq!(stats.p25, last[1]);
+        assert_eq!(stats.p75, last[3]);
+        assert_eq!(stats.p5, last[0] + (last[1] - last[0]) * 0.2);
+
+        let mut last = values[values.len() - 2000..].to_vec();
+        last.sort_by(f64::total_cmp);
+        let options = StatsOptions {
+            quantiles: vec![0.0, 0.125, 1.0],
+            ..StatsOptions::default()
+        };
+        let stats = agg.get_stats_with(2, &options).unwrap();
+        assert_eq!(stats.median, (last[999] + last[1000]) / 2.0);
+        assert_eq!(
+            stats.quantiles,
+            vec![
+                Quantile {
+                    q: 0.0,
+                    value: stats.min
+                },
+                Quantile {
+                    q: 0.125,
+                    value: last[249] + (last[250] - last[249]) * 0.875
+                },
+                Quantile {
+                    q: 1.0,
+                    value: stats.max
+                },
+            ]
+        );
+
+        // too big to be exact
+        let stats = agg.get_stats_with(3, &options).unwrap();
+        assert!(stats.median.is_nan());
+        assert!(stats.rank_error.is_nan());
+        assert!(
+            stats
+                .quantiles
+                .iter()
+                .all(|quantile| quantile.value.is_nan())
+        );
+    }
+
+    /// Distance of `estimate` from rank of quantile `q` among sorted `values`, as a fraction.
+    fn rank_error(values: &[f64], q: f64, estimate: f64) -> f64 {
+        let rank = (q * values.len() as f64).ceil().max(1.0);
+        let lower = values.partition_point(|&v| v < estimate) as f64 + 1.0;
+        let upper = values.partition_point(|&v| v <= estimate) as f64;
+        (lower - rank).max(rank - upper).max(0.0) / values.len() as f64
+    }
+
+    #[test]
+    fn test_approx_quantiles() {
+        let windows = Windows::new(["100", "50000", "1m"].map(|s| s.parse().unwrap())).unwrap();
+        let options = AggregatorOptions {
+            exact_quantiles_max_window: 100,
+            approx_quantiles: true,
+            ..AggregatorOptions::default()
+        };
+        let mut agg = SymbolAggregator::with_options(windows, options);
+        let values: Vec<f64> = (0..120_000)
+            .map(|i| ((i * 7919) % 100_003) as f64)
+            .collect();
+        let timestamps: Vec<u64> = (0..values.len() as u64).collect();
+        agg.add_batch_at(&values, Some(&timestamps));
+
+        let options = StatsOptions {
+            quantiles: vec![0.01, 0.99],
+            ..StatsOptions::default()
+        };
+        let stats = agg.get_stats_with(1, &options).unwrap();
+        assert_eq!(stats.rank_error, 0.0);
+
+        // the count window, whose oldest bucket is partially evicted
+        let stats = agg.get_stats_with(2, &options).unwrap();
+        assert!(stats.rank_error > 0.0 && stats.rank_error < 0.03);
+        let mut window = values[values.len() - 50_000..].to_vec();
+        window.sort_by(f64::total_cmp);
+        for (q, estimate) in [(0.5, stats.median), (0.05, stats.p5), (0.95, stats.p95)] {
+            assert!(rank_error(&window, q, estimate) <= stats.rank_error);
+        }
+        for quantile in &stats.quantiles {
+            assert!(rank_error(&window, quantile.q, quantile.value) <= stats.rank_error);
+        }
+
+        // the time window, i.e. the last `60_000` values, one per millisecond
+        let stats = agg.get_stats_with(3, &options).unwrap();
+        assert!(stats.rank_error > 0.0 && stats.rank_error < 0.03);
+        let mut window = values[values.len() - 60_000..].to_vec();
+        window.sort_by(f64::total_cmp);
+        assert!(rank_error(&window, 0.5, stats.median) <= stats.rank_error);
+    }
+
+    #[test]
+    fn test_time_windows() {
+        let windows = Windows::new(["100", "1s", "1m"].map(|s| s.parse().unwrap())).unwrap();
+        let mut agg = SymbolAggregator::new(windows);
+        agg.add_batch_at(&[9., 1., 2.], Some(&[0, 30_000, 30_500]));
+        agg.add_batch_at(&[3., 4.], Some(&[59_900, 60_000]));
+
+        // `(59s, 60s]`
+        assert_eq!(agg.windows().level_of("1s"), Some(1));
+        let stats = agg.get_stats(2).unwrap();
+        assert_eq!(stats.min, 3.0);
+        assert_eq!(stats.max, 4.0);
+        assert_eq!(stats.last, 4.0);
+        assert_eq!(stats.avg, 3.5);
+        assert_eq!(stats.var, 0.25);
+
+        // `(0s, 60s]`, `9.0` is just out
+        let stats = agg.get_stats(3).unwrap();
+        assert_eq!(stats.min, 1.0);
+        assert_eq!(stats.max, 4.0);
+        assert_eq!(stats.avg, 2.5);
+        assert_eq!(stats.var, 1.25);
+
+        // time passes without values
+        let options = StatsOptions {
+            now: 91_000,
+            ..StatsOptions::default()
+        };
+        let stats = agg.get_stats_with(3, &options).unwrap();
+        assert_eq!(stats.min, 3.0);
+        assert_eq!(stats.avg, 3.5);
+        let stats = agg.get_stats_with(2, &options).unwrap();
+        assert!(stats.avg.is_nan());
+        assert!(stats.min.is_nan());
+
+        // late value is counted at the latest time seen, `91s`
+        agg.add_batch_at(&[5.], Some(&[61_000]));
+        let stats = agg.get_stats(2).unwrap();
+        assert_eq!(stats.min, 5.0);
+        assert_eq!(stats.avg, 5.0);
+
+        // count windows are not affected by time
+        let stats = agg.get_stats(1).unwrap();
+        assert_eq!(stats.min, 1.0);
+        assert_eq!(stats.max, 9.0);
+        assert_eq!(stats.avg, 4.0);
+    }
+
     #[test]
     fn test_inf_values_skipped() {
-        let mut agg: SymbolAggregator<2, 2> = SymbolAggregator::new();
+        let mut agg = SymbolAggregator::new(Windows::geometric(2, 2));
         agg.add_batch(&[1e200, 1., 2.]);
 
         // two last elems
@@ -177,7 +379,7 @@ mod tests {
 
     #[test]
     fn test_inf_variance() {
-        let mut agg: SymbolAggregator<2, 2> = SymbolAggregator::new();
+        let mut agg = SymbolAggregator::new(Windows::geometric(2, 2));
         agg.add_batch(&[1e154, -1e154]);
 
         // two last elems
@@ -191,7 +393,9 @@ mod tests {
         let seriaized_stats = serde_json::ser::to_string(&stats).unwrap();
         assert_eq!(
             seriaized_stats,
-            "{\"min\":1e154,\"max\":1e154,\"last\":1e154,\"avg\":1e154,\"var\":0.0}"
+            "{\"min\":1e154,\"max\":1e154,\"last\":1e154,\"avg\":1e154,\"var\":0.0,\
+             \"median\":1e154,\"p5\":1e154,\"p25\":1e154,\"p75\":1e154,\"p95\":1e154,\
+             \"rank_error\":0.0}"
         );
 
         // full set
@@ -207,7 +411,7 @@ mod tests {
     fn test_max_variance() {
         // tracing_subscriber::fmt::init();
 
-        let mut agg: SymbolAggregator<2, 2> = SymbolAggregator::new();
+        let mut agg = SymbolAggregator::new(Windows::geometric(2, 2));
         agg.add_batch(&[1e153, -1e153, 1e153]);
 
         // two last elems
@@ -229,7 +433,7 @@ mod tests {
 
     #[test]
     fn test_skip_too_big_value_and_second() {
-        let mut agg: SymbolAggregator<8, 2> = SymbolAggregator::new();
+        let mut agg = SymbolAggregator::new(Windows::geometric(8, 2));
         let data = [
             f64::MAX, // this will be skipped
             1e154,    // this will be handled normally
@@ -508,9 +712,463 @@ mod tests {
         assert_eq!(stats.var, 2610076991714.1025);
     }
 
+    /// Exact `avg` and `var` of values `units / scale`, rounded once.
+    fn reference_stats(units: &[i64], scale: f64) -> (f64, f64) {
+        let n = units.len() as i128;
+        let sum: i128 = units.iter().map(|&u| u as i128).sum();
+        let sum_sq: i128 = units.iter().map(|&u| u as i128 * u as i128).sum();
+        let avg = sum as f64 / n as f64 / scale;
+        let var = (n * sum_sq - sum * sum) as f64 / (n * n) as f64 / (scale * scale);
+        (avg, var)
+    }
+
+    /// Feeds values `units / scale`, one per millisecond, in batches, checking `avg` and `var`
+    /// of windows `10`, `1000` and `1s` against exact ones after every batch.
+    fn check_variance(units: &[i64], scale: f64) {
+        let windows = Windows::new(["10", "1000", "1s"].map(|s| s.parse().unwrap())).unwrap();
+        let options = AggregatorOptions {
+            exact_quantiles_max_window: 0,
+            approx_quantiles: false,
+            ..AggregatorOptions::default()
+        };
+        let mut agg = SymbolAggregator::with_options(windows, options);
+        for (i, batch) in units.chunks(250).enumerate() {
+            let values: Vec<f64> = batch.iter().map(|&u| u as f64 / scale).collect();
+            let start = (i * 250) as u64;
+            let timestamps: Vec<u64> = (start..start + batch.len() as u64).collect();
+            agg.add_batch_at(&values, Some(&timestamps));
+
+            let end = start as usize + batch.len();
+            for (k, len) in [(1, 10), (2, 1000), (3, 1000)] {
+                let (avg, var) = reference_stats(&units[end.saturating_sub(len)..end], scale);
+                let stats = agg.get_stats(k).unwrap();
+                assert!(
+                    (stats.avg - avg).abs() <= 1e-13 * avg.abs(),
+                    "avg of level {k} after {end} values: {} != {avg}",
+                    stats.avg
+                );
+                assert!(
+                    (stats.var - var).abs() <= 1e-9 * var,
+                    "var of level {k} after {end} values: {} != {var}",
+                    stats.var
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_stable_variance_of_prices() {
+        // `10_000.01` moving by cents, where `E[x²] - E[x]²` loses all digits
+        let mut cents = 1_000_001;
+        let units: Vec<i64> = (0..20_000)
+            .map(|i| {
+                cents += (i * 7919) % 3 - 1;
+                cents
+            })
+            .collect();
+        check_variance(&units, 100.0);
+    }
+
     #[test]
+    fn test_stable_variance_far_from_zero() {
+        // `1e9` with noise of `1/1024` steps, which are exact in binary
+        let units: Vec<i64> = (0..20_000)
+            .map(|i| (1 << 40) + (i * 7919) % 1024 - 512)
+            .collect();
+        check_variance(&units, 1024.0);
+    }
+
+    #[test]
+    fn test_stable_variance_of_trend() {
+        // mean drifts away from the first value by far more than values spread, so shift
+        // has to follow it
+        let units: Vec<i64> = (0..20_000)
+            .map(|i| 1_000_000_000 + i * 1_000 + (i * 7919) % 7)
+            .collect();
+        check_variance(&units, 100.0);
+
+        // and jumps back after a spike
+        let mut units = vec![1_000_000_000_000; 2_000];
+        units.extend((0..5_000).map(|i| 1_000_001 + (i * 7919) % 2));
+        check_variance(&units, 100.0);
+    }
+
+    #[test]
+    fn test_constant_variance_is_zero() {
+        let windows = Windows::new(["10", "1000", "1s"].map(|s| s.parse().unwrap())).unwrap();
+        let mut agg = SymbolAggregator::new(windows);
+        let timestamps: Vec<u64> = (0..3_000).collect();
+        let mut values = vec![1e6 + 0.1; 1_000];
+        values.extend([10_000.01; 2_000]);
+        for (batch, timestamps) in values.chunks(100).zip(timestamps.chunks(100)) {
+            agg.add_batch_at(batch, Some(timestamps));
+            for k in 1..=3 {
+                assert!(agg.get_stats(k).unwrap().var >= 0.0);
+            }
+        }
+        for k in 1..=3 {
+            let stats = agg.get_stats(k).unwrap();
+            assert_eq!(stats.avg, 10_000.01);
+            assert_eq!(stats.var, 0.0);
+        }
+    }
+
+    /// Population skewness and excess kurtosis of `values`, by two passes.
+    fn reference_moments(values: &[f64]) -> (f64, f64) {
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let central = |p| values.iter().map(|v| (v - mean).powi(p)).sum::<f64>() / n;
+        let m2 = central(2);
+        (central(3) / m2.powf(1.5), central(4) / (m2 * m2) - 3.0)
+    }
+
+    #[test]
+    fn test_moments() {
+        let windows = Windows::new(["5", "10", "1s"].map(|s| s.parse().unwrap())).unwrap();
+        let mut agg = SymbolAggregator::new(windows);
+        let values = [
+            9., 10_000.02, 10_000.01, 10_000.05, 10_000.01, 10_000.3, 10_000.1,
+        ];
+        agg.add_batch_at(&values, Some(&[0, 1, 2, 3, 4, 5, 6]));
+
+        // opt-in only
+        let stats = agg.get_stats(1).unwrap();
+        assert!(stats.std.is_none() && stats.skew.is_none() && stats.kurtosis.is_none());
+
+        let options = StatsOptions {
+            moments: true,
+            ..StatsOptions::default()
+        };
+        for (k, window) in [(1, &values[2..]), (2, &values[..]), (3, &values[..])] {
+            let stats = agg.get_stats_with(k, &options).unwrap();
+            let (skew, kurtosis) = reference_moments(window);
+            assert_eq!(stats.std, Some(stats.var.sqrt()));
+            assert!((stats.skew.unwrap() - skew).abs() < 1e-9, "{k}: {skew}");
+            assert!(
+                (stats.kurtosis.unwrap() - kurtosis).abs() < 1e-9,
+                "{k}: {kurtosis}"
+            );
+        }
+
+        assert_eq!(
+            agg.get_stats_with(1, &options).unwrap().moments_skipped,
+            Some(0)
+        );
+
+        // fourth power of `1e100` overflows, so the value is kept but skipped by higher moments
+        agg.add_batch_at(&[1e100], Some(&[7]));
+        let stats = agg.get_stats_with(1, &options).unwrap();
+        assert_eq!(stats.max, 1e100);
+        assert!(stats.var.is_finite());
+        assert!(stats.skew.unwrap().is_nan() && stats.kurtosis.unwrap().is_nan());
+        // periodic recentering moves shift close to it, so the others overflow then
+        assert_eq!(stats.moments_skipped, Some(5));
+        // of time windows too
+        let stats = agg.get_stats_with(3, &options).unwrap();
+        assert!(stats.skew.unwrap().is_nan());
+        assert_eq!(stats.moments_skipped, Some(8));
+
+        // and moments are back once it leaves the window
+        let values = [1., 2., 3., 4., 10.];
+        agg.add_batch_at(&values, Some(&[8, 9, 10, 11, 12]));
+        let stats = agg.get_stats_with(1, &options).unwrap();
+        let (skew, kurtosis) = reference_moments(&values);
+        assert!((stats.skew.unwrap() - skew).abs() < 1e-12);
+        assert!((stats.kurtosis.unwrap() - kurtosis).abs() < 1e-12);
+        assert_eq!(stats.moments_skipped, Some(0));
+
+        // all values equal
+        agg.add_batch_at(&[5.; 5], Some(&[13, 14, 15, 16, 17]));
+        let stats = agg.get_stats_with(1, &options).unwrap();
+        assert_eq!(stats.std, Some(0.0));
+        assert!(stats.skew.unwrap().is_nan() && stats.kurtosis.unwrap().is_nan());
+    }
+
+    #[test]
+    fn test_ewma() {
+        let windows = Windows::new(["2", "1s"].map(|s| s.parse().unwrap())).unwrap();
+        let options = AggregatorOptions {
+            ewma_half_lives: vec![Span::Count(1), Span::Time(1000)],
+            ..AggregatorOptions::default()
+        };
+        let mut agg = SymbolAggregator::with_options(windows, options);
+        agg.add_batch_at(&[4., 1.], Some(&[0, 0]));
+        agg.add_batch_at(&[7.], Some(&[1000]));
+
+        // same for every window
+        for k in 1..=2 {
+            let stats = agg.get_stats(k).unwrap();
+            assert_eq!(stats.ewma.len(), 2);
+
+            // weights `0.25`, `0.5` and `1`
+            assert_eq!(stats.ewma[0].half_life, "1");
+            assert!((stats.ewma[0].avg - 8.5 / 1.75).abs() < 1e-15);
+            // weights `0.5`, `0.5` and `1`
+            assert_eq!(stats.ewma[1].half_life, "1s");
+            assert_eq!(stats.ewma[1].avg, 4.75);
+            assert_eq!(stats.ewma[1].var, 6.1875);
+        }
+
+        // not in responses without half-lives
+        let mut agg = SymbolAggregator::new(Windows::geometric(2, 2));
+        agg.add_batch(&[1.]);
+        let json = serde_json::to_string(&agg.get_stats(1).unwrap()).unwrap();
+        assert!(!json.contains("ewma"));
+    }
+
+    #[test]
+    fn test_weighted() {
+        let windows = Windows::new(["3", "1s"].map(|s| s.parse().unwrap())).unwrap();
+        let mut agg = SymbolAggregator::new(windows);
+        agg.add_batch_at(&[10.], Some(&[0]));
+        // not in responses until the first weights
+        let json = serde_json::to_string(&agg.get_stats(1).unwrap()).unwrap();
+        assert!(!json.contains("vwap") && !json.contains("volume"));
+
+        // the value before weighs `1`
+        agg.add_batch_with(&[20., 30.], Some(&[0, 500]), Some(&[3., 0.]));
+        let stats = agg.get_stats(1).unwrap();
+        assert_eq!(stats.vwap, Some(17.5));
+        assert_eq!(stats.weighted_var, Some(18.75));
+        assert_eq!(stats.volume, Some(4.));
+
+        // `10` and `20` are evicted from both windows
+        agg.add_batch_with(&[40.], Some(&[1000]), Some(&[2.]));
+        let stats = agg.get_stats(1).unwrap();
+        assert_eq!(stats.vwap, Some(28.));
+        assert_eq!(stats.weighted_var, Some(96.));
+        assert_eq!(stats.volume, Some(5.));
+        let stats = agg.get_stats(2).unwrap();
+        assert_eq!(stats.vwap, Some(40.));
+        assert_eq!(stats.weighted_var, Some(0.));
+        assert_eq!(stats.volume, Some(2.));
+
+        // values without weights weigh `1`
+        agg.add_batch_at(&[50.], Some(&[1000]));
+        let stats = agg.get_stats(1).unwrap();
+        assert!((stats.vwap.unwrap() - 130. / 3.).abs() < 1e-12);
+        assert_eq!(stats.volume, Some(3.));
+
+        // empty time window has no volume
+        let options = StatsOptions {
+            now: 5000,
+            ..StatsOptions::default()
+        };
+        let stats = agg.get_stats_with(2, &options).unwrap();
+        assert!(stats.vwap.unwrap().is_nan());
+        assert_eq!(stats.volume, Some(0.));
+    }
+
+    fn returns_agg(windows: &[&str], kind: ReturnKind) -> SymbolAggregator {
+        let windows = Windows::new(windows.iter().map(|s| s.parse().unwrap())).unwrap();
+        let options = AggregatorOptions {
+            returns: Some(kind),
+            ..AggregatorOptions::default()
+        };
+        SymbolAggregator::with_options(windows, options)
+    }
+
+    const RETURNS: StatsOptions = StatsOptions {
+        now: 0,
+        quantiles: Vec::new(),
+        moments: false,
+        returns: true,
+        periods_per_year: 4.0,
+    };
+
+    #[test]
+    fn test_returns() {
+        let mut agg = returns_agg(&["3", "5"], ReturnKind::Simple);
+        agg.add_batch(&[100., 110., 99., 99., 198.]);
+
+        // returns of `99` and `198`, but not of the oldest `99`
+        let returns = agg.get_stats_with(1, &RETURNS).unwrap().returns.unwrap();
+        assert_eq!(returns.count, 2);
+        assert_eq!((returns.avg, returns.var), (0.5, 0.25));
+        assert_eq!((returns.min, returns.max), (0., 1.));
+        // annualized by `sqrt(4)`
+        assert_eq!(returns.volatility, 0.5f64.sqrt() * 2.);
+        assert_eq!(returns.sharpe, 2.);
+
+        let returns = agg.get_stats_with(2, &RETURNS).unwrap().returns.unwrap();
+        assert_eq!(returns.count, 4);
+        assert!((returns.avg - 0.25).abs() < 1e-15);
+        assert!((returns.min + 0.1).abs() < 1e-15);
+        assert_eq!(returns.max, 1.);
+
+        // not requested
+        assert!(agg.get_stats(1).unwrap().returns.is_none());
+    }
+
+    #[test]
+    fn test_undefined_returns() {
+        let mut agg = returns_agg(&["3", "1s"], ReturnKind::Log);
+        agg.add_batch_at(&[1., -1., 2., 4.], Some(&[0; 4]));
+
+        // neither `-1` nor `2` after it have log returns
+        let returns = agg.get_stats_with(1, &RETURNS).unwrap().returns.unwrap();
+        assert_eq!(returns.count, 1);
+        assert_eq!(returns.avg, 2f64.ln());
+        assert_eq!((returns.min, returns.max), (2f64.ln(), 2f64.ln()));
+        assert!(returns.sharpe.is_infinite());
+
+        // time windows do not maintain returns
+        assert!(agg.get_stats_with(2, &RETURNS).unwrap().returns.is_none());
+    }
+
+    #[test]
+    fn test_returns_of_sliding_windows() {
+        let mut agg = returns_agg(&["1", "2", "7", "20"], ReturnKind::Log);
+        let mut values = Vec::new();
+        let mut state = 7u64;
+        for batch in 0..30 {
+            let batch: Vec<f64> = (0..batch % 4 + 1)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    100.0 + (state >> 11) as f64 / (1u64 << 53) as f64
+                })
+                .collect();
+            agg.add_batch(&batch);
+            values.extend(batch);
+
+            for (k, size) in [1, 2, 7, 20].into_iter().enumerate() {
+                let window = &values[values.len().saturating_sub(size)..];
+                let expected: Vec<f64> = window.windows(2).map(|w| (w[1] / w[0]).ln()).collect();
+                let returns = agg
+                    .get_stats_with(k as u32 + 1, &RETURNS)
+                    .unwrap()
+                    .returns
+                    .unwrap();
+                assert_eq!(returns.count, expected.len());
+                if expected.is_empty() {
+                    assert!(returns.avg.is_nan() && returns.min.is_nan());
+                    continue;
+                }
+                let n = expected.len() as f64;
+                let avg = expected.iter().sum::<f64>() / n;
+                let var = expected.iter().map(|r| (r - avg).powi(2)).sum::<f64>() / n;
+                assert!(
+                    (returns.avg - avg).abs() < 1e-15,
+                    "{k}: {} {avg}",
+                    returns.avg
+                );
+                assert!(
+                    (returns.var - var).abs() < 1e-15,
+                    "{k}: {} {var}",
+                    returns.var
+                );
+                let min = expected.iter().copied().fold(f64::INFINITY, f64::min);
+                let max = expected.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                assert_eq!(
+                    (returns.min, returns.max),
+                    (min, max),
+                    "{k} {}",
+                    values.len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_all_stats() {
+        let windows = Windows::new(["2", "4", "1s"].map(|s| s.parse().unwrap())).unwrap();
+        let mut agg = SymbolAggregator::new(windows);
+        assert!(agg.get_all_stats_with(&StatsOptions::default()).is_none());
+
+        agg.add_batch_at(&[1.0, 2.0, 3.0, 4.0, 5.0], Some(&[0, 0, 500, 1000, 1500]));
+        let options = StatsOptions {
+            now: 1500,
+            ..StatsOptions::default()
+        };
+        let all = agg.get_all_stats_with(&options).unwrap();
+        assert_eq!(all.keys().copied().collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!((all[&1].avg, all[&2].avg, all[&3].avg), (4.5, 3.5, 4.5));
+        for (k, stats) in all {
+            let single = agg.get_stats_with(k, &options).unwrap();
+            assert_eq!(
+                (stats.min, stats.max, stats.var),
+                (single.min, single.max, single.var)
+            );
+        }
+    }
+
+    #[test]
+    fn test_candles() {
+        let windows = Windows::new(["2", "4", "1s"].map(|s| s.parse().unwrap())).unwrap();
+        let mut agg = SymbolAggregator::new(windows);
+        agg.add_batch_at(
+            &[3., 1., 4., 1., 5.],
+            Some(&[1_100, 1_200, 1_900, 2_000, 2_500]),
+        );
+        agg.add_batch_with(&[9.], Some(&[2_700]), Some(&[4.]));
+
+        // blocks of `2` values: `[3, 1]`, `[4, 1]` and `[5, 9]`, all closed
+        let candles = agg.candles(1, 0).unwrap();
+        let closed: Vec<_> = candles.closed(usize::MAX).map(|c| c.close).collect();
+        assert_eq!(closed, vec![1., 1., 9.]);
+        assert!(candles.forming().is_none());
+        let last = candles.closed(1).next().unwrap();
+        assert_eq!(
+            (last.start, last.open, last.high, last.low),
+            (4, 5., 9., 5.)
+        );
+        assert_eq!((last.count, last.volume), (2, 5.));
+
+        // `[3, 1, 4, 1]` closed, `[5, 9]` forming
+        let candles = agg.candles(2, 0).unwrap();
+        assert_eq!(candles.closed(usize::MAX).count(), 1);
+        let forming = candles.forming().unwrap();
+        assert_eq!((forming.start, forming.high, forming.count), (4, 9., 2));
+
+        // `[1s, 2s)` closed, `[2s, 3s)` forming until `3s`
+        let candles = agg.candles(3, 0).unwrap();
+        let closed: Vec<_> = candles.closed(usize::MAX).collect();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(
+            (closed[0].start, closed[0].open, closed[0].close),
+            (1_000, 3., 4.)
+        );
+        assert_eq!(candles.forming().unwrap().volume, 6.);
+        let candles = agg.candles(3, 3_000).unwrap();
+        assert!(candles.forming().is_none());
+        assert_eq!(candles.closed(usize::MAX).count(), 2);
+
+        assert!(agg.candles(0, 0).is_none());
+        assert!(agg.candles(4, 0).is_none());
+    }
+
+    #[test]
+    fn test_min_max_of_small_batches() {
+        let mut agg = SymbolAggregator::new(Windows::geometric(3, 3));
+        let mut values = Vec::new();
+        let mut state = 11u64;
+        for batch in 0..60 {
+            let batch: Vec<f64> = (0..batch % 4 + 1)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    (state >> 54) as f64
+                })
+                .collect();
+            agg.add_batch(&batch);
+            values.extend(batch);
+
+            // a new best value evicting entries before the cached best of a lower level,
+            // followed by more values in the same batch, must not leave that cache stale
+            for (k, size) in [3, 9, 27].into_iter().enumerate() {
+                let window = &values[values.len().saturating_sub(size)..];
+                let stats = agg.get_stats(k as u32 + 1).unwrap();
+                let min = window.iter().copied().fold(f64::INFINITY, f64::min);
+                let max = window.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+                assert_eq!((stats.min, stats.max), (min, max), "{k} {}", values.len());
+            }
+        }
+    }
+
+    #[cfg(feature = "test")]
+    #[test]
+    #[allow(clippy::approx_constant)]
     fn test_big_stats() {
-        let mut agg: SymbolAggregator<8, 2> = SymbolAggregator::new();
+        let mut agg = SymbolAggregator::new(Windows::geometric(8, 2));
         let data = super::generate_random_data(257, 3.14, 271.72, 457325.);
         agg.add_batch(&data);
 
@@ -522,7 +1180,7 @@ mod tests {
         assert_eq!(stats.last, last);
 
         if last < sec2last {
-            mem::swap(&mut last, &mut sec2last);
+            std::mem::swap(&mut last, &mut sec2last);
         }
 
         assert_eq!(stats.min, sec2last);
@@ -535,25 +1193,8 @@ mod tests {
         // first should be skipped
         let d = &data[1..];
         println!("{data:?}");
-        assert_eq!(stats.min, d.to_vec().into_iter().reduce(f64::min).unwrap());
-        assert_eq!(stats.max, d.to_vec().into_iter().reduce(f64::max).unwrap());
+        assert_eq!(stats.min, d.iter().copied().reduce(f64::min).unwrap());
+        assert_eq!(stats.max, d.iter().copied().reduce(f64::max).unwrap());
         assert_eq!(stats.last, data[data.len().wrapping_sub(1)]);
     }
 }
-
-#[cfg(feature = "test")]
-pub fn generate_random_data(n: usize, base: f64, drift: f64, volatility: f64) -> Vec<f64> {
-    use rand_distr::{Distribution, Normal};
-    let mut rng = rand::rng();
-    let normal = Normal::new(drift, volatility).unwrap();
-
-    let mut prices = Vec::with_capacity(n);
-    let mut price = base;
-    for _ in 0..n {
-        let delta = normal.sample(&mut rng);
-        price += delta;
-        prices.push((price * 100.0).round() / 100.0); // round to 2 decimals
-    }
-
-    prices
-}
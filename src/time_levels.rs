@@ -0,0 +1,442 @@
+//! Time-based levels of a symbol, e.g. values of the last `1s`, `1m` or `1h`.
+use crate::api::StatsResult;
+use crate::quantile_sketch::{BUCKETS, SlidingQuantiles};
+use crate::shared_monotonic_queue::{MaxCmp, MinCmp, SharedMonotonicQueue};
+use crate::snapshot::{Decoder, Encoder, Persist, SnapshotError};
+use crate::symbol_aggregator::StatsOptions;
+use crate::variance::{ShiftedSums, WeightedSums};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+/// Sums of values in the last `duration` milliseconds, for `avg` and `var`.
+struct TimeLevelStats {
+    /// for debug
+    id: usize,
+    /// length of the level window in milliseconds
+    duration: u64,
+    /// absolute position of the oldest value in this level
+    start: u64,
+    sums: ShiftedSums,
+    /// weighted sums, if levels have weights
+    weighted: Option<WeightedSums>,
+    /// sketches for approximate quantiles, bucketed by time
+    sketch: Option<SlidingQuantiles>,
+}
+
+impl TimeLevelStats {
+    /// Milliseconds of values sketched together.
+    fn bucket_span(&self) -> u64 {
+        (self.duration / BUCKETS).max(1)
+    }
+}
+
+/// Values of the longest time window with their timestamps, shared by all time levels,
+/// just like the ring is shared by count levels.
+///
+/// Unlike a count level, a time level shrinks even without new values, so levels are
+/// advanced to the current time on every query, as well as after every batch.
+/// Each level keeps position of its oldest value, so advancing subtracts every value once.
+///
+/// `min` and `max` use the same [`SharedMonotonicQueue`] as count levels,
+/// indexed by timestamps instead of logical indexes.
+///
+/// Time never goes back: values with timestamps older than the latest seen time
+/// are treated as if they came at that time.
+pub struct TimeLevels {
+    /// values with timestamps, oldest first
+    values: VecDeque<(u64, f64)>,
+    /// weights parallel to `values`, since the first weighted batch
+    weights: Option<VecDeque<f64>>,
+    /// absolute position of the front of `values`
+    offset: u64,
+    /// latest time seen, by a value or a query
+    now: u64,
+    /// shortest first
+    levels: Vec<TimeLevelStats>,
+    minq: SharedMonotonicQueue<MinCmp>,
+    maxq: SharedMonotonicQueue<MaxCmp>,
+    /// smallest indexes of `minq` and `maxq` entries evicted by pushes since the last advance
+    minq_evicted_idx: Option<usize>,
+    maxq_evicted_idx: Option<usize>,
+}
+
+impl TimeLevels {
+    /// Creates levels for given non-empty durations in milliseconds, sorted ascending,
+    /// with quantile sketches if `approx_quantiles` is set.
+    pub fn new(durations: impl IntoIterator<Item = u64>, approx_quantiles: bool) -> Self {
+        let levels: Vec<TimeLevelStats> = durations
+            .into_iter()
+            .enumerate()
+            .map(|(id, duration)| TimeLevelStats {
+                id,
+                duration,
+                start: 0,
+                sums: ShiftedSums::default(),
+                weighted: None,
+                sketch: approx_quantiles.then(SlidingQuantiles::new),
+            })
+            .collect();
+        let durations: Vec<u64> = levels.iter().map(|level| level.duration).collect();
+        Self {
+            values: VecDeque::new(),
+            weights: None,
+            offset: 0,
+            now: 0,
+            levels,
+            minq: SharedMonotonicQueue::new(durations.iter().copied()),
+            maxq: SharedMonotonicQueue::new(durations),
+            minq_evicted_idx: None,
+            maxq_evicted_idx: None,
+        }
+    }
+
+    /// Latest time seen, in milliseconds since unix epoch.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Sets up weights and weighted sums of all levels, with weight `1` for all values so far.
+    pub fn enable_weights(&mut self) {
+        self.weights = Some(VecDeque::from(vec![1.0; self.values.len()]));
+        for level in self.levels.iter_mut() {
+            let values = self.values.range((level.start - self.offset) as usize..);
+            let mut weighted = WeightedSums::default();
+            weighted.recenter(values.map(|&(_, val)| (val, 1.0)));
+            level.weighted = Some(weighted);
+        }
+    }
+
+    /// Whether levels have weights, see [`Self::enable_weights`].
+    pub fn is_weighted(&self) -> bool {
+        self.weights.is_some()
+    }
+
+    /// Whether shifted sums of squares of all levels, and weighted sums, stay finite
+    /// after adding `val` of `weight`, recentering levels whose shift is too far from it.
+    ///
+    /// Overflowing higher powers do not make `val` too big, see [`ShiftedSums::fits`].
+    pub fn fits(&mut self, val: f64, weight: f64) -> bool {
+        let end = self.offset + self.values.len() as u64;
+        for level in self.levels.iter_mut() {
+            let start = (level.start - self.offset) as usize;
+            if !level.sums.fits(val) {
+                let values = self.values.range(start..);
+                level.sums.recenter(values.map(|&(_, val)| val));
+                if !level.sums.sum_sq_with(val).is_finite() {
+                    tracing::debug!(
+                        "{val} overflows time level {} of {} values",
+                        level.id,
+                        end - level.start
+                    );
+                    return false;
+                }
+            }
+            if let (Some(weighted), Some(weights)) = (&mut level.weighted, &self.weights)
+                && !weighted.fits(val, weight)
+            {
+                let values = self.values.range(start..).map(|&(_, val)| val);
+                weighted.recenter(values.zip(weights.range(start..).copied()));
+                if !weighted.fits(val, weight) {
+                    tracing::debug!(
+                        "{val} of weight {weight} overflows weighted sums of time level {}",
+                        level.id
+                    );
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Pushes value of `weight` observed at `timestamp` to all levels.
+    ///
+    /// Too old values are evicted by the next [`TimeLevels::advance`],
+    /// which must be called after each batch.
+    pub fn push(&mut self, timestamp: u64, val: f64, weight: f64) {
+        self.now = self.now.max(timestamp);
+        let position = self.offset + self.values.len() as u64;
+        for level in self.levels.iter_mut() {
+            let count = (position - level.start) as usize;
+            level.sums.push(val, count);
+            if let Some(weighted) = &mut level.weighted {
+                weighted.push(val, weight, count);
+            }
+            let bucket = self.now / level.bucket_span();
+            if let Some(sketch) = &mut level.sketch {
+                sketch.push(bucket, position, val);
+            }
+        }
+        self.values.push_back((self.now, val));
+        if let Some(weights) = &mut self.weights {
+            weights.push_back(weight);
+        }
+        self.minq.push(self.now, val, &mut self.minq_evicted_idx);
+        self.maxq.push(self.now, val, &mut self.maxq_evicted_idx);
+    }
+
+    /// Moves all levels to time `now`, evicting values which are too old.
+    ///
+    /// Level of duration `d` covers values with timestamps in `(now - d, now]`.
+    pub fn advance(&mut self, now: u64) {
+        self.now = self.now.max(now);
+        let now = self.now;
+
+        // monotonic queues keep entries with indexes in `[current - d, current)`
+        self.minq.evict(now + 1, self.minq_evicted_idx.take());
+        self.maxq.evict(now + 1, self.maxq_evicted_idx.take());
+
+        let end = self.offset + self.values.len() as u64;
+        for level in self.levels.iter_mut() {
+            while level.start < end {
+                let i = (level.start - self.offset) as usize;
+                let (timestamp, val) = self.values[i];
+                if timestamp.saturating_add(level.duration) > now {
+                    break;
+                }
+                tracing::trace!(
+                    "evicting value {val} @ {timestamp} of time level {}",
+                    level.id
+                );
+                level.sums.evict(val);
+                if let (Some(weighted), Some(weights)) = (&mut level.weighted, &self.weights) {
+                    weighted.evict(val, weights[i]);
+                }
+                level.start += 1;
+            }
+            let start = (level.start - self.offset) as usize;
+            let values = self.values.range(start..);
+            if level.sums.should_recenter(values.len()) {
+                level.sums.recenter(values.clone().map(|&(_, val)| val));
+            }
+            if let (Some(weighted), Some(weights)) = (&mut level.weighted, &self.weights)
+                && weighted.should_recenter(values.len())
+            {
+                weighted.recenter(
+                    values
+                        .map(|&(_, val)| val)
+                        .zip(weights.range(start..).copied()),
+                );
+            }
+            if let Some(sketch) = &mut level.sketch {
+                sketch.evict(level.start);
+            }
+        }
+
+        // the longest level has the oldest values
+        let oldest = self.levels[self.levels.len() - 1].start;
+        while self.offset < oldest {
+            self.values.pop_front();
+            if let Some(weights) = &mut self.weights {
+                weights.pop_front();
+            }
+            self.offset += 1;
+        }
+    }
+
+    /// Stats of time level (`0` based) at time `options.now`.
+    ///
+    /// Stats of an empty level are `NaN`, which is `null` in responses.
+    /// Quantiles are approximate, if levels have sketches.
+    pub fn get_stats(&mut self, level: usize, options: &StatsOptions) -> StatsResult {
+        self.advance(options.now);
+        let now = self.now;
+        let end = self.offset + self.values.len() as u64;
+        let stats = &self.levels[level];
+
+        let count = end - stats.start;
+        if count == 0 {
+            let mut stats = StatsResult::default();
+            stats.set_quantiles(&options.quantiles, |_| f64::NAN);
+            if options.moments {
+                stats.set_moments(&ShiftedSums::default(), 0);
+            }
+            if self.weights.is_some() {
+                stats.vwap = Some(f64::NAN);
+                stats.weighted_var = Some(f64::NAN);
+                stats.volume = Some(0.0);
+            }
+            return stats;
+        }
+
+        let avg = stats.sums.avg(count as usize);
+        let var = stats.sums.var(count as usize);
+        tracing::debug!("get_stats: count: {count} for time level: {}", stats.id);
+        let mut stats = StatsResult {
+            min: self
+                .minq
+                .best_or_refresh(level, now + 1)
+                .unwrap_or(f64::NAN),
+            max: self
+                .maxq
+                .best_or_refresh(level, now + 1)
+                .unwrap_or(f64::NAN),
+            last: self.values.back().map_or(f64::NAN, |&(_, val)| val),
+            avg,
+            var,
+            ..StatsResult::default()
+        };
+        let level = &mut self.levels[level];
+        if options.moments {
+            stats.set_moments(&level.sums, count as usize);
+        }
+        if let Some(weighted) = &level.weighted {
+            stats.set_weighted(weighted);
+        }
+        match &mut level.sketch {
+            Some(sketch) => {
+                let summary = sketch.summary(level.start);
+                stats.rank_error = summary.rank_error;
+                stats.set_quantiles(&options.quantiles, |q| summary.quantile(q));
+            }
+            None => stats.set_quantiles(&options.quantiles, |_| f64::NAN),
+        }
+        stats
+    }
+
+    /// Durations of levels in milliseconds.
+    pub fn durations(&self) -> impl Iterator<Item = u64> + '_ {
+        self.levels.iter().map(|level| level.duration)
+    }
+
+    /// Number of entries of both monotonic queues.
+    pub fn monotonic_queue_len(&self) -> usize {
+        self.minq.entries.len() + self.maxq.entries.len()
+    }
+
+    /// Approximate number of bytes allocated by values with weights, levels with sketches
+    /// and monotonic queues.
+    pub fn memory_usage(&self) -> usize {
+        size_of::<Self>()
+            + self.values.capacity() * size_of::<(u64, f64)>()
+            + self.weights.as_ref().map_or(0, VecDeque::capacity) * size_of::<f64>()
+            + self.levels.capacity() * size_of::<TimeLevelStats>()
+            + self
+                .levels
+                .iter()
+                .filter_map(|level| level.sketch.as_ref())
+                .map(SlidingQuantiles::memory_usage)
+                .sum::<usize>()
+            + self.minq.memory_usage()
+            + self.maxq.memory_usage()
+    }
+
+    /// Number of bytes values, with weights if `weighted` or already set up, will additionally
+    /// allocate when `n` more values are pushed, assuming none of them is evicted meanwhile.
+    pub fn estimated_growth(&self, n: usize, weighted: bool) -> usize {
+        let len = self.values.len() + n;
+        let weights_growth = match &self.weights {
+            Some(weights) => len.saturating_sub(weights.capacity()),
+            None if weighted => len,
+            None => 0,
+        };
+        len.saturating_sub(self.values.capacity()) * size_of::<(u64, f64)>()
+            + weights_growth * size_of::<f64>()
+    }
+}
+
+impl Persist for TimeLevels {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.u64(self.now)?;
+        enc.u64(self.offset)?;
+        enc.usize(self.values.len())?;
+        for &(timestamp, val) in &self.values {
+            enc.u64(timestamp)?;
+            enc.f64(val)?;
+        }
+        match &self.weights {
+            Some(weights) => {
+                enc.u8(1)?;
+                weights.iter().try_for_each(|&weight| enc.f64(weight))?;
+            }
+            None => enc.u8(0)?,
+        }
+        enc.usize(self.levels.len())?;
+        for level in &self.levels {
+            enc.u64(level.duration)?;
+            enc.u64(level.start)?;
+            level.sums.encode(enc)?;
+            match &level.weighted {
+                Some(weighted) => {
+                    enc.u8(1)?;
+                    weighted.encode(enc)?;
+                }
+                None => enc.u8(0)?,
+            }
+            match &level.sketch {
+                Some(sketch) => {
+                    enc.u8(1)?;
+                    sketch.encode(enc)?;
+                }
+                None => enc.u8(0)?,
+            }
+        }
+        // evictions pending from pushes are applied by `advance` at the end of every batch
+        self.minq.encode(enc)?;
+        self.maxq.encode(enc)
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        let now = dec.u64()?;
+        let offset = dec.u64()?;
+        let len = dec.usize()?;
+        let mut values = VecDeque::new();
+        for _ in 0..len {
+            values.push_back((dec.u64()?, dec.f64()?));
+        }
+        let weights = match dec.u8()? {
+            0 => None,
+            _ => Some(
+                (0..len)
+                    .map(|_| dec.f64())
+                    .collect::<Result<VecDeque<_>, _>>()?,
+            ),
+        };
+        let end = offset + values.len() as u64;
+
+        let len = dec.usize()?;
+        let mut levels = Vec::new();
+        for id in 0..len {
+            let level = TimeLevelStats {
+                id,
+                duration: dec.u64()?,
+                start: dec.u64()?,
+                sums: ShiftedSums::decode(dec)?,
+                weighted: match dec.u8()? {
+                    0 => None,
+                    _ => Some(WeightedSums::decode(dec)?),
+                },
+                sketch: match dec.u8()? {
+                    0 => None,
+                    _ => Some(SlidingQuantiles::decode(dec)?),
+                },
+            };
+            if !(offset..=end).contains(&level.start) {
+                return Err(SnapshotError::Corrupted(format!(
+                    "time level {id} starts at {}, outside of values {offset}..{end}",
+                    level.start
+                )));
+            }
+            if level.weighted.is_some() != weights.is_some() {
+                return Err(SnapshotError::Corrupted(format!(
+                    "time level {id} weighted sums do not match weights"
+                )));
+            }
+            levels.push(level);
+        }
+        if levels.is_empty() {
+            return Err(SnapshotError::Corrupted("no time levels".into()));
+        }
+
+        Ok(Self {
+            values,
+            weights,
+            offset,
+            now,
+            levels,
+            minq: SharedMonotonicQueue::decode(dec)?,
+            maxq: SharedMonotonicQueue::decode(dec)?,
+            minq_evicted_idx: None,
+            maxq_evicted_idx: None,
+        })
+    }
+}
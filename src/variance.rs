@@ -0,0 +1,558 @@
+//! Numerically stable sums for sliding `avg`, `var` and higher moments, plain or weighted,
+//! and for covariance of pairs.
+use crate::kahan::NeumaierSum;
+use crate::snapshot::{Decoder, Encoder, Persist, SnapshotError};
+use std::io::{self, Read, Write};
+
+/// Shift is recentered when squared distance to the mean exceeds this many variances.
+const MAX_DRIFT: f64 = 16.0;
+
+/// Shift is recentered at most once per `count / RECENTER_PERIOD` pushed values,
+/// so recentering costs amortised `RECENTER_PERIOD` operations per value at most.
+const RECENTER_PERIOD: usize = 16;
+
+/// Shift is recentered right away when squared distance to the mean exceeds this many
+/// variances, i.e. when less than half of the digits of `var` are left. It takes evicting
+/// outliers, e.g. a spike leaving the window, which does not happen on every push.
+const MAX_LOSS: f64 = (1u64 << 26) as f64;
+
+/// Sums of values and their powers up to `4`, shifted by a value close to their mean.
+///
+/// `var = sum_sq / n - avg²` cancels catastrophically when values are far from `0` relative
+/// to their spread, e.g. prices around `10_000.01` moving by cents, and it can even get negative.
+/// Shifted by `K`, the same formula only cancels by how far `K` is from the mean.
+///
+/// Shift is the first value pushed, and it is moved to the mean of the window by
+/// [`ShiftedSums::recenter`] whenever the mean drifts away, or the shifted sum of squares
+/// would overflow. Recentering sums all window values again, so it also drops rounding errors
+/// accumulated by pushes and evictions.
+///
+/// Third and fourth powers overflow much sooner than squares, but values are not skipped
+/// for them: their higher powers are left out and counted by [`ShiftedSums::skipped`],
+/// and higher moments are unknown until such values leave the window, and the periodic
+/// recentering sums them again.
+///
+/// Values are summed unshifted as well, and never summed again, so `avg` is exactly what
+/// a plain running sum gives.
+#[derive(Clone, Default)]
+pub struct ShiftedSums {
+    /// sum of values as they are, for `avg`
+    raw: NeumaierSum,
+    /// subtracted from values before summing
+    shift: f64,
+    /// sum of shifted values
+    sum: NeumaierSum,
+    /// sum of squares of shifted values
+    sum_sq: NeumaierSum,
+    /// sums of third and fourth powers of shifted values, for skewness and kurtosis
+    sum_cube: NeumaierSum,
+    sum_quad: NeumaierSum,
+    /// summed values whose fourth power overflows, left out of higher sums
+    skipped: usize,
+    /// values pushed since the last recentering
+    pushed: usize,
+}
+
+impl ShiftedSums {
+    /// Adds `val` to sums of `count` values.
+    pub fn push(&mut self, val: f64, count: usize) {
+        if count == 0 {
+            // nothing to keep, so start over exactly
+            *self = Self {
+                shift: val,
+                ..Self::default()
+            };
+        }
+        self.raw += val;
+        self.add(val);
+        self.pushed += 1;
+    }
+
+    fn add(&mut self, val: f64) {
+        let shifted = val - self.shift;
+        let shifted_sq = shifted * shifted;
+        self.sum += shifted;
+        self.sum_sq += shifted_sq;
+        let shifted_quad = shifted_sq * shifted_sq;
+        if shifted_quad.is_finite() {
+            self.sum_cube += shifted_sq * shifted;
+            self.sum_quad += shifted_quad;
+        } else {
+            self.skipped += 1;
+        }
+    }
+
+    /// Removes `val` from sums.
+    pub fn evict(&mut self, val: f64) {
+        let shifted = val - self.shift;
+        let shifted_sq = shifted * shifted;
+        self.raw += -val;
+        self.sum += -shifted;
+        self.sum_sq += -shifted_sq;
+        // shift is the same as when `val` was added, so it is left out the same way
+        let shifted_quad = shifted_sq * shifted_sq;
+        if shifted_quad.is_finite() {
+            self.sum_cube += -(shifted_sq * shifted);
+            self.sum_quad += -shifted_quad;
+        } else {
+            self.skipped -= 1;
+        }
+    }
+
+    /// Shifted sum of squares after adding `val`, to detect overflow.
+    pub fn sum_sq_with(&self, val: f64) -> f64 {
+        let shifted = val - self.shift;
+        (self.sum_sq.clone() + shifted * shifted).sum()
+    }
+
+    /// Whether sums stay finite after adding `val`, unless higher powers are unknown already.
+    ///
+    /// Unlike [`Self::sum_sq_with`], overflowing higher powers do not make a value too big,
+    /// so they are checked only to recenter once, not on every push while they are unknown.
+    pub fn fits(&self, val: f64) -> bool {
+        if !self.sum_sq_with(val).is_finite() {
+            return false;
+        }
+        if !self.higher_known() {
+            return true;
+        }
+        let shifted_sq = (val - self.shift) * (val - self.shift);
+        (self.sum_quad.clone() + shifted_sq * shifted_sq)
+            .sum()
+            .is_finite()
+    }
+
+    /// Number of summed values left out of higher sums, as their fourth power overflows.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    fn higher_finite(&self) -> bool {
+        self.sum_cube.sum().is_finite() && self.sum_quad.sum().is_finite()
+    }
+
+    fn higher_known(&self) -> bool {
+        self.skipped == 0 && self.higher_finite()
+    }
+
+    /// Mean of `count` values, from shifted sum only if the plain one overflows.
+    pub fn avg(&self, count: usize) -> f64 {
+        let raw = self.raw.sum();
+        if raw.is_finite() {
+            raw / count as f64
+        } else {
+            self.shift + self.sum.sum() / count as f64
+        }
+    }
+
+    /// Population variance of `count` values; never negative, but infinite if it overflows.
+    pub fn var(&self, count: usize) -> f64 {
+        let n = count as f64;
+        let mean = self.sum.sum() / n;
+        let var = self.sum_sq.sum() / n - mean * mean;
+        if var < 0.0 { 0.0 } else { var }
+    }
+
+    /// Population skewness and excess kurtosis of `count` values;
+    /// `NaN` if values are all equal, or their powers overflow.
+    pub fn skew_kurtosis(&self, count: usize) -> (f64, f64) {
+        if !self.higher_known() {
+            return (f64::NAN, f64::NAN);
+        }
+        let n = count as f64;
+        let mean = self.sum.sum() / n;
+        let mean_sq = mean * mean;
+        let (s2, s3, s4) = (
+            self.sum_sq.sum() / n,
+            self.sum_cube.sum() / n,
+            self.sum_quad.sum() / n,
+        );
+        // central moments from raw ones of shifted values, whose mean is close to `0`
+        let m2 = s2 - mean_sq;
+        let m3 = s3 - 3.0 * mean * s2 + 2.0 * mean * mean_sq;
+        let m4 = s4 - 4.0 * mean * s3 + 6.0 * mean_sq * s2 - 3.0 * mean_sq * mean_sq;
+        if m2.is_nan() || m2 <= 0.0 {
+            return (f64::NAN, f64::NAN);
+        }
+        (m3 / (m2 * m2.sqrt()), m4 / (m2 * m2) - 3.0)
+    }
+
+    /// Whether shift drifted from the mean of `count` values so far that precision suffers,
+    /// and enough values were pushed since the last recentering to amortise another one,
+    /// or precision is mostly lost already.
+    ///
+    /// Unknown higher powers are summed again periodically too, as overflowing values
+    /// might have left the window.
+    pub fn should_recenter(&self, count: usize) -> bool {
+        if count == 0 {
+            return false;
+        }
+        let periodic = self.pushed * RECENTER_PERIOD >= count;
+        if periodic && !self.higher_known() {
+            return true;
+        }
+        let n = count as f64;
+        drifted(self.sum.sum() / n, self.sum_sq.sum() / n, periodic)
+    }
+
+    /// Moves shift to the mean of `values`, which are all values currently summed,
+    /// and sums them again shifted.
+    pub fn recenter<I>(&mut self, values: I)
+    where
+        I: Iterator<Item = f64> + Clone,
+    {
+        let mut total = NeumaierSum::from(0.0);
+        let mut count = 0;
+        for val in values.clone() {
+            total += val;
+            count += 1;
+        }
+        if count == 0 {
+            *self = Self::default();
+            return;
+        }
+
+        *self = Self {
+            raw: self.raw.clone(),
+            shift: total.sum() / count as f64,
+            ..Self::default()
+        };
+        for val in values {
+            self.add(val);
+        }
+        tracing::debug!("recentered {count} values at {}", self.shift);
+    }
+}
+
+/// Whether shift is so far from the mean of shifted values that precision suffers,
+/// given mean of their squares, and whether enough values were pushed to amortise recentering.
+fn drifted(mean: f64, mean_sq: f64, periodic: bool) -> bool {
+    let drift = mean * mean;
+    let var = mean_sq - drift;
+    drift > MAX_LOSS * var || (periodic && drift > MAX_DRIFT * var)
+}
+
+/// Sums of weights, weighted values and their squares, shifted just like [`ShiftedSums`],
+/// for volume weighted `avg` and `var`.
+///
+/// Weights are non-negative, e.g. traded volumes. Values of zero weight count for
+/// recentering, but not for stats, so stats of zero total weight are `NaN`.
+#[derive(Clone, Default)]
+pub struct WeightedSums {
+    /// subtracted from values before summing
+    shift: f64,
+    /// sum of weights
+    weight: NeumaierSum,
+    /// sum of weighted shifted values
+    sum: NeumaierSum,
+    /// sum of weighted squares of shifted values
+    sum_sq: NeumaierSum,
+    /// values pushed since the last recentering
+    pushed: usize,
+}
+
+impl WeightedSums {
+    /// Adds `val` of `weight` to sums of `count` values.
+    pub fn push(&mut self, val: f64, weight: f64, count: usize) {
+        if count == 0 {
+            // nothing to keep, so start over exactly
+            *self = Self {
+                shift: val,
+                ..Self::default()
+            };
+        }
+        self.add(val, weight);
+        self.pushed += 1;
+    }
+
+    fn add(&mut self, val: f64, weight: f64) {
+        let shifted = val - self.shift;
+        self.weight += weight;
+        self.sum += weight * shifted;
+        self.sum_sq += weight * shifted * shifted;
+    }
+
+    /// Removes `val` of `weight` from sums.
+    pub fn evict(&mut self, val: f64, weight: f64) {
+        let shifted = val - self.shift;
+        self.weight += -weight;
+        self.sum += -(weight * shifted);
+        self.sum_sq += -(weight * shifted * shifted);
+    }
+
+    /// Whether all sums stay finite after adding `val` of `weight`.
+    pub fn fits(&self, val: f64, weight: f64) -> bool {
+        let mut sums = self.clone();
+        sums.add(val, weight);
+        sums.weight.sum().is_finite() && sums.sum.sum().is_finite() && sums.sum_sq.sum().is_finite()
+    }
+
+    /// Sum of weights, e.g. traded volume.
+    pub fn volume(&self) -> f64 {
+        self.weight.sum()
+    }
+
+    /// Weighted mean, e.g. VWAP; `NaN` without any weight.
+    pub fn avg(&self) -> f64 {
+        let volume = self.volume();
+        if volume > 0.0 {
+            self.shift + self.sum.sum() / volume
+        } else {
+            f64::NAN
+        }
+    }
+
+    /// Weighted population variance; never negative, `NaN` without any weight.
+    pub fn var(&self) -> f64 {
+        let volume = self.volume();
+        if volume.is_nan() || volume <= 0.0 {
+            return f64::NAN;
+        }
+        let mean = self.sum.sum() / volume;
+        let var = self.sum_sq.sum() / volume - mean * mean;
+        if var < 0.0 { 0.0 } else { var }
+    }
+
+    /// Whether shift drifted from the weighted mean, see [`ShiftedSums::should_recenter`].
+    pub fn should_recenter(&self, count: usize) -> bool {
+        let volume = self.volume();
+        if count == 0 || volume.is_nan() || volume <= 0.0 {
+            return false;
+        }
+        let periodic = self.pushed * RECENTER_PERIOD >= count;
+        drifted(
+            self.sum.sum() / volume,
+            self.sum_sq.sum() / volume,
+            periodic,
+        )
+    }
+
+    /// Moves shift to the weighted mean of `values` with their weights, which are all values
+    /// currently summed, and sums them again.
+    pub fn recenter<I>(&mut self, values: I)
+    where
+        I: Iterator<Item = (f64, f64)> + Clone,
+    {
+        let mut weight = NeumaierSum::from(0.0);
+        let mut total = NeumaierSum::from(0.0);
+        let mut first = None;
+        for (val, w) in values.clone() {
+            weight += w;
+            total += w * val;
+            first.get_or_insert(val);
+        }
+        let Some(first) = first else {
+            *self = Self::default();
+            return;
+        };
+
+        let weight = weight.sum();
+        *self = Self {
+            shift: if weight > 0.0 {
+                total.sum() / weight
+            } else {
+                first
+            },
+            ..Self::default()
+        };
+        for (val, w) in values {
+            self.add(val, w);
+        }
+    }
+}
+
+/// Sums of pairs of values, their squares and cross products, shifted just like [`ShiftedSums`],
+/// for covariance of two series, e.g. prices of a symbol and of its benchmark.
+#[derive(Clone, Default)]
+pub struct CoSums {
+    /// subtracted from values of each series before summing
+    shift_x: f64,
+    shift_y: f64,
+    /// sums of shifted values of each series
+    sum_x: NeumaierSum,
+    sum_y: NeumaierSum,
+    /// sums of squares of shifted values of each series
+    sum_xx: NeumaierSum,
+    sum_yy: NeumaierSum,
+    /// sum of products of shifted values
+    sum_xy: NeumaierSum,
+    /// pairs pushed since the last recentering
+    pushed: usize,
+}
+
+/// Population `var` of both series and their `cov`, see [`CoSums::moments`].
+pub struct CoMoments {
+    pub var_x: f64,
+    pub var_y: f64,
+    pub cov: f64,
+}
+
+impl CoSums {
+    /// Adds pair `(x, y)` to sums of `count` pairs.
+    pub fn push(&mut self, x: f64, y: f64, count: usize) {
+        if count == 0 {
+            // nothing to keep, so start over exactly
+            *self = Self {
+                shift_x: x,
+                shift_y: y,
+                ..Self::default()
+            };
+        }
+        self.add(x, y);
+        self.pushed += 1;
+    }
+
+    fn add(&mut self, x: f64, y: f64) {
+        let (dx, dy) = (x - self.shift_x, y - self.shift_y);
+        self.sum_x += dx;
+        self.sum_y += dy;
+        self.sum_xx += dx * dx;
+        self.sum_yy += dy * dy;
+        self.sum_xy += dx * dy;
+    }
+
+    /// Removes pair `(x, y)` from sums.
+    pub fn evict(&mut self, x: f64, y: f64) {
+        let (dx, dy) = (x - self.shift_x, y - self.shift_y);
+        self.sum_x += -dx;
+        self.sum_y += -dy;
+        self.sum_xx += -(dx * dx);
+        self.sum_yy += -(dy * dy);
+        self.sum_xy += -(dx * dy);
+    }
+
+    /// Whether sums of squares and products stay finite after adding pair `(x, y)`.
+    pub fn fits(&self, x: f64, y: f64) -> bool {
+        let mut sums = self.clone();
+        sums.add(x, y);
+        sums.sum_xx.sum().is_finite()
+            && sums.sum_yy.sum().is_finite()
+            && sums.sum_xy.sum().is_finite()
+    }
+
+    /// Population variances and covariance of `count` pairs; variances are never negative.
+    pub fn moments(&self, count: usize) -> CoMoments {
+        let n = count as f64;
+        let (mean_x, mean_y) = (self.sum_x.sum() / n, self.sum_y.sum() / n);
+        let var_x = self.sum_xx.sum() / n - mean_x * mean_x;
+        let var_y = self.sum_yy.sum() / n - mean_y * mean_y;
+        CoMoments {
+            var_x: if var_x < 0.0 { 0.0 } else { var_x },
+            var_y: if var_y < 0.0 { 0.0 } else { var_y },
+            cov: self.sum_xy.sum() / n - mean_x * mean_y,
+        }
+    }
+
+    /// Whether either shift drifted from the mean of its series,
+    /// see [`ShiftedSums::should_recenter`].
+    pub fn should_recenter(&self, count: usize) -> bool {
+        if count == 0 {
+            return false;
+        }
+        let periodic = self.pushed * RECENTER_PERIOD >= count;
+        let n = count as f64;
+        drifted(self.sum_x.sum() / n, self.sum_xx.sum() / n, periodic)
+            || drifted(self.sum_y.sum() / n, self.sum_yy.sum() / n, periodic)
+    }
+
+    /// Moves shifts to the means of `pairs`, which are all pairs currently summed,
+    /// and sums them again.
+    pub fn recenter<I>(&mut self, pairs: I)
+    where
+        I: Iterator<Item = (f64, f64)> + Clone,
+    {
+        let mut total_x = NeumaierSum::from(0.0);
+        let mut total_y = NeumaierSum::from(0.0);
+        let mut count = 0;
+        for (x, y) in pairs.clone() {
+            total_x += x;
+            total_y += y;
+            count += 1;
+        }
+        if count == 0 {
+            *self = Self::default();
+            return;
+        }
+
+        *self = Self {
+            shift_x: total_x.sum() / count as f64,
+            shift_y: total_y.sum() / count as f64,
+            ..Self::default()
+        };
+        for (x, y) in pairs {
+            self.add(x, y);
+        }
+    }
+}
+
+impl Persist for ShiftedSums {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        self.raw.encode(enc)?;
+        enc.f64(self.shift)?;
+        self.sum.encode(enc)?;
+        self.sum_sq.encode(enc)?;
+        self.sum_cube.encode(enc)?;
+        self.sum_quad.encode(enc)?;
+        enc.usize(self.skipped)?;
+        enc.usize(self.pushed)
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            raw: NeumaierSum::decode(dec)?,
+            shift: dec.f64()?,
+            sum: NeumaierSum::decode(dec)?,
+            sum_sq: NeumaierSum::decode(dec)?,
+            sum_cube: NeumaierSum::decode(dec)?,
+            sum_quad: NeumaierSum::decode(dec)?,
+            skipped: dec.usize()?,
+            pushed: dec.usize()?,
+        })
+    }
+}
+
+impl Persist for CoSums {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.f64(self.shift_x)?;
+        enc.f64(self.shift_y)?;
+        self.sum_x.encode(enc)?;
+        self.sum_y.encode(enc)?;
+        self.sum_xx.encode(enc)?;
+        self.sum_yy.encode(enc)?;
+        self.sum_xy.encode(enc)?;
+        enc.usize(self.pushed)
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            shift_x: dec.f64()?,
+            shift_y: dec.f64()?,
+            sum_x: NeumaierSum::decode(dec)?,
+            sum_y: NeumaierSum::decode(dec)?,
+            sum_xx: NeumaierSum::decode(dec)?,
+            sum_yy: NeumaierSum::decode(dec)?,
+            sum_xy: NeumaierSum::decode(dec)?,
+            pushed: dec.usize()?,
+        })
+    }
+}
+
+impl Persist for WeightedSums {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.f64(self.shift)?;
+        self.weight.encode(enc)?;
+        self.sum.encode(enc)?;
+        self.sum_sq.encode(enc)?;
+        enc.usize(self.pushed)
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            shift: dec.f64()?,
+            weight: NeumaierSum::decode(dec)?,
+            sum: NeumaierSum::decode(dec)?,
+            sum_sq: NeumaierSum::decode(dec)?,
+            pushed: dec.usize()?,
+        })
+    }
+}
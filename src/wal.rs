@@ -0,0 +1,609 @@
+//! Write-ahead log of accepted batches, replayed on top of the latest snapshot at startup.
+//!
+//! The log is a directory of segments named by the sequence number of their first record,
+//! e.g. `00000000000000000001.wal`. Each record is, little endian:
+//! ```txt
+//! | payload len: u32 | crc32 of payload: u32 | seq: u64 | symbol len: u16 | symbol | count: u32 | values: f64... | weights: f64... | timestamps: u64... |
+//! ```
+//! Sequence numbers start at `1` and grow by one with every record.
+//! Weights and timestamps are optional: there are either `count` of them, or none.
+//! Weights are present if the highest bit of `count` is set, so records without them
+//! are the same as before weights were introduced.
+//!
+//! Batches applied atomically are appended as a group of consecutive records, all but the last
+//! one with the second highest bit of `count` set. A group is replayed either whole or not at all:
+//! one cut short, e.g. by a crash while writing it, is truncated just like a torn record.
+//!
+//! When opening the log, the first torn or corrupted record ends it: the segment is truncated
+//! right before that record and later segments are removed, so the log never has gaps.
+//! New records always go to a fresh segment.
+use crate::registry::Batch;
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const SEGMENT_EXTENSION: &str = "wal";
+/// `len` and `crc32`
+const HEADER_LEN: usize = 8;
+/// bit of `count` marking records with weights
+const WEIGHTS_FLAG: u32 = 1 << 31;
+/// bit of `count` marking records followed by more records of the same group
+const CONTINUED_FLAG: u32 = 1 << 30;
+
+/// When to `fsync` appended records.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum FsyncPolicy {
+    /// after every record, before the batch is acknowledged
+    Always,
+    /// in the background every `wal_fsync_interval_ms`
+    #[default]
+    Periodic,
+    /// leave it to the OS
+    Never,
+}
+
+/// Single accepted batch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WalRecord {
+    pub seq: u64,
+    pub symbol: String,
+    pub values: Vec<f64>,
+    /// milliseconds since unix epoch, one per value, if the batch was timestamped
+    pub timestamps: Option<Vec<u64>>,
+    /// one per value, if the batch was weighted
+    pub weights: Option<Vec<f64>>,
+}
+
+pub struct Wal {
+    dir: PathBuf,
+    fsync: FsyncPolicy,
+    /// segment is rotated once it reaches this size
+    segment_size: usize,
+    writer: Mutex<SegmentWriter>,
+}
+
+struct SegmentWriter {
+    file: File,
+    /// bytes written to the current segment
+    size: usize,
+    /// sequence number of the next record
+    next_seq: u64,
+}
+
+impl Wal {
+    /// Opens the log in `dir`, calling `replay` for every valid record in order.
+    ///
+    /// Torn or corrupted tail of the last segment is truncated, while a broken record in any
+    /// earlier one fails opening, leaving all segments in place. New records are appended to
+    /// a fresh segment.
+    pub fn open(
+        dir: &Path,
+        fsync: FsyncPolicy,
+        segment_size: usize,
+        mut replay: impl FnMut(WalRecord),
+    ) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut next_seq = 1;
+        let segments = segments(dir)?;
+        // a segment is synced before records go to the next one, so only the last one holding
+        // any can be torn; segments started by opening and left empty do not count
+        let mut last_written = 0;
+        for (i, (_, path)) in segments.iter().enumerate() {
+            if fs::metadata(path)?.len() > 0 {
+                last_written = i;
+            }
+        }
+        for (i, (first_seq, path)) in segments.into_iter().enumerate() {
+            // segments left empty by a rotation still carry the numbering on
+            next_seq = next_seq.max(first_seq);
+            read_segment(&path, i >= last_written, &mut |record| {
+                next_seq = record.seq + 1;
+                replay(record);
+            })?;
+        }
+
+        let file = create_segment(dir, next_seq)?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            fsync,
+            segment_size,
+            writer: Mutex::new(SegmentWriter {
+                file,
+                size: 0,
+                next_seq,
+            }),
+        })
+    }
+
+    /// Appends a batch, returning its sequence number.
+    pub fn append(
+        &self,
+        symbol: &str,
+        values: &[f64],
+        timestamps: Option<&[u64]>,
+        weights: Option<&[f64]>,
+    ) -> io::Result<u64> {
+        let batch = Batch {
+            symbol,
+            values,
+            timestamps,
+            weights,
+        };
+        Ok(self.append_group(&[batch])?.start)
+    }
+
+    /// Appends batches as a group, which is replayed either whole or not at all,
+    /// returning their sequence numbers.
+    ///
+    /// A group is never split between segments.
+    pub fn append_group(&self, batches: &[Batch<'_>]) -> io::Result<Range<u64>> {
+        let mut writer = self.writer.lock().unwrap();
+        let first_seq = writer.next_seq;
+        let seqs = first_seq..first_seq + batches.len() as u64;
+        if batches.is_empty() {
+            return Ok(seqs);
+        }
+
+        let mut group = Vec::new();
+        for (i, batch) in batches.iter().enumerate() {
+            let continued = i + 1 < batches.len();
+            encode_record(&mut group, first_seq + i as u64, batch, continued)?;
+        }
+
+        if writer.size > 0 && writer.size + group.len() > self.segment_size {
+            writer.file.sync_data()?;
+            writer.file = create_segment(&self.dir, first_seq)?;
+            writer.size = 0;
+        }
+
+        writer.file.write_all(&group)?;
+        writer.size += group.len();
+        writer.next_seq = seqs.end;
+        if self.fsync == FsyncPolicy::Always {
+            writer.file.sync_data()?;
+        }
+        Ok(seqs)
+    }
+
+    /// Flushes appended records to disk.
+    pub fn sync(&self) -> io::Result<()> {
+        self.writer.lock().unwrap().file.sync_data()
+    }
+
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        self.fsync
+    }
+
+    /// Starts a new segment, returning sequence number of its first record.
+    ///
+    /// All records before it are in older segments.
+    pub fn rotate(&self) -> io::Result<u64> {
+        let mut writer = self.writer.lock().unwrap();
+        let seq = writer.next_seq;
+        if writer.size > 0 {
+            writer.file.sync_data()?;
+            writer.file = create_segment(&self.dir, seq)?;
+            writer.size = 0;
+        }
+        Ok(seq)
+    }
+
+    /// Removes segments holding only records older than `seq`, e.g. covered by a snapshot.
+    ///
+    /// Returns number of removed segments.
+    pub fn remove_segments_before(&self, seq: u64) -> io::Result<usize> {
+        let segments = segments(&self.dir)?;
+        let mut removed = 0;
+        for pair in segments.windows(2) {
+            let ((_, path), (next_first_seq, _)) = (&pair[0], &pair[1]);
+            if *next_first_seq <= seq {
+                fs::remove_file(path)?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Appends record of `batch` with sequence number `seq` to `buf`,
+/// `continued` by more records of the same group.
+fn encode_record(
+    buf: &mut Vec<u8>,
+    seq: u64,
+    batch: &Batch<'_>,
+    continued: bool,
+) -> io::Result<()> {
+    let Batch {
+        symbol,
+        values,
+        timestamps,
+        weights,
+    } = *batch;
+    let timestamps = timestamps.unwrap_or_default();
+    debug_assert!(timestamps.is_empty() || timestamps.len() == values.len());
+    debug_assert!(weights.is_none_or(|weights| weights.len() == values.len()));
+    let symbol_len = u16::try_from(symbol.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "symbol is too long"))?;
+    let mut count = u32::try_from(values.len())
+        .ok()
+        .filter(|&count| count < CONTINUED_FLAG)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "batch is too big"))?;
+    if weights.is_some() {
+        count |= WEIGHTS_FLAG;
+    }
+    if continued {
+        count |= CONTINUED_FLAG;
+    }
+    let weights = weights.unwrap_or_default();
+
+    let start = buf.len();
+    let payload_len =
+        8 + 2 + symbol.len() + 4 + values.len() * 8 + weights.len() * 8 + timestamps.len() * 8;
+    let encoded_len = u32::try_from(payload_len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "batch is too big"))?;
+    buf.reserve(HEADER_LEN + payload_len);
+    buf.extend(encoded_len.to_le_bytes());
+    buf.extend(0u32.to_le_bytes()); // crc placeholder
+    buf.extend(seq.to_le_bytes());
+    buf.extend(symbol_len.to_le_bytes());
+    buf.extend(symbol.as_bytes());
+    buf.extend(count.to_le_bytes());
+    buf.extend(values.iter().flat_map(|v| v.to_le_bytes()));
+    buf.extend(weights.iter().flat_map(|w| w.to_le_bytes()));
+    buf.extend(timestamps.iter().flat_map(|t| t.to_le_bytes()));
+    let crc = crc32fast::hash(&buf[start + HEADER_LEN..]);
+    buf[start + 4..start + HEADER_LEN].copy_from_slice(&crc.to_le_bytes());
+    Ok(())
+}
+
+/// Segments in `dir` sorted by their first sequence number.
+fn segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != SEGMENT_EXTENSION) {
+            continue;
+        }
+        let first_seq = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse().ok());
+        match first_seq {
+            Some(first_seq) => segments.push((first_seq, path)),
+            None => tracing::warn!("ignoring unexpected file {path:?} in WAL directory"),
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+fn create_segment(dir: &Path, first_seq: u64) -> io::Result<File> {
+    let path = dir.join(format!("{first_seq:020}.{SEGMENT_EXTENSION}"));
+    tracing::debug!("starting WAL segment {path:?}");
+    OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(path)
+}
+
+/// Reads all records of a segment.
+///
+/// If reading stops at a torn or corrupted record, or a group cut short, the segment is
+/// truncated right before it if it may be `truncated`, or it is an error otherwise.
+fn read_segment(
+    path: &Path,
+    truncate: bool,
+    on_record: &mut impl FnMut(WalRecord),
+) -> io::Result<()> {
+    let mut reader = BufReader::new(File::open(path)?);
+    // end of the last complete group, and records of the group after it read so far
+    let mut offset = 0u64;
+    let mut group = Vec::new();
+    let mut group_len = 0;
+    loop {
+        let reason = match read_record(&mut reader)? {
+            ReadResult::Record(record, len, continued) => {
+                group.push(record);
+                group_len += len as u64;
+                if !continued {
+                    offset += group_len;
+                    group_len = 0;
+                    group.drain(..).for_each(&mut *on_record);
+                }
+                continue;
+            }
+            ReadResult::End if group.is_empty() => return Ok(()),
+            ReadResult::End => format!("group cut short after {} records", group.len()),
+            ReadResult::Broken(reason) => reason,
+        };
+        if !truncate {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("WAL segment {path:?} followed by others is broken at {offset}: {reason}"),
+            ));
+        }
+        tracing::warn!("truncating WAL segment {path:?} at {offset}: {reason}");
+        let file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(offset)?;
+        file.sync_all()?;
+        return Ok(());
+    }
+}
+
+enum ReadResult {
+    /// record, its length in bytes with header, and whether more records of its group follow
+    Record(WalRecord, usize, bool),
+    /// clean end of the segment
+    End,
+    Broken(String),
+}
+
+fn read_record(reader: &mut impl Read) -> io::Result<ReadResult> {
+    let mut header = [0; HEADER_LEN];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(ReadResult::End),
+        HEADER_LEN => {}
+        n => return Ok(ReadResult::Broken(format!("torn header of {n} bytes"))),
+    }
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+    // do not trust `len` before checksum is verified, so read it in a bounded way
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len {
+        return Ok(ReadResult::Broken(format!(
+            "torn record of {} out of {len} bytes",
+            payload.len()
+        )));
+    }
+    if crc32fast::hash(&payload) != crc {
+        return Ok(ReadResult::Broken("checksum mismatch".into()));
+    }
+
+    match decode_payload(&payload) {
+        Some((record, continued)) => Ok(ReadResult::Record(record, HEADER_LEN + len, continued)),
+        None => Ok(ReadResult::Broken("malformed record".into())),
+    }
+}
+
+/// Decodes record, and whether more records of its group follow.
+fn decode_payload(payload: &[u8]) -> Option<(WalRecord, bool)> {
+    let (seq, rest) = payload.split_first_chunk::<8>()?;
+    let (symbol_len, rest) = rest.split_first_chunk::<2>()?;
+    let (symbol, rest) = rest.split_at_checked(u16::from_le_bytes(*symbol_len) as usize)?;
+    let (count, rest) = rest.split_first_chunk::<4>()?;
+    let count = u32::from_le_bytes(*count);
+    let weighted = count & WEIGHTS_FLAG != 0;
+    let continued = count & CONTINUED_FLAG != 0;
+    let count = (count & !(WEIGHTS_FLAG | CONTINUED_FLAG)) as usize;
+    let (values, rest) = rest.split_at_checked(count * 8)?;
+    let (weights, timestamps) = rest.split_at_checked(if weighted { count * 8 } else { 0 })?;
+    if !timestamps.is_empty() && timestamps.len() != count * 8 {
+        return None;
+    }
+    let record = WalRecord {
+        seq: u64::from_le_bytes(*seq),
+        symbol: String::from_utf8(symbol.to_vec()).ok()?,
+        values: values
+            .chunks_exact(8)
+            .map(|b| f64::from_le_bytes(b.try_into().expect("chunk of 8 bytes")))
+            .collect(),
+        timestamps: (!timestamps.is_empty()).then(|| {
+            timestamps
+                .chunks_exact(8)
+                .map(|b| u64::from_le_bytes(b.try_into().expect("chunk of 8 bytes")))
+                .collect()
+        }),
+        weights: weighted.then(|| {
+            weights
+                .chunks_exact(8)
+                .map(|b| f64::from_le_bytes(b.try_into().expect("chunk of 8 bytes")))
+                .collect()
+        }),
+    };
+    Some((record, continued))
+}
+
+/// Like `read_exact`, but returns number of bytes read on early EOF.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("fast-stats-wal-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open(dir: &Path, segment_size: usize) -> (Wal, Vec<WalRecord>) {
+        let mut records = Vec::new();
+        let wal = Wal::open(dir, FsyncPolicy::Never, segment_size, |r| records.push(r)).unwrap();
+        (wal, records)
+    }
+
+    #[test]
+    fn test_append_and_replay() {
+        let dir = temp_dir("replay");
+        let (wal, records) = open(&dir, 100);
+        assert!(records.is_empty());
+        assert_eq!(wal.append("A", &[1.0, 2.0], None, None).unwrap(), 1);
+        assert_eq!(wal.append("B", &[3.0; 10], None, None).unwrap(), 2);
+        assert_eq!(wal.append("A", &[], None, None).unwrap(), 3);
+        drop(wal);
+
+        let (wal, records) = open(&dir, 100);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].symbol, "A");
+        assert_eq!(records[0].values, vec![1.0, 2.0]);
+        assert_eq!(records[1].values, vec![3.0; 10]);
+        assert_eq!(records[2].seq, 3);
+        // numbering continues
+        assert_eq!(wal.append("C", &[4.0], Some(&[1000]), None).unwrap(), 4);
+        let weights = [0.5, 2.0];
+        assert_eq!(
+            wal.append("D", &[5.0, 6.0], None, Some(&weights)).unwrap(),
+            5
+        );
+        assert_eq!(
+            wal.append("D", &[7.0], Some(&[2000]), Some(&[3.0]))
+                .unwrap(),
+            6
+        );
+        drop(wal);
+
+        let (_, records) = open(&dir, 100);
+        assert_eq!(records[2].timestamps, None);
+        assert_eq!(records[3].timestamps, Some(vec![1000]));
+        assert_eq!(records[3].weights, None);
+        assert_eq!(records[4].weights, Some(vec![0.5, 2.0]));
+        assert_eq!(records[4].timestamps, None);
+        assert_eq!(records[5].weights, Some(vec![3.0]));
+        assert_eq!(records[5].timestamps, Some(vec![2000]));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_torn_tail_truncated() {
+        let dir = temp_dir("torn");
+        let (wal, _) = open(&dir, 1 << 20);
+        wal.append("A", &[1.0, 2.0], None, None).unwrap();
+        wal.append("A", &[3.0, 4.0], None, None).unwrap();
+        drop(wal);
+
+        // cut the last record in half
+        let (_, path) = segments(&dir).unwrap().remove(0);
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 10)
+            .unwrap();
+
+        let (wal, records) = open(&dir, 1 << 20);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].values, vec![1.0, 2.0]);
+        assert_eq!(fs::metadata(&path).unwrap().len(), (len - 10) / 2 + 5);
+        assert_eq!(wal.append("A", &[5.0], None, None).unwrap(), 2);
+        drop(wal);
+
+        let (_, records) = open(&dir, 1 << 20);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].values, vec![5.0]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_group_cut_short_dropped() {
+        let dir = temp_dir("group");
+        let (wal, _) = open(&dir, 1 << 20);
+        wal.append("A", &[1.0, 1.5], None, None).unwrap();
+        let batch = |symbol| Batch {
+            symbol,
+            values: &[2.0, 3.0],
+            timestamps: None,
+            weights: None,
+        };
+        assert_eq!(wal.append_group(&[batch("A"), batch("B")]).unwrap(), 2..4);
+        assert_eq!(wal.append_group(&[batch("C"), batch("D")]).unwrap(), 4..6);
+        drop(wal);
+
+        let (_, records) = open(&dir, 1 << 20);
+        let symbols: Vec<&str> = records.iter().map(|r| r.symbol.as_str()).collect();
+        assert_eq!(symbols, vec!["A", "A", "B", "C", "D"]);
+
+        // drop the last record, leaving the first record of its group behind
+        let (_, path) = segments(&dir).unwrap().remove(0);
+        let len = fs::metadata(&path).unwrap().len();
+        // all records are of the same length
+        let record_len = len / 5;
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - record_len)
+            .unwrap();
+
+        let (wal, records) = open(&dir, 1 << 20);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[2].symbol, "B");
+        assert_eq!(fs::metadata(&path).unwrap().len(), len - 2 * record_len);
+        // the empty segment started by the previous open keeps the dropped numbers from reuse
+        assert_eq!(wal.append("E", &[4.0], None, None).unwrap(), 6);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_segment_kept() {
+        let dir = temp_dir("corrupted");
+        // every record gets its own segment
+        let (wal, _) = open(&dir, 1);
+        for i in 0..3 {
+            wal.append("A", &[i as f64], None, None).unwrap();
+        }
+        drop(wal);
+
+        let sizes = |dir: &Path| -> Vec<(u64, u64)> {
+            let segments = segments(dir).unwrap().into_iter();
+            segments
+                .map(|(seq, path)| (seq, fs::metadata(path).unwrap().len()))
+                .collect()
+        };
+        let before = sizes(&dir);
+        assert_eq!(before.len(), 3);
+        let (_, first) = segments(&dir).unwrap().remove(0);
+        let mut bytes = fs::read(&first).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&first, bytes).unwrap();
+
+        let err = Wal::open(&dir, FsyncPolicy::Never, 1, |_| {})
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // neither the broken segment nor the later ones are touched
+        assert_eq!(sizes(&dir), before);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_segments_before() {
+        let dir = temp_dir("remove");
+        let (wal, _) = open(&dir, 1 << 20);
+        wal.append("A", &[1.0], None, None).unwrap();
+        wal.append("A", &[2.0], None, None).unwrap();
+        let checkpoint = wal.rotate().unwrap();
+        assert_eq!(checkpoint, 3);
+        wal.append("A", &[3.0], None, None).unwrap();
+
+        assert_eq!(wal.remove_segments_before(checkpoint).unwrap(), 1);
+        drop(wal);
+
+        let (_, records) = open(&dir, 1 << 20);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].seq, 3);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+//! Delivery of JSON notifications to plain HTTP webhooks, retried with exponential backoff.
+//!
+//! Webhooks are meant to be local relays, so there is no TLS: URLs are `http://host[:port][/path]`.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// Delay before the first retry, doubled by every next one.
+pub const BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Time limit of a single delivery attempt.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct WebhookUrl {
+    pub host: String,
+    pub port: u16,
+    /// starts with `/`
+    pub path: String,
+}
+
+impl FromStr for WebhookUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("webhook must be `http://host[:port][/path]`, got {s:?}");
+        let rest = s.trim().strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl TryFrom<String> for WebhookUrl {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<WebhookUrl> for String {
+    fn from(url: WebhookUrl) -> Self {
+        url.to_string()
+    }
+}
+
+impl fmt::Display for WebhookUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/// JSON `body` to post to `webhooks`, or to the default ones if there are none.
+#[derive(Debug)]
+pub struct Notification {
+    pub webhooks: Vec<WebhookUrl>,
+    pub body: String,
+}
+
+/// Delivers `notifications` as they come, each webhook on its own, making `1 + retries` attempts.
+pub async fn deliver_all(
+    mut notifications: mpsc::Receiver<Notification>,
+    default_webhooks: Vec<WebhookUrl>,
+    retries: u32,
+) {
+    while let Some(notification) = notifications.recv().await {
+        let webhooks = match notification.webhooks.is_empty() {
+            true => &default_webhooks,
+            false => &notification.webhooks,
+        };
+        for url in webhooks {
+            let (url, body) = (url.clone(), notification.body.clone());
+            tokio::spawn(async move {
+                if let Err(err) = post_with_retries(&url, &body, retries, BACKOFF).await {
+                    tracing::error!("unable to notify webhook {url}: {err}");
+                }
+            });
+        }
+    }
+}
+
+/// Posts `body` to `url` until it succeeds, retrying `retries` times after growing delays.
+pub async fn post_with_retries(
+    url: &WebhookUrl,
+    body: &str,
+    retries: u32,
+    backoff: Duration,
+) -> io::Result<()> {
+    let mut delay = backoff;
+    let mut attempt = 0;
+    loop {
+        let result = match tokio::time::timeout(TIMEOUT, post(url, body)).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")),
+        };
+        match result {
+            Err(err) if attempt < retries => {
+                tracing::warn!("webhook {url} failed, retrying in {delay:?}: {err}");
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_BACKOFF);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Posts JSON `body`, failing unless the response status is `2xx`.
+async fn post(url: &WebhookUrl, body: &str) -> io::Result<()> {
+    let mut stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        url.path,
+        url.host,
+        url.port,
+        body.len()
+    );
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line).await?;
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        Some(status) => Err(io::Error::other(format!("responded with {status}"))),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected response {status_line:?}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_url() {
+        let url: WebhookUrl = "http://localhost:8080/hooks/alerts".parse().unwrap();
+        assert_eq!(url.host, "localhost");
+        assert_eq!(url.port, 8080);
+        assert_eq!(url.path, "/hooks/alerts");
+        let url: WebhookUrl = "http://127.0.0.1".parse().unwrap();
+        assert_eq!(url.to_string(), "http://127.0.0.1:80/");
+        assert!("https://example.com".parse::<WebhookUrl>().is_err());
+        assert!("http://:80/".parse::<WebhookUrl>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_retried_until_delivered() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for status in ["503 Service Unavailable", "204 No Content"] {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 1024];
+                let len = stream.read(&mut request).await.unwrap();
+                requests.push(String::from_utf8_lossy(&request[..len]).into_owned());
+                let response = format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n");
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+
+        let url = format!("http://127.0.0.1:{port}/alerts").parse().unwrap();
+        post_with_retries(&url, r#"{"a":1}"#, 1, Duration::from_millis(10))
+            .await
+            .unwrap();
+        let requests = server.await.unwrap();
+        assert!(requests[1].starts_with("POST /alerts HTTP/1.1\r\n"));
+        assert!(requests[1].ends_with("\r\n\r\n{\"a\":1}"));
+
+        // nothing listens anymore
+        let err = post_with_retries(&url, "{}", 1, Duration::from_millis(10)).await;
+        assert!(err.is_err());
+    }
+}
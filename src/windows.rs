@@ -0,0 +1,365 @@
+//! Runtime definition of sliding windows maintained per symbol.
+use crate::snapshot::{Decoder, Encoder, Persist, SnapshotError};
+use serde::Deserialize;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+/// Single window as written in config: either bare span `390` or `5m`,
+/// or named one `session=390`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawWindowSpec")]
+pub struct WindowSpec {
+    pub name: Option<String>,
+    pub span: Span,
+}
+
+/// TOML allows both `windows = [50, "session=390", "1m"]`, so accept integers and strings.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawWindowSpec {
+    Size(usize),
+    Spec(String),
+}
+
+impl TryFrom<RawWindowSpec> for Span {
+    type Error = String;
+
+    fn try_from(raw: RawWindowSpec) -> Result<Self, Self::Error> {
+        match raw {
+            RawWindowSpec::Size(size) => Ok(Span::Count(size)),
+            RawWindowSpec::Spec(spec) => spec.parse(),
+        }
+    }
+}
+
+impl TryFrom<RawWindowSpec> for WindowSpec {
+    type Error = String;
+
+    fn try_from(raw: RawWindowSpec) -> Result<Self, Self::Error> {
+        match raw {
+            RawWindowSpec::Size(size) => Ok(Self {
+                name: None,
+                span: Span::Count(size),
+            }),
+            RawWindowSpec::Spec(spec) => spec.parse(),
+        }
+    }
+}
+
+impl FromStr for WindowSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, span) = match s.split_once('=') {
+            Some((name, span)) => (Some(name.trim().to_string()), span),
+            None => (None, s),
+        };
+        Ok(Self {
+            name,
+            span: span.parse()?,
+        })
+    }
+}
+
+/// What a window covers: a number of most recent values, or values of recent period of time.
+///
+/// Also used for EWMA half-lives, so config accepts integers and strings just like windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "RawWindowSpec")]
+pub enum Span {
+    Count(usize),
+    /// in milliseconds
+    Time(u64),
+}
+
+/// Units of time spans, biggest first.
+const TIME_UNITS: [(&str, u64); 5] = [
+    ("d", 24 * 60 * 60 * 1000),
+    ("h", 60 * 60 * 1000),
+    ("m", 60 * 1000),
+    ("s", 1000),
+    ("ms", 1),
+];
+
+impl FromStr for Span {
+    type Err = String;
+
+    /// Parses count `390`, or time span with unit: `500ms`, `1s`, `5m`, `1h`, `1d`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (number, unit) = s.split_at(digits);
+        let invalid =
+            || format!("window must be a count like `390` or a time span like `5m`, got {s:?}");
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        if unit.is_empty() {
+            return usize::try_from(number)
+                .map(Span::Count)
+                .map_err(|_| invalid());
+        }
+        let (_, millis) = TIME_UNITS
+            .iter()
+            .find(|(name, _)| *name == unit.trim())
+            .ok_or_else(invalid)?;
+        number
+            .checked_mul(*millis)
+            .map(Span::Time)
+            .ok_or_else(invalid)
+    }
+}
+
+impl fmt::Display for Span {
+    /// Counts as they are, time spans in the biggest unit dividing them, e.g. `90s`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Span::Count(size) => write!(f, "{size}"),
+            Span::Time(0) => write!(f, "0ms"),
+            Span::Time(millis) => {
+                let (unit, per_unit) = TIME_UNITS
+                    .iter()
+                    .find(|(_, per_unit)| millis % per_unit == 0)
+                    .expect("every span is a whole number of milliseconds");
+                write!(f, "{}{unit}", millis / per_unit)
+            }
+        }
+    }
+}
+
+/// Single window of a [`Windows`] set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Window {
+    /// name used in queries; defaults to the span, e.g. `"1000"` or `"5m"`
+    pub name: String,
+    pub span: Span,
+}
+
+/// Non-empty set of windows: count windows sorted by size, then time windows sorted by duration.
+///
+/// Levels are numbered in that order. The biggest count window determines capacity
+/// of the values ring, so at least one count window is required.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Windows {
+    windows: Vec<Window>,
+    /// number of count windows, which come first
+    counts: usize,
+}
+
+impl Windows {
+    /// Builds sorted set of windows, rejecting sets without count windows, zero spans and duplicates.
+    pub fn new(specs: impl IntoIterator<Item = WindowSpec>) -> Result<Self, String> {
+        let mut windows: Vec<Window> = specs
+            .into_iter()
+            .map(|spec| Window {
+                name: spec.name.unwrap_or_else(|| spec.span.to_string()),
+                span: spec.span,
+            })
+            .collect();
+        let counts = windows
+            .iter()
+            .filter(|w| matches!(w.span, Span::Count(_)))
+            .count();
+        if counts == 0 {
+            return Err("at least one count window is required".into());
+        }
+
+        windows.sort_by_key(|w| match w.span {
+            Span::Count(size) => (0, size as u64),
+            Span::Time(millis) => (1, millis),
+        });
+        for (i, window) in windows.iter().enumerate() {
+            if matches!(window.span, Span::Count(0) | Span::Time(0)) {
+                return Err("window span must be greater than 0".into());
+            }
+            if window.name.is_empty() {
+                return Err(format!("window {} has empty name", window.span));
+            }
+            if i > 0 && windows[i - 1].span == window.span {
+                return Err(format!("duplicated window size {}", window.span));
+            }
+            if windows[..i].iter().any(|w| w.name == window.name) {
+                return Err(format!("duplicated window name {:?}", window.name));
+            }
+        }
+
+        Ok(Self { windows, counts })
+    }
+
+    /// Windows of sizes `radix^1 ..= radix^levels`, e.g. `10, 100, ..., 10^8`.
+    pub fn geometric(levels: u32, radix: usize) -> Self {
+        Self::new((1..=levels).map(|k| WindowSpec {
+            name: None,
+            span: Span::Count(radix.pow(k)),
+        }))
+        .expect("geometric windows are sorted and unique")
+    }
+
+    /// Number of all windows.
+    pub fn len(&self) -> usize {
+        self.windows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.windows.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Window> {
+        self.windows.iter()
+    }
+
+    /// Number of count windows; those are levels `0..counts`.
+    pub fn counts(&self) -> usize {
+        self.counts
+    }
+
+    /// Sizes of count windows, smallest first.
+    pub fn sizes(&self) -> impl Iterator<Item = usize> + '_ {
+        self.windows.iter().filter_map(|w| match w.span {
+            Span::Count(size) => Some(size),
+            Span::Time(_) => None,
+        })
+    }
+
+    /// Durations of time windows in milliseconds, shortest first.
+    pub fn durations(&self) -> impl Iterator<Item = u64> + '_ {
+        self.windows.iter().filter_map(|w| match w.span {
+            Span::Count(_) => None,
+            Span::Time(millis) => Some(millis),
+        })
+    }
+
+    /// Size of the top (biggest) count window.
+    pub fn max_size(&self) -> usize {
+        self.sizes().last().expect("there is always a count window")
+    }
+
+    /// Finds level (`0` based) of the window by its name or span.
+    pub fn level_of(&self, window: &str) -> Option<usize> {
+        if let Some(level) = self.windows.iter().position(|w| w.name == window) {
+            return Some(level);
+        }
+        let span: Span = window.parse().ok()?;
+        self.windows.iter().position(|w| w.span == span)
+    }
+}
+
+impl Default for Windows {
+    /// `10^k` windows for `1 <= k <= 8`
+    fn default() -> Self {
+        Self::geometric(8, 10)
+    }
+}
+
+impl fmt::Display for Windows {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, window) in self.windows.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}={}", window.name, window.span)?;
+        }
+        Ok(())
+    }
+}
+
+impl Persist for Windows {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        enc.usize(self.windows.len())?;
+        for window in &self.windows {
+            enc.str(&window.name)?;
+            window.span.encode(enc)?;
+        }
+        Ok(())
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        let len = dec.usize()?;
+        let mut specs = Vec::new();
+        for _ in 0..len {
+            let name = Some(dec.str()?);
+            let span = Span::decode(dec)?;
+            specs.push(WindowSpec { name, span });
+        }
+        Self::new(specs).map_err(SnapshotError::Corrupted)
+    }
+}
+
+impl Persist for Span {
+    fn encode<W: Write>(&self, enc: &mut Encoder<W>) -> io::Result<()> {
+        match *self {
+            Span::Count(size) => {
+                enc.u8(0)?;
+                enc.usize(size)
+            }
+            Span::Time(millis) => {
+                enc.u8(1)?;
+                enc.u64(millis)
+            }
+        }
+    }
+
+    fn decode<R: Read>(dec: &mut Decoder<R>) -> Result<Self, SnapshotError> {
+        match dec.u8()? {
+            0 => Ok(Span::Count(dec.usize()?)),
+            1 => Ok(Span::Time(dec.u64()?)),
+            kind => Err(SnapshotError::Corrupted(format!(
+                "unknown window kind {kind}"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn windows(specs: &[&str]) -> Result<Windows, String> {
+        Windows::new(specs.iter().map(|s| s.parse().unwrap()))
+    }
+
+    #[test]
+    fn test_windows_sorted_and_named() {
+        let windows = windows(&["1000", "session=390", "50", "200"]).unwrap();
+        assert_eq!(
+            windows.sizes().collect::<Vec<_>>(),
+            vec![50, 200, 390, 1000]
+        );
+        assert_eq!(windows.max_size(), 1000);
+        assert_eq!(windows.level_of("session"), Some(2));
+        assert_eq!(windows.level_of("390"), Some(2));
+        assert_eq!(windows.level_of("50"), Some(0));
+        assert_eq!(windows.level_of("51"), None);
+        assert_eq!(windows.to_string(), "50=50,200=200,session=390,1000=1000");
+    }
+
+    #[test]
+    fn test_time_windows() {
+        let windows = windows(&["1h", "100", "minute=60s", "500ms", "10"]).unwrap();
+        assert_eq!(windows.sizes().collect::<Vec<_>>(), vec![10, 100]);
+        assert_eq!(
+            windows.durations().collect::<Vec<_>>(),
+            vec![500, 60_000, 3_600_000]
+        );
+        assert_eq!(windows.counts(), 2);
+        assert_eq!(windows.max_size(), 100);
+        assert_eq!(windows.level_of("minute"), Some(3));
+        assert_eq!(windows.level_of("1m"), Some(3));
+        assert_eq!(windows.level_of("1h"), Some(4));
+        assert_eq!(
+            windows.to_string(),
+            "10=10,100=100,500ms=500ms,minute=1m,1h=1h"
+        );
+    }
+
+    #[test]
+    fn test_invalid_windows() {
+        assert!(windows(&[]).is_err());
+        assert!(windows(&["0"]).is_err());
+        assert!(windows(&["50", "a=50"]).is_err());
+        assert!(windows(&["a=50", "a=60"]).is_err());
+        assert!(windows(&["1s", "1m"]).is_err());
+        assert!(windows(&["10", "60s", "1m"]).is_err());
+        assert!("a=b".parse::<WindowSpec>().is_err());
+        assert!("5w".parse::<WindowSpec>().is_err());
+    }
+}
@@ -0,0 +1,109 @@
+//! `/ws` endpoint pushing stats of subscribed symbols after their batches.
+//!
+//! Clients send JSON text messages:
+//! ```txt
+//! {"subscribe": {"symbol": "AB", "levels": [1, 3], "fields": ["avg", "last"], "throttle_ms": 500}}
+//! {"unsubscribe": "AB"}
+//! ```
+//! and get [`Update`]s, or `{"error": "..."}` for messages they got wrong.
+//! Subscribing to a symbol again replaces its subscription.
+use crate::error::Error;
+use crate::subscriptions::{Subscriber, Subscription, Update};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::response::IntoResponse;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Updates taken from subscribers but not sent yet; once the socket lags behind by that many,
+/// subscribers wait, coalescing batches meanwhile.
+const PENDING_UPDATES: usize = 16;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ClientMessage {
+    Subscribe(Subscription),
+    Unsubscribe(String),
+}
+
+pub async fn subscribe(ws: WebSocketUpgrade) -> impl IntoResponse {
+    ws.on_upgrade(serve)
+}
+
+/// Serves a client until it disconnects, then drops all its subscriptions.
+async fn serve(mut socket: WebSocket) {
+    let (updates_tx, mut updates) = mpsc::channel(PENDING_UPDATES);
+    let mut subscriptions = Subscriptions::default();
+
+    loop {
+        let reply = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => {
+                    match subscriptions.handle(&text, &updates_tx) {
+                        Ok(()) => continue,
+                        Err(err) => json!({ "error": err.to_string() }),
+                    }
+                }
+                // pings are answered by axum
+                Some(Ok(Message::Binary(_) | Message::Ping(_) | Message::Pong(_))) => continue,
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+            },
+            Some(update) = updates.recv() => json!(update),
+        };
+        if socket
+            .send(Message::Text(reply.to_string().into()))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    tracing::debug!(
+        "WebSocket client with {} subscriptions disconnected",
+        subscriptions.0.len()
+    );
+}
+
+/// Tasks pushing updates of subscribed symbols, aborted when dropped.
+#[derive(Default)]
+struct Subscriptions(HashMap<String, JoinHandle<()>>);
+
+impl Subscriptions {
+    fn handle(&mut self, text: &str, updates: &mpsc::Sender<Update>) -> Result<(), Error> {
+        let message = serde_json::from_str(text)
+            .map_err(|err| Error::InvalidRequest(format!("Unexpected message: {err}")))?;
+        match message {
+            ClientMessage::Subscribe(subscription) => {
+                let mut subscriber = Subscriber::new(subscription)?;
+                let symbol = subscriber.symbol().to_string();
+                let updates = updates.clone();
+                let task = tokio::spawn(async move {
+                    loop {
+                        let update = subscriber.next().await;
+                        if updates.send(update).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                if let Some(replaced) = self.0.insert(symbol, task) {
+                    replaced.abort();
+                }
+            }
+            ClientMessage::Unsubscribe(symbol) => match self.0.remove(&symbol) {
+                Some(task) => task.abort(),
+                None => return Err(Error::InvalidRequest(format!("Not subscribed to {symbol}"))),
+            },
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for task in self.0.values() {
+            task.abort();
+        }
+    }
+}